@@ -8,6 +8,9 @@ pub enum AST {
     StringLit(Range, String),
     Identifier(Range, String),
     Number(Range, f64),
+    Integer(Range, i64),
+    /// A comment, without its leading semicolon.
+    Comment(Range, String),
 }
 
 impl AST {
@@ -25,12 +28,14 @@ impl AST {
 
     pub fn to_value(&self) -> Value {
         match self {
-            &AST::Expr(_, ref exprs) => Value::List(exprs.iter()
+            &AST::Expr(_, ref exprs) => Value::new_list(exprs.iter()
                                                     .map(|x| x.to_value())
                                                     .collect()),
             &AST::StringLit(_, ref s) => Value::String(s.to_string()),
             &AST::Identifier(_, ref i) => Value::Identifier(i.to_string()),
             &AST::Number(_, n) => Value::Number(n),
+            &AST::Integer(_, n) => Value::Integer(n),
+            &AST::Comment(_, _) => panic!("Attempted to get the value of a comment"),
         }
     }
 
@@ -40,6 +45,8 @@ impl AST {
             &AST::StringLit(ref r, _) => r,
             &AST::Identifier(ref r, _) => r,
             &AST::Number(ref r, _) => r,
+            &AST::Integer(ref r, _) => r,
+            &AST::Comment(ref r, _) => r,
         }
     }
 
@@ -89,6 +96,43 @@ impl AST {
         }
     }
 
+    pub fn is_comment(&self) -> bool {
+        match self {
+            &AST::Comment(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Calls a function on this item and on every item inside of it, depth-first. Expressions
+    /// are visited before the items inside of them.
+    pub fn walk<F: FnMut(&AST)>(&self, f: &mut F) {
+        f(self);
+        if let &AST::Expr(_, ref exprs) = self {
+            for expr in exprs {
+                expr.walk(f);
+            }
+        }
+    }
+
+    /// Like `walk`, but allows changing the items as they are visited. Since expressions are
+    /// visited before the items inside of them, any items that are added to or removed from an
+    /// expression are walked (or not) accordingly.
+    pub fn walk_mut<F: FnMut(&mut AST)>(&mut self, f: &mut F) {
+        f(self);
+        if let &mut AST::Expr(_, ref mut exprs) = self {
+            for expr in exprs.iter_mut() {
+                expr.walk_mut(f);
+            }
+        }
+    }
+
+    /// Recursively removes all comments inside of this expression.
+    pub fn strip_comments(&mut self) {
+        self.walk_mut(&mut |item| if let &mut AST::Expr(_, ref mut exprs) = item {
+            exprs.retain(|e| !e.is_comment());
+        });
+    }
+
     pub fn display_recursive(&self, f: &mut fmt::Formatter, level: i32) -> fmt::Result {
         match self {
             &AST::Expr(_, ref v) => {
@@ -113,6 +157,14 @@ impl AST {
                 print_spaces(level * 4, f);
                 write!(f, "{}", n)
             },
+            &AST::Integer(_, n) => {
+                print_spaces(level * 4, f);
+                write!(f, "{}", n)
+            },
+            &AST::Comment(_, ref s) => {
+                print_spaces(level * 4, f);
+                write!(f, ";{}", s)
+            },
         }
     }
 }
@@ -125,6 +177,8 @@ impl Clone for AST {
             &AST::StringLit(ref r, ref s) => AST::StringLit(*r, s.clone()),
             &AST::Identifier(ref r, ref s) => AST::Identifier(*r, s.clone()),
             &AST::Number(ref r, n) => AST::Number(*r, n),
+            &AST::Integer(ref r, n) => AST::Integer(*r, n),
+            &AST::Comment(ref r, ref s) => AST::Comment(*r, s.clone()),
         }       
     }
 }
@@ -148,3 +202,49 @@ impl fmt::Display for AST {
         self.display_recursive(f, 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+
+    fn parse(source: &str) -> Vec<AST> {
+        Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn walk_visits_every_item() {
+        let ast = parse("(&define f (x) \"doc\" (+ x 1.5 ; half\n (list 2)))");
+        // expressions, identifiers, strings, numbers, integers and comments
+        let mut counts = [0; 6];
+        let mut order = Vec::new();
+        ast[0].walk(&mut |item| {
+            let index = match item {
+                &AST::Expr(_, _) => 0,
+                &AST::Identifier(_, ref name) => { order.push(name.clone()); 1 },
+                &AST::StringLit(_, _) => 2,
+                &AST::Number(_, _) => 3,
+                &AST::Integer(_, _) => 4,
+                &AST::Comment(_, _) => 5,
+            };
+            counts[index] += 1;
+        });
+        assert_eq!(counts, [4, 6, 1, 1, 1, 1]);
+        // depth-first, in source order
+        assert_eq!(order, vec!["&define", "f", "x", "+", "x", "list"]);
+    }
+
+    #[test]
+    fn strip_comments_removes_nested_comments() {
+        let mut ast = parse("(list 1 ; one\n (list 2 ; two\n))");
+        ast[0].strip_comments();
+        let mut comments = 0;
+        ast[0].walk(&mut |item| if item.is_comment() { comments += 1 });
+        assert_eq!(comments, 0);
+        assert_eq!(ast[0].exprs().len(), 3);
+        assert_eq!(ast[0].exprs()[2].exprs().len(), 2);
+    }
+}
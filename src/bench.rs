@@ -0,0 +1,120 @@
+use vm::VM;
+use internal::{FunTable, TypeTable};
+use errors::*;
+use util;
+
+use std::time::{Duration, Instant};
+
+/// Timings from running a program several times.
+pub struct BenchResult {
+    pub runs: usize,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl BenchResult {
+    /// Gets the average time that a run took.
+    pub fn mean(&self) -> Duration {
+        self.total / self.runs as u32
+    }
+}
+
+/// Runs a program through the whole pipeline (parsing, preprocessing, compiling and running) the
+/// given number of times, and times each run.
+/// Every run gets a fresh VM, which `setup` is called on first, so runs don't share any functions
+/// or state. The source is only read once, so reading the file isn't timed.
+pub fn run<F: Fn(&mut VM)>(path: &str, runs: usize, setup: F) -> Result<BenchResult> {
+    assert!(runs > 0, "Attempted to benchmark a program with no runs");
+    let source_text = util::read_file(path)?;
+    let mut times = Vec::new();
+    for _ in 0 .. runs {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        setup(&mut vm);
+        let start = Instant::now();
+        vm.eval(path, &source_text)?;
+        times.push(start.elapsed());
+    }
+    Ok(BenchResult {
+        runs: runs,
+        total: times.iter().sum(),
+        min: *times.iter().min().unwrap(),
+        max: *times.iter().max().unwrap(),
+    })
+}
+
+/// Formats a duration in milliseconds.
+pub fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1000000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Gets the path of one of the benchmark programs.
+    fn bench_path(name: &str) -> String {
+        format!("{}/bench/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Calls a function on its own thread, giving what it returns. The benchmark programs recurse
+    /// deeper than a test thread's stack allows, so anything that runs them to the end uses this.
+    fn with_big_stack<T, F>(f: F) -> T
+        where T: Send + 'static,
+              F: FnOnce() -> T + Send + 'static
+    {
+        thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    /// Runs one of the benchmark programs once, describing its last value.
+    fn bench_value(name: &str) -> String {
+        let path = bench_path(name);
+        with_big_stack(move || {
+            let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+            vm.eval(&path, &util::read_file(&path).unwrap())
+                .unwrap()
+                .pop()
+                .unwrap()
+                .to_string()
+        })
+    }
+
+    #[test]
+    fn bench_programs_give_right_answers() {
+        assert_eq!(bench_value("fib.rasp"), "2584");
+        assert_eq!(bench_value("loop.rasp"), "41791750");
+        // each n is added as itself and as two copies
+        assert_eq!(bench_value("lists.rasp"), (3 * 200 * 201 / 2).to_string());
+    }
+
+    #[test]
+    fn run_times_every_run() {
+        let path = bench_path("fib.rasp");
+        let result = with_big_stack(move || run(&path, 3, |_| { }).unwrap());
+        assert_eq!(result.runs, 3);
+        assert!(result.min <= result.mean() && result.mean() <= result.max);
+        assert!(result.total >= result.max);
+    }
+
+    #[test]
+    fn run_applies_setup_to_every_vm() {
+        // the step limit stops the program long before it recurses deeply, so this needs no thread
+        let err = run(&bench_path("fib.rasp"), 2, |vm| vm.set_max_steps(Some(10)))
+            .err()
+            .unwrap();
+        assert_eq!(err.root_message(), "execution step limit exceeded");
+    }
+
+    #[test]
+    fn millis_has_three_places() {
+        assert_eq!(millis(Duration::new(1, 500000)), "1000.500ms");
+        assert_eq!(millis(Duration::new(0, 0)), "0.000ms");
+    }
+}
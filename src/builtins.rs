@@ -1,9 +1,10 @@
 use vm;
 use errors::*;
+use json::{self, Json};
 
 use libc::{
     // libc functions
-    open, close, read, write,
+    open, close, read,
 
     // libc flags
     O_RDONLY, O_WRONLY, O_RDWR, O_CREAT, O_APPEND, O_TRUNC,
@@ -12,7 +13,9 @@ use libc::{
     c_int, c_void,
 };
 
+use std::char;
 use std::collections::HashMap;
+use std::env;
 use std::ffi::CString;
 
 /// Builtin function definition map
@@ -23,24 +26,217 @@ lazy_static! {
         map.insert("stdclose", rasp_close as fn(&mut vm::VM) -> Result<()>);
         map.insert("stdwrite", rasp_write as fn(&mut vm::VM) -> Result<()>);
         map.insert("stdread", rasp_read as fn(&mut vm::VM) -> Result<()>);
+        map.insert("flush", flush as fn(&mut vm::VM) -> Result<()>);
+        map.insert("getenv", rasp_getenv as fn(&mut vm::VM) -> Result<()>);
+        map.insert("setenv", rasp_setenv as fn(&mut vm::VM) -> Result<()>);
 
         map.insert("+", plus as fn(&mut vm::VM) -> Result<()>);
         map.insert("-", minus as fn(&mut vm::VM) -> Result<()>);
         map.insert("*", times as fn(&mut vm::VM) -> Result<()>);
         map.insert("/", divide as fn(&mut vm::VM) -> Result<()>);
+        map.insert("min", min as fn(&mut vm::VM) -> Result<()>);
+        map.insert("max", max as fn(&mut vm::VM) -> Result<()>);
+        map.insert("inc", inc as fn(&mut vm::VM) -> Result<()>);
+        map.insert("dec", dec as fn(&mut vm::VM) -> Result<()>);
+        map.insert("zero?", is_zero as fn(&mut vm::VM) -> Result<()>);
+        map.insert("positive?", is_positive as fn(&mut vm::VM) -> Result<()>);
+        map.insert("negative?", is_negative as fn(&mut vm::VM) -> Result<()>);
+        map.insert("nan?", is_nan as fn(&mut vm::VM) -> Result<()>);
+        map.insert("inf?", is_inf as fn(&mut vm::VM) -> Result<()>);
+        map.insert("even?", is_even as fn(&mut vm::VM) -> Result<()>);
+        map.insert("odd?", is_odd as fn(&mut vm::VM) -> Result<()>);
 
         map.insert("car", car as fn(&mut vm::VM) -> Result<()>);
         map.insert("cdr", cdr as fn(&mut vm::VM) -> Result<()>);
         map.insert("nil?", is_nil as fn(&mut vm::VM) -> Result<()>);
+        map.insert("empty?", is_empty as fn(&mut vm::VM) -> Result<()>);
         map.insert("list", list as fn(&mut vm::VM) -> Result<()>);
         map.insert("append", append as fn(&mut vm::VM) -> Result<()>);
+        map.insert("conj", conj as fn(&mut vm::VM) -> Result<()>);
+        map.insert("set-nth", set_nth as fn(&mut vm::VM) -> Result<()>);
+        map.insert("flatten", flatten as fn(&mut vm::VM) -> Result<()>);
+        map.insert("enumerate", enumerate as fn(&mut vm::VM) -> Result<()>);
+        map.insert("repeat", repeat as fn(&mut vm::VM) -> Result<()>);
+        map.insert("take", take as fn(&mut vm::VM) -> Result<()>);
+        map.insert("drop", drop as fn(&mut vm::VM) -> Result<()>);
+        map.insert("member?", is_member as fn(&mut vm::VM) -> Result<()>);
+        map.insert("sort", sort as fn(&mut vm::VM) -> Result<()>);
         map.insert("string", string as fn(&mut vm::VM) -> Result<()>);
+        map.insert("to-json", to_json as fn(&mut vm::VM) -> Result<()>);
+        map.insert("from-json", from_json as fn(&mut vm::VM) -> Result<()>);
         
         map.insert("=", equals as fn(&mut vm::VM) -> Result<()>);
+        map.insert("!=", not_equals as fn(&mut vm::VM) -> Result<()>);
+
+        map.insert("char->number", char_to_number as fn(&mut vm::VM) -> Result<()>);
+        map.insert("number->char", number_to_char as fn(&mut vm::VM) -> Result<()>);
+        map.insert("char-upcase", char_upcase as fn(&mut vm::VM) -> Result<()>);
+        map.insert("char-downcase", char_downcase as fn(&mut vm::VM) -> Result<()>);
+        map.insert("string-replace", string_replace as fn(&mut vm::VM) -> Result<()>);
+        map.insert("trim", trim as fn(&mut vm::VM) -> Result<()>);
+        map.insert("parse-int", parse_int as fn(&mut vm::VM) -> Result<()>);
+        map.insert("parse-float", parse_float as fn(&mut vm::VM) -> Result<()>);
+        map.insert("upper", upper as fn(&mut vm::VM) -> Result<()>);
+        map.insert("lower", lower as fn(&mut vm::VM) -> Result<()>);
+
+        map.insert("load", load as fn(&mut vm::VM) -> Result<()>);
+        map.insert("exit", exit as fn(&mut vm::VM) -> Result<()>);
         map
     };
 }
 
+lazy_static! {
+    /// Descriptions of the builtin functions, for looking up in the REPL
+    pub static ref BUILTIN_DOCS: HashMap<&'static str, &'static str> = {
+        let mut map = HashMap::new();
+        map.insert("stdopen", "(stdopen path mode) opens a file with an fopen mode, giving its file descriptor");
+        map.insert("stdclose", "(stdclose fd) closes a file descriptor");
+        map.insert("stdwrite", "(stdwrite fd string) writes a string to a file descriptor, giving the result");
+        map.insert("flush", "(flush fd) writes out anything buffered for a file descriptor, with --buffer-writes");
+        map.insert("stdread", "(stdread fd count) reads up to count characters from a file descriptor, giving the result and the contents");
+        map.insert("getenv", "(getenv name) gets the value of an environment variable, or nil if it isn't set");
+        map.insert("setenv", "(setenv name value) sets an environment variable, giving its old value or nil if it wasn't set");
+
+        map.insert("+", "(+ numbers...) adds numbers together; with no numbers, gives 0");
+        map.insert("-", "(- number numbers...) subtracts the rest of the numbers from the first, or negates a single number");
+        map.insert("*", "(* numbers...) multiplies numbers together; with no numbers, gives 1");
+        map.insert("/", "(/ number numbers...) divides the first number by the rest, or takes the reciprocal of a single number");
+        map.insert("min", "(min numbers...) gives the smallest of some numbers");
+        map.insert("max", "(max numbers...) gives the largest of some numbers");
+        map.insert("inc", "(inc number) adds one to a number");
+        map.insert("dec", "(dec number) subtracts one from a number");
+        map.insert("zero?", "(zero? number) gets whether a number is zero");
+        map.insert("positive?", "(positive? number) gets whether a number is greater than zero");
+        map.insert("negative?", "(negative? number) gets whether a number is less than zero");
+        map.insert("nan?", "(nan? number) gets whether a number is NaN (not a number)");
+        map.insert("inf?", "(inf? number) gets whether a number is positive or negative infinity");
+        map.insert("even?", "(even? integer) gets whether an integer is even");
+        map.insert("odd?", "(odd? integer) gets whether an integer is odd");
+
+        map.insert("car", "(car list) gets the first item of a list or string");
+        map.insert("cdr", "(cdr list) gets everything after the first item of a list or string");
+        map.insert("nil?", "(nil? value) gets whether a value is an empty list or string");
+        map.insert("empty?", "(empty? list) gets whether a list or string is empty");
+        map.insert("list", "(list values...) makes a list of its arguments");
+        map.insert("append", "(append lists...) puts lists (or strings) together, in order");
+        map.insert("conj", "(conj list values...) adds values to the end of a list");
+        map.insert("set-nth", "(set-nth list index value) gives a copy of a list with one item replaced; negative indices count from the end");
+        map.insert("flatten", "(flatten list) moves the items of nested lists into the outer list");
+        map.insert("enumerate", "(enumerate list) pairs each item of a list with its index, as (index item) lists");
+        map.insert("repeat", "(repeat count value) makes a list of a value repeated, or repeats a string");
+        map.insert("take", "(take count list) gets the first items of a list or string, up to count of them");
+        map.insert("drop", "(drop count list) gets everything after the first count items of a list or string");
+        map.insert("member?", "(member? value list) gets whether a value is in a list, or a char is in a string");
+        map.insert("sort", "(sort list) sorts a list of numbers or a list of strings");
+        map.insert("string", "(string value) converts a value to a string");
+        map.insert("to-json", "(to-json value) writes a value as JSON text; lists become arrays");
+        map.insert("from-json", "(from-json string) reads JSON text as a value; objects become lists of (key value) lists");
+
+        map.insert("=", "(= a b) gets whether two values are equal");
+        map.insert("!=", "(!= a b) gets whether two values are not equal");
+
+        map.insert("char->number", "(char->number char) gets the code point of a char");
+        map.insert("number->char", "(number->char number) gets the char with a code point");
+        map.insert("char-upcase", "(char-upcase char) converts a char to uppercase");
+        map.insert("char-downcase", "(char-downcase char) converts a char to lowercase");
+        map.insert("string-replace", "(string-replace string pattern replacement) replaces every occurrence of a pattern in a string");
+        map.insert("parse-int", "(parse-int string ? radix) reads an integer from a string, in a radix from 2 to 36 (10 by default)");
+        map.insert("parse-float", "(parse-float string) reads a number from a string, as a float");
+        map.insert("trim", "(trim string) removes whitespace from the start and end of a string");
+        map.insert("upper", "(upper string) converts a string to uppercase");
+        map.insert("lower", "(lower string) converts a string to lowercase");
+
+        map.insert("load", "(load path) compiles and runs a file, so its functions may be called; gives whether it was loaded");
+        map.insert("exit", "(exit status) stops the program with an integer exit status");
+        map
+    };
+}
+
+lazy_static! {
+    /// How many arguments each builtin function takes, as the fewest and the most (`None` if there's
+    /// no limit). Calls are checked against these, so builtins may pop their arguments without
+    /// checking that they're there.
+    pub static ref BUILTIN_ARITIES: HashMap<&'static str, (usize, Option<usize>)> = {
+        let mut map = HashMap::new();
+        map.insert("stdopen", (2, Some(2)));
+        map.insert("stdclose", (1, Some(1)));
+        map.insert("stdwrite", (2, Some(2)));
+        map.insert("stdread", (2, Some(2)));
+        map.insert("flush", (1, Some(1)));
+        map.insert("getenv", (1, Some(1)));
+        map.insert("setenv", (2, Some(2)));
+
+        map.insert("+", (0, None));
+        map.insert("-", (1, None));
+        map.insert("*", (0, None));
+        map.insert("/", (1, None));
+        map.insert("min", (1, None));
+        map.insert("max", (1, None));
+        map.insert("inc", (1, Some(1)));
+        map.insert("dec", (1, Some(1)));
+        map.insert("zero?", (1, Some(1)));
+        map.insert("positive?", (1, Some(1)));
+        map.insert("negative?", (1, Some(1)));
+        map.insert("nan?", (1, Some(1)));
+        map.insert("inf?", (1, Some(1)));
+        map.insert("even?", (1, Some(1)));
+        map.insert("odd?", (1, Some(1)));
+
+        map.insert("car", (1, Some(1)));
+        map.insert("cdr", (1, Some(1)));
+        map.insert("nil?", (1, Some(1)));
+        map.insert("empty?", (1, Some(1)));
+        map.insert("list", (0, None));
+        map.insert("append", (0, None));
+        map.insert("conj", (1, None));
+        map.insert("set-nth", (3, Some(3)));
+        map.insert("flatten", (1, Some(1)));
+        map.insert("enumerate", (1, Some(1)));
+        map.insert("repeat", (2, Some(2)));
+        map.insert("take", (2, Some(2)));
+        map.insert("drop", (2, Some(2)));
+        map.insert("member?", (2, Some(2)));
+        map.insert("sort", (1, Some(1)));
+        map.insert("string", (1, Some(1)));
+        map.insert("to-json", (1, Some(1)));
+        map.insert("from-json", (1, Some(1)));
+
+        map.insert("=", (2, Some(2)));
+        map.insert("!=", (2, Some(2)));
+
+        map.insert("char->number", (1, Some(1)));
+        map.insert("number->char", (1, Some(1)));
+        map.insert("char-upcase", (1, Some(1)));
+        map.insert("char-downcase", (1, Some(1)));
+        map.insert("string-replace", (3, Some(3)));
+        map.insert("trim", (1, Some(1)));
+        map.insert("parse-int", (1, Some(2)));
+        map.insert("parse-float", (1, Some(1)));
+        map.insert("upper", (1, Some(1)));
+        map.insert("lower", (1, Some(1)));
+
+        map.insert("load", (1, Some(1)));
+        map.insert("exit", (1, Some(1)));
+        map
+    };
+}
+
+/// Checks that a builtin function is being called with a number of arguments that it takes.
+pub fn check_arity(name: &str, arg_count: usize) -> Result<()> {
+    let (min_args, max_args) = BUILTIN_ARITIES.get(name)
+        .cloned()
+        .unwrap_or((0, None));
+    if arg_count >= min_args && max_args.map_or(true, |max_args| arg_count <= max_args) {
+        return Ok(());
+    }
+    let takes = match max_args {
+        Some(max_args) if max_args == min_args => format!("exactly {}", min_args),
+        Some(max_args) => format!("{} to {}", min_args, max_args),
+        None => format!("at least {}", min_args),
+    };
+    Err(format!("builtin function {} takes {} arguments, but was called with {}", name, takes, arg_count).into())
+}
+
 /*
 /// Builtin list function
 /// The list function takes n parameters and makes a list out of those parameters.
@@ -57,100 +253,228 @@ pub fn string(v: &mut vm::VM) -> Result<()> {
     Ok(())
 }
 
+/// Builtin to-json function
+/// Writes a value as JSON text, e.g. `(to-json (list 1 "a"))` is `[1,"a"]`. Chars are written as
+/// strings, and lists are always written as arrays, even if they came from JSON objects.
+pub fn to_json(v: &mut vm::VM) -> Result<()> {
+    let item = v.pop_value();
+    let json = value_json(&item)?;
+    v.push(vm::Value::String(json.to_string()));
+    Ok(())
+}
+
+/// Builtin from-json function
+/// Reads JSON text as a value. Arrays become lists, objects become lists of `(key value)` lists in
+/// the order they were written, numbers without a fractional part become integers, and `null`
+/// becomes the empty list.
+pub fn from_json(v: &mut vm::VM) -> Result<()> {
+    let text_val = v.pop_value();
+    if !text_val.is_string() {
+        return Err(format!("argument to `from-json' function must be a string (instead got {})", text_val.type_str()).into());
+    }
+    let json = json::parse(text_val.string())?;
+    v.push(json_value(json));
+    Ok(())
+}
+
+fn value_json(val: &vm::Value) -> Result<Json> {
+    match val {
+        &vm::Value::String(ref s) => Ok(Json::String(s.clone())),
+        &vm::Value::Char(c) => Ok(Json::String(c.to_string())),
+        &vm::Value::Number(n) => Ok(Json::Number(n)),
+        &vm::Value::Integer(i) => Ok(Json::Number(i as f64)),
+        &vm::Value::Boolean(b) => Ok(Json::Bool(b)),
+        &vm::Value::List(ref l) => Ok(Json::Array(l.iter()
+                                                 .map(value_json)
+                                                 .collect::<Result<Vec<Json>>>()?)),
+        v => Err(format!("`to-json' function can't write a {} as JSON", v.type_str()).into()),
+    }
+}
+
+fn json_value(json: Json) -> vm::Value {
+    match json {
+        Json::Null => vm::Value::new_list(Vec::new()),
+        Json::Bool(b) => vm::Value::Boolean(b),
+        Json::Number(n) => if n.fract() == 0.0 && n.abs() < i64::max_value() as f64 {
+            vm::Value::Integer(n as i64)
+        }
+        else {
+            vm::Value::Number(n)
+        },
+        Json::String(s) => vm::Value::String(s),
+        Json::Array(items) => vm::Value::new_list(items.into_iter()
+                                                  .map(json_value)
+                                                  .collect()),
+        Json::Object(fields) => vm::Value::new_list(fields.into_iter()
+                                                    .map(|(key, value)| vm::Value::new_list(vec![vm::Value::String(key),
+                                                                                                 json_value(value)]))
+                                                    .collect()),
+    }
+}
+
+/// Builtin load function
+/// Compiles and runs a file, after which its functions may be called.
+/// Since a program is compiled before it is run, only code that is compiled after the load (e.g.
+/// the body of a function that is first called afterwards) may call the loaded functions.
+/// Leaves whether the file was loaded on the stack; a file is only ever loaded once.
+pub fn load(v: &mut vm::VM) -> Result<()> {
+    let path_val = v.pop_value();
+    if !path_val.is_string() {
+        Err("path to load must be a string".into())
+    }
+    else {
+        let loaded = v.load_file(path_val.string())?;
+        v.push(vm::Value::Boolean(loaded));
+        Ok(())
+    }
+}
+
+/// Builtin exit function
+/// Stops the program with the given integer exit status.
+/// This doesn't exit the process directly; it unwinds the VM with an `Exit` error, and it's up to
+/// whoever is running the VM to actually exit.
+pub fn exit(v: &mut vm::VM) -> Result<()> {
+    let status_val = v.pop_value();
+    if !status_val.is_integer() {
+        Err(format!("exit status must be an integer (instead got {})", status_val.type_str()).into())
+    }
+    else if status_val.integer() < i32::min_value() as i64 || status_val.integer() > i32::max_value() as i64 {
+        Err(format!("exit status {} is out of range", status_val.integer()).into())
+    }
+    else {
+        Err(ErrorKind::Exit(status_val.integer() as i32).into())
+    }
+}
+
 /// Auxiliary function that turns a list into a string.
 fn value_to_string(val: vm::Value) -> String {
     match val {
         vm::Value::String(s) => s,
-        vm::Value::Number(n) => n.to_string(),
+        vm::Value::Number(n) => match vm::non_finite_name(n) {
+            Some(name) => name.to_string(),
+            None => n.to_string(),
+        },
+        vm::Value::Integer(i) => i.to_string(),
+        vm::Value::Char(c) => c.to_string(),
         vm::Value::Identifier(s) => s,
+        vm::Value::Function(s) => s,
+        vm::Value::Closure(c) => c.name.clone(),
         vm::Value::Boolean(b) => b.to_string(),
-        vm::Value::List(l) => {
+        l @ vm::Value::List(_) => {
             let mut constructed = String::new();
-            for i in l {
+            for i in l.into_list() {
                 constructed += &value_to_string(i);
             }
             constructed
         },
-        _ => unreachable!(),
     }
 }
 
 /// Builtin append function
-/// Puts the top two items on the stack together.
+/// Puts all of its arguments together, in order, e.g. `(append (list 1 2) (list 3) (list 4))` is
+/// `(1 2 3 4)`, and `(append "ab" "cd")` is `"abcd"`. The arguments must be either all lists or all
+/// strings; with no arguments, the result is an empty list.
 pub fn append(v: &mut vm::VM) -> Result<()> {
-    let first = v.pop_value();
-    let second = v.pop_value();
-    if !first.is_listy() || !second.is_listy() {
+    let arg_count = v.arg_count();
+    let args = v.pop_values(arg_count)?;
+    if args.iter().any(|a| !a.is_listy()) {
         Err("append takes only listy items".into())
     }
-    else if first.is_list() != second.is_list() {
+    else if args.iter().any(|a| a.is_list() != args[0].is_list()) {
         Err("append arguments either must be both Lists or Strings".into())
     }
-    else if first.is_list() {
-        assert!(second.is_list());
-        let mut list_start = second.into_list();
-        let mut list_end = first.into_list();
-        list_start.append(&mut list_end);
-        v.push(vm::Value::List(list_start));
+    else if args.is_empty() || args[0].is_list() {
+        let mut result_list = Vec::new();
+        for arg in args {
+            result_list.append(&mut arg.into_list());
+        }
+        v.push(vm::Value::new_list(result_list));
         Ok(())
     }
     else {
-        assert!(second.is_string() && first.is_string());
-        v.push(vm::Value::String(second.string().to_string() + first.string()));
+        let mut result_string = String::new();
+        for arg in &args {
+            result_string += arg.string();
+        }
+        v.push(vm::Value::String(result_string));
         Ok(())
     }
 }
 
+/// Builtin conj function
+/// Adds items to the end of a list, e.g. `(conj (list 1 2) 3 4)` is `(1 2 3 4)`. Unlike `append`,
+/// the items are added as they are, so `(conj (list 1) (list 2))` is `(1 (2))`.
+pub fn conj(v: &mut vm::VM) -> Result<()> {
+    let arg_count = v.arg_count();
+    if arg_count == 0 {
+        return Err("conj requires a list to add items to".into());
+    }
+    let mut args = v.pop_values(arg_count)?;
+    let items = args.split_off(1);
+    let list = args.pop()
+        .unwrap();
+    if !list.is_list() {
+        return Err(format!("conj expects a list to add items to, but got a {}", list.type_str()).into());
+    }
+    let mut result_list = list.into_list();
+    result_list.extend(items);
+    v.push(vm::Value::new_list(result_list));
+    Ok(())
+}
+
 /// Builtin = function
 /// Gets whether two items are equal to one another
 pub fn equals(v: &mut vm::VM) -> Result<()> {
     let first = v.pop_value();
     let second = v.pop_value();
-    v.push(vm::Value::Boolean(first == second));
+    let equal = first.equals(&second)?;
+    v.push(vm::Value::Boolean(equal));
+    Ok(())
+}
+
+/// Builtin != function
+/// Gets whether two items are not equal to one another
+pub fn not_equals(v: &mut vm::VM) -> Result<()> {
+    let first = v.pop_value();
+    let second = v.pop_value();
+    let equal = first.equals(&second)?;
+    v.push(vm::Value::Boolean(!equal));
     Ok(())
 }
 
 /// Builtin list function
-/// Gets whether a given listy item is empty.
+/// Makes a list out of all of its arguments.
 pub fn list(v: &mut vm::VM) -> Result<()> {
-    let mut arg_count = v.pop_value()
-        .start_args();
-    let mut result_list = Vec::new();
-    while arg_count >= 0 {
-        if v.peek_value().is_some() {
-            let value = v.pop_value();
-            if value.is_end_args() {
-                break;
-            }
-            else {
-                result_list.push(value);
-            }
-        }
-        else {
-            return Err("VM error: unexpected end of value stack when popping var args".into());
-        }
-        arg_count -= 1;
-    }
-    v.push(vm::Value::List(result_list));
+    let arg_count = v.arg_count();
+    let result_list = v.pop_values(arg_count)?;
+    v.push(vm::Value::new_list(result_list));
     Ok(())
 }
 
 /// Builtin nil? function
-/// Gets whether a given listy item is empty.
+/// Gets whether a given value is an empty list or string. Any other kind of value is never nil.
 pub fn is_nil(v: &mut vm::VM) -> Result<()> {
     let first = v.pop_value();
-    if first.is_listy() {
-        match first {
-            vm::Value::String(ref s) => v.push(vm::Value::Boolean(s.len() == 0)),
-            vm::Value::List(ref l) => v.push(vm::Value::Boolean(l.len() == 0)),
-            _ => unreachable!(),
-        }
-        Ok(())
-    }
-    else {
-        debug!("{:?}", first);
-        Err(format!("argument to `nil?' function must be listy (instead got {})", first.type_str()).into())
+    let is_nil = match first {
+        vm::Value::String(ref s) => s.len() == 0,
+        vm::Value::List(ref l) => l.len() == 0,
+        _ => false,
+    };
+    v.push(vm::Value::Boolean(is_nil));
+    Ok(())
+}
+
+/// Builtin empty? function
+/// Gets whether a list or string has no items. Unlike `nil?`, this is an error for any other kind of
+/// value.
+pub fn is_empty(v: &mut vm::VM) -> Result<()> {
+    let first = v.pop_value();
+    match first {
+        vm::Value::String(ref s) => v.push(vm::Value::Boolean(s.len() == 0)),
+        vm::Value::List(ref l) => v.push(vm::Value::Boolean(l.len() == 0)),
+        _ => return Err(format!("argument to `empty?' function must be listy (instead got {})", first.type_str()).into()),
     }
+    Ok(())
 }
 
 /// Builtin cdr function
@@ -166,13 +490,14 @@ pub fn cdr(v: &mut vm::VM) -> Result<()> {
                     v.push(vm::Value::String(String::new()));
                 },
             vm::Value::List(l) => if l.len() > 0 {
-                    let e = l.into_iter()
+                    let e = l.iter()
                         .skip(1)
+                        .cloned()
                         .collect();
-                    v.push(vm::Value::List(e));
+                    v.push(vm::Value::new_list(e));
                 }
                 else {
-                    v.push(vm::Value::List(Vec::new()));
+                    v.push(vm::Value::new_list(Vec::new()));
                 },
             _ => unreachable!(),
         }
@@ -190,21 +515,16 @@ pub fn car(v: &mut vm::VM) -> Result<()> {
     if first.is_listy() {
         match first {
             vm::Value::String(s) => if let Some(c) = s.chars().nth(0) {
-                    let mut c_str = String::new();
-                    c_str.push(c);
-                    v.push(vm::Value::String(c.to_string()));
+                    v.push(vm::Value::Char(c));
                 }
                 else {
                     v.push(vm::Value::String(String::new()));
                 },
             vm::Value::List(l) => if l.len() > 0 {
-                    let e = l.into_iter()
-                        .nth(0)
-                        .unwrap();
-                    v.push(e);
+                    v.push(l[0].clone());
                 }
                 else {
-                    v.push(vm::Value::List(Vec::new()));
+                    v.push(vm::Value::new_list(Vec::new()));
                 },
             _ => unreachable!(),
         }
@@ -215,59 +535,605 @@ pub fn car(v: &mut vm::VM) -> Result<()> {
     }
 }
 
-/// Builtin + function
-/// The plus function takes two numbers.
-pub fn plus(v: &mut vm::VM) -> Result<()> {
-    let right_val = v.pop_value();
-    let left_val = v.pop_value();
-    if !left_val.is_number() || !right_val.is_number() {
-        Err("+ function may only be used on numbers".into())
+/// Builtin set-nth function
+/// Gets a copy of a list with the item at the given index replaced, e.g. `(set-nth (list 1 2 3) 1 5)`
+/// is `(1 5 3)`.
+pub fn set_nth(v: &mut vm::VM) -> Result<()> {
+    let value = v.pop_value();
+    let index_val = v.pop_value();
+    let list_val = v.pop_value();
+    if !list_val.is_list() {
+        Err(format!("first argument to `set-nth' function must be a list (instead got {})", list_val.type_str()).into())
+    }
+    else if !index_val.is_integer() {
+        Err(format!("second argument to `set-nth' function must be an integer (instead got {})", index_val.type_str()).into())
     }
     else {
-        Ok(v.push(vm::Value::Number(left_val.number() + right_val.number())))
+        let mut list = list_val.into_list();
+        let index = as_index(&index_val, list.len())
+            .chain_err(|| "bad index for `set-nth' function")?;
+        list[index] = value;
+        v.push(vm::Value::new_list(list));
+        Ok(())
     }
 }
 
-/// Builtin - function
-/// The minus function takes two numbers.
-pub fn minus(v: &mut vm::VM) -> Result<()> {
-    // TODO : allow using this function to make single expressions negative?
-    let right_val = v.pop_value();
-    let left_val = v.pop_value();
-    if !left_val.is_number() || !right_val.is_number() {
-        Err("- function may only be used on numbers".into())
+/// Turns an index value into a position in a list or string of the given length. Negative indices
+/// count back from the end, so -1 is the last item. Every builtin that indexes goes through this, so
+/// they all agree on which indices are allowed.
+fn as_index(val: &vm::Value, len: usize) -> Result<usize> {
+    if !val.is_integer() {
+        return Err(format!("index must be an integer (instead got {})", val.type_str()).into());
+    }
+    let index = val.integer();
+    let position = if index < 0 { index + len as i64 } else { index };
+    if position < 0 || position as u64 >= len as u64 {
+        Err(format!("index {} is out of range for a length of {}", index, len).into())
     }
     else {
-        Ok(v.push(vm::Value::Number(left_val.number() - right_val.number())))
+        Ok(position as usize)
     }
 }
 
-/// Builtin * function
-/// The times function takes two numbers.
-pub fn times(v: &mut vm::VM) -> Result<()> {
-    let right_val = v.pop_value();
-    let left_val = v.pop_value();
-    if !left_val.is_number() || !right_val.is_number() {
-        Err("* function may only be used on numbers".into())
+/// Builtin repeat function
+/// Makes a list of a value repeated the given number of times, e.g. `(repeat 3 0)` is `(0 0 0)`.
+/// Repeating a string gives a string instead, so `(repeat 2 "ab")` is `"abab"`; to get a list of
+/// strings, repeat a list holding the string and flatten it.
+pub fn repeat(v: &mut vm::VM) -> Result<()> {
+    let value = v.pop_value();
+    let count_val = v.pop_value();
+    if !count_val.is_integer() {
+        Err(format!("first argument to `repeat' function must be an integer (instead got {})", count_val.type_str()).into())
+    }
+    else if count_val.integer() < 0 {
+        Err(format!("count for `repeat' function must not be negative (instead got {})", count_val.integer()).into())
+    }
+    else {
+        let count = count_val.integer() as usize;
+        let too_large = || format!("count for `repeat' function is too large (there isn't room for {} copies)", count);
+        // the space is reserved up front, so asking for too much is an error instead of a crash
+        if value.is_string() {
+            let mut repeated = String::new();
+            let size = value.string().len().checked_mul(count);
+            if size.map_or(true, |size| repeated.try_reserve_exact(size).is_err()) {
+                return Err(too_large().into());
+            }
+            if !value.string().is_empty() {
+                for _ in 0 .. count {
+                    repeated.push_str(value.string());
+                }
+            }
+            v.push(vm::Value::String(repeated));
+        }
+        else {
+            let mut items = Vec::new();
+            if items.try_reserve_exact(count).is_err() {
+                return Err(too_large().into());
+            }
+            items.resize(count, value);
+            v.push(vm::Value::new_list(items));
+        }
+        Ok(())
+    }
+}
+
+/// Builtin take function
+/// Gets the first items of a list or the first characters of a string, e.g. `(take 2 (list 1 2 3))`
+/// is `(1 2)`. Taking more items than there are gets all of them.
+pub fn take(v: &mut vm::VM) -> Result<()> {
+    take_or_drop(v, "take", true)
+}
+
+/// Builtin drop function
+/// Gets everything after the first items of a list or the first characters of a string, e.g.
+/// `(drop 2 (list 1 2 3))` is `(3)`. Dropping more items than there are gets an empty list or string.
+pub fn drop(v: &mut vm::VM) -> Result<()> {
+    take_or_drop(v, "drop", false)
+}
+
+/// Splits a list or string after a count of items, clamped to its length, and keeps either the
+/// items before the split (taking) or after it (dropping).
+fn take_or_drop(v: &mut vm::VM, name: &str, take: bool) -> Result<()> {
+    let listy_val = v.pop_value();
+    let count_val = v.pop_value();
+    if !count_val.is_integer() {
+        return Err(format!("first argument to `{}' function must be an integer (instead got {})", name, count_val.type_str())
+                   .into());
+    }
+    else if count_val.integer() < 0 {
+        return Err(format!("count for `{}' function must not be negative (instead got {})", name, count_val.integer()).into());
+    }
+    let count = count_val.integer() as usize;
+    match listy_val {
+        l @ vm::Value::List(_) => {
+            let mut l = l.into_list();
+            let split = count.min(l.len());
+            let rest = l.split_off(split);
+            v.push(vm::Value::new_list(if take { l } else { rest }));
+        },
+        vm::Value::String(ref s) => {
+            let split = s.char_indices()
+                .nth(count)
+                .map(|(index, _)| index)
+                .unwrap_or(s.len());
+            let (first, rest) = s.split_at(split);
+            v.push(vm::Value::String(if take { first } else { rest }.to_string()));
+        },
+        _ => return Err(format!("second argument to `{}' function must be listy (instead got {})", name, listy_val.type_str())
+                        .into()),
+    }
+    Ok(())
+}
+
+/// Builtin member? function
+/// Gets whether a value is an item of a list, using the same equality as `=`, e.g.
+/// `(member? 2 (list 1 2 3))` is true. For a string, this gets whether a char is one of its
+/// characters; anything other than a char is never in a string.
+pub fn is_member(v: &mut vm::VM) -> Result<()> {
+    let list_val = v.pop_value();
+    let value = v.pop_value();
+    let is_member = match list_val {
+        vm::Value::List(ref l) => l.contains(&value),
+        vm::Value::String(ref s) => match value {
+            vm::Value::Char(c) => s.contains(c),
+            _ => false,
+        },
+        _ => return Err(format!("second argument to `member?' function must be listy (instead got {})", list_val.type_str()).into()),
+    };
+    v.push(vm::Value::Boolean(is_member));
+    Ok(())
+}
+
+/// Builtin sort function
+/// Gets a copy of a list with its items in ascending order. The items must either all be numbers,
+/// which are sorted by value, or all be strings, which are sorted lexicographically. NaN can't be
+/// sorted, since it isn't less than, equal to or greater than anything.
+pub fn sort(v: &mut vm::VM) -> Result<()> {
+    let list_val = v.pop_value();
+    if !list_val.is_list() {
+        return Err(format!("argument to `sort' function must be a list (instead got {})", list_val.type_str()).into());
+    }
+    let mut list = list_val.into_list();
+    if list.iter().all(|x| x.is_number()) {
+        if let Some(index) = list.iter().position(|x| x.number().is_nan()) {
+            return Err(format!("`sort' function cannot sort NaN (the item at index {} is NaN)", index).into());
+        }
+        list.sort_by(|a, b| a.number().total_cmp(&b.number()));
+    }
+    else if list.iter().all(|x| x.is_string()) {
+        list.sort_by(|a, b| a.string().cmp(b.string()));
+    }
+    else {
+        let mut types = list.iter()
+            .map(|x| x.type_str())
+            .collect::<Vec<_>>();
+        types.sort();
+        types.dedup();
+        return Err(format!("`sort' function can only sort a list of all numbers or all strings (instead got {})",
+                           types.join(", ")).into());
+    }
+    v.push(vm::Value::new_list(list));
+    Ok(())
+}
+
+/// Builtin flatten function
+/// Splices the items of any nested lists into a single list, e.g.
+/// `(flatten (list 1 (list 2 3) (list (list 4))))` is `(1 2 3 4)`.
+pub fn flatten(v: &mut vm::VM) -> Result<()> {
+    let list_val = v.pop_value();
+    if !list_val.is_list() {
+        Err(format!("argument to `flatten' function must be a list (instead got {})", list_val.type_str()).into())
+    }
+    else {
+        let mut flat = Vec::new();
+        flatten_into(list_val.into_list(), &mut flat);
+        v.push(vm::Value::new_list(flat));
+        Ok(())
+    }
+}
+
+/// Appends the items of a list to another list, recursively flattening any nested lists.
+fn flatten_into(list: Vec<vm::Value>, flat: &mut Vec<vm::Value>) {
+    for value in list {
+        if value.is_list() {
+            flatten_into(value.into_list(), flat);
+        }
+        else {
+            flat.push(value);
+        }
+    }
+}
+
+/// Builtin enumerate function
+/// Pairs each item of a list with its index, e.g. `(enumerate (list "a" "b"))` is
+/// `((0 "a") (1 "b"))`.
+pub fn enumerate(v: &mut vm::VM) -> Result<()> {
+    let list_val = v.pop_value();
+    if !list_val.is_list() {
+        return Err(format!("argument to `enumerate' function must be a list (instead got {})", list_val.type_str()).into());
+    }
+    let pairs = list_val.into_list()
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| vm::Value::new_list(vec![vm::Value::Integer(index as i64), value]))
+        .collect();
+    v.push(vm::Value::new_list(pairs));
+    Ok(())
+}
+
+/// Builtin char->number function
+/// Gets the Unicode code point of a character.
+pub fn char_to_number(v: &mut vm::VM) -> Result<()> {
+    let char_val = v.pop_value();
+    if !char_val.is_char() {
+        Err(format!("argument to `char->number' function must be a char (instead got {})", char_val.type_str()).into())
+    }
+    else {
+        v.push(vm::Value::Integer(char_val.char() as i64));
+        Ok(())
+    }
+}
+
+/// Builtin number->char function
+/// Gets the character for a Unicode code point.
+pub fn number_to_char(v: &mut vm::VM) -> Result<()> {
+    let num_val = v.pop_value();
+    if !num_val.is_integer() {
+        Err(format!("argument to `number->char' function must be an integer (instead got {})", num_val.type_str()).into())
+    }
+    else {
+        let num = num_val.integer();
+        let c = if num < 0 || num > u32::max_value() as i64 { None } else { char::from_u32(num as u32) };
+        match c {
+            Some(c) => {
+                v.push(vm::Value::Char(c));
+                Ok(())
+            },
+            None => Err(format!("{} is not a valid character code", num).into()),
+        }
+    }
+}
+
+/// Builtin char-upcase function
+/// Gets the uppercase version of a character.
+/// Characters whose uppercase version is more than one character (e.g. ß) are left as-is.
+pub fn char_upcase(v: &mut vm::VM) -> Result<()> {
+    let char_val = v.pop_value();
+    if !char_val.is_char() {
+        Err(format!("argument to `char-upcase' function must be a char (instead got {})", char_val.type_str()).into())
+    }
+    else {
+        v.push(vm::Value::Char(single_char(char_val.char().to_uppercase(), char_val.char())));
+        Ok(())
+    }
+}
+
+/// Builtin char-downcase function
+/// Gets the lowercase version of a character.
+/// Characters whose lowercase version is more than one character are left as-is.
+pub fn char_downcase(v: &mut vm::VM) -> Result<()> {
+    let char_val = v.pop_value();
+    if !char_val.is_char() {
+        Err(format!("argument to `char-downcase' function must be a char (instead got {})", char_val.type_str()).into())
+    }
+    else {
+        v.push(vm::Value::Char(single_char(char_val.char().to_lowercase(), char_val.char())));
+        Ok(())
+    }
+}
+
+/// Builtin string-replace function
+/// Replaces every occurrence of a pattern in a string, e.g. `(string-replace "a-b-c" "-" "+")` is
+/// `"a+b+c"`. The pattern can't be empty, since an empty pattern occurs everywhere.
+pub fn string_replace(v: &mut vm::VM) -> Result<()> {
+    let replacement_val = v.pop_value();
+    let pattern_val = v.pop_value();
+    let string_val = v.pop_value();
+    for &(what, ref val) in &[("string", &string_val), ("pattern", &pattern_val), ("replacement", &replacement_val)] {
+        if !val.is_string() {
+            return Err(format!("{} given to `string-replace' function must be a string (instead got {})", what, val.type_str())
+                       .into());
+        }
+    }
+    if pattern_val.string().is_empty() {
+        Err("pattern given to `string-replace' function must not be empty".into())
+    }
+    else {
+        v.push(vm::Value::String(string_val.string().replace(pattern_val.string(), replacement_val.string())));
+        Ok(())
+    }
+}
+
+/// Builtin parse-int function
+/// Reads an integer from a string, with an optional radix from 2 to 36, e.g. `(parse-int "ff" 16)`
+/// is 255. The whole string must be a valid integer in the radix, with an optional sign.
+pub fn parse_int(v: &mut vm::VM) -> Result<()> {
+    let arg_count = v.arg_count();
+    if arg_count < 1 || arg_count > 2 {
+        return Err(format!("`parse-int' function takes 1 or 2 arguments (instead got {})", arg_count).into());
+    }
+    let radix = if arg_count == 2 {
+        let radix_val = v.pop_value();
+        if !radix_val.is_integer() {
+            return Err(format!("radix given to `parse-int' function must be an integer (instead got {})", radix_val.type_str())
+                       .into());
+        }
+        else if radix_val.integer() < 2 || radix_val.integer() > 36 {
+            return Err(format!("radix given to `parse-int' function must be from 2 to 36 (instead got {})", radix_val.integer())
+                       .into());
+        }
+        radix_val.integer() as u32
+    }
+    else {
+        10
+    };
+    let string_val = v.pop_value();
+    if !string_val.is_string() {
+        return Err(format!("argument to `parse-int' function must be a string (instead got {})", string_val.type_str()).into());
+    }
+    match i64::from_str_radix(string_val.string(), radix) {
+        Ok(i) => {
+            v.push(vm::Value::Integer(i));
+            Ok(())
+        },
+        Err(e) => Err(format!("could not parse {:?} as an integer in radix {}: {}", string_val.string(), radix, e).into()),
+    }
+}
+
+/// Builtin parse-float function
+/// Reads a number from a string as a float, e.g. `(parse-float "2.5")` is 2.5.
+pub fn parse_float(v: &mut vm::VM) -> Result<()> {
+    let string_val = v.pop_value();
+    if !string_val.is_string() {
+        return Err(format!("argument to `parse-float' function must be a string (instead got {})", string_val.type_str()).into());
+    }
+    match string_val.string().parse::<f64>() {
+        Ok(n) => {
+            v.push(vm::Value::Number(n));
+            Ok(())
+        },
+        Err(e) => Err(format!("could not parse {:?} as a number: {}", string_val.string(), e).into()),
+    }
+}
+
+/// Builtin trim function
+/// Removes whitespace from the start and end of a string.
+pub fn trim(v: &mut vm::VM) -> Result<()> {
+    map_string(v, "trim", |s| s.trim().to_string())
+}
+
+/// Builtin upper function
+/// Gets the uppercase version of a string. Unlike `char-upcase`, characters whose uppercase version
+/// is more than one character are expanded, e.g. `(upper "straße")` is `"STRASSE"`.
+pub fn upper(v: &mut vm::VM) -> Result<()> {
+    map_string(v, "upper", |s| s.to_uppercase())
+}
+
+/// Builtin lower function
+/// Gets the lowercase version of a string.
+pub fn lower(v: &mut vm::VM) -> Result<()> {
+    map_string(v, "lower", |s| s.to_lowercase())
+}
+
+/// Auxiliary function for builtins that turn a string into another string.
+fn map_string<F: Fn(&str) -> String>(v: &mut vm::VM, name: &str, f: F) -> Result<()> {
+    let string_val = v.pop_value();
+    if !string_val.is_string() {
+        Err(format!("argument to `{}' function must be a string (instead got {})", name, string_val.type_str()).into())
     }
     else {
-        Ok(v.push(vm::Value::Number(left_val.number() * right_val.number())))
+        v.push(vm::Value::String(f(string_val.string())));
+        Ok(())
     }
 }
 
+/// Auxiliary function that gets the only character of a case mapping, or `default` if the mapping
+/// is not exactly one character.
+fn single_char<I: Iterator<Item=char>>(mut chars: I, default: char) -> char {
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => default,
+    }
+}
+
+/// Auxiliary function that applies a binary arithmetic function to two numbers.
+/// If both numbers are integers, `int_op` is used, so the result stays exact. If either number is a
+/// float, or `int_op` can't produce an exact result (e.g. on overflow), both numbers are promoted to
+/// floats and `float_op` is used instead.
+fn apply_arithmetic(left_val: &vm::Value, right_val: &vm::Value, int_op: fn(i64, i64) -> Option<i64>,
+                    float_op: fn(f64, f64) -> f64) -> vm::Value {
+    if left_val.is_integer() && right_val.is_integer() {
+        if let Some(result) = int_op(left_val.integer(), right_val.integer()) {
+            return vm::Value::Integer(result);
+        }
+    }
+    vm::Value::Number(float_op(left_val.number(), right_val.number()))
+}
+
+/// Auxiliary function that folds an arithmetic function over all of the arguments on the stack,
+/// from left to right.
+/// A single argument is combined with `identity` first, so e.g. `(- x)` negates `x`. With no
+/// arguments, `identity` itself is the result, unless `needs_args` is set.
+fn arithmetic(v: &mut vm::VM, name: &str, identity: i64, needs_args: bool, int_op: fn(i64, i64) -> Option<i64>,
+              float_op: fn(f64, f64) -> f64) -> Result<()> {
+    let arg_count = v.arg_count();
+    let mut args = v.pop_values(arg_count)?;
+    if args.iter().any(|a| !a.is_number()) {
+        return Err(format!("{} function may only be used on numbers", name).into());
+    }
+    if args.len() < 2 {
+        if args.is_empty() && needs_args {
+            return Err(format!("{} function requires at least one argument", name).into());
+        }
+        args.insert(0, vm::Value::Integer(identity));
+    }
+    let mut args = args.into_iter();
+    let first = args.next().unwrap();
+    let result = args.fold(first, |acc, arg| apply_arithmetic(&acc, &arg, int_op, float_op));
+    v.push(result);
+    Ok(())
+}
+
+/// Builtin + function
+/// The plus function adds any number of numbers together; with no numbers, it gives 0.
+pub fn plus(v: &mut vm::VM) -> Result<()> {
+    arithmetic(v, "+", 0, false, i64::checked_add, |a, b| a + b)
+}
+
+/// Builtin - function
+/// The minus function subtracts the rest of its numbers from the first one. With only one number,
+/// it negates that number.
+pub fn minus(v: &mut vm::VM) -> Result<()> {
+    arithmetic(v, "-", 0, true, i64::checked_sub, |a, b| a - b)
+}
+
+/// Builtin * function
+/// The times function multiplies any number of numbers together; with no numbers, it gives 1.
+pub fn times(v: &mut vm::VM) -> Result<()> {
+    arithmetic(v, "*", 1, false, i64::checked_mul, |a, b| a * b)
+}
+
 /// Builtin / function
-/// The divide function takes two numbers.
+/// The divide function divides the first of its numbers by the rest of them. With only one number,
+/// it gives the reciprocal of that number.
+/// Dividing two integers only gives an integer if the division is exact.
 pub fn divide(v: &mut vm::VM) -> Result<()> {
-    let right_val = v.pop_value();
-    let left_val = v.pop_value();
-    if !left_val.is_number() || !right_val.is_number() {
-        Err("/ function may only be used on numbers".into())
+    arithmetic(v, "/", 1, true,
+               |a, b| match a.checked_rem(b) {
+                   Some(0) => a.checked_div(b),
+                   _ => None,
+               },
+               |a, b| a / b)
+}
+
+/// Auxiliary function that picks the argument on the stack that `pick` prefers, comparing by
+/// numeric value. The picked argument is left on the stack unchanged, so an integer stays an integer.
+fn extremum(v: &mut vm::VM, name: &str, pick: fn(f64, f64) -> bool) -> Result<()> {
+    let arg_count = v.arg_count();
+    let args = v.pop_values(arg_count)?;
+    if args.is_empty() {
+        return Err(format!("{} function requires at least one argument", name).into());
+    }
+    if args.iter().any(|a| !a.is_number()) {
+        return Err(format!("{} function may only be used on numbers", name).into());
+    }
+    let mut args = args.into_iter();
+    let first = args.next().unwrap();
+    let result = args.fold(first, |best, arg| if pick(arg.number(), best.number()) { arg } else { best });
+    v.push(result);
+    Ok(())
+}
+
+/// Builtin min function
+/// Gets the smallest of one or more numbers.
+pub fn min(v: &mut vm::VM) -> Result<()> {
+    extremum(v, "min", |a, b| a < b)
+}
+
+/// Builtin max function
+/// Gets the largest of one or more numbers.
+pub fn max(v: &mut vm::VM) -> Result<()> {
+    extremum(v, "max", |a, b| a > b)
+}
+
+/// Builtin inc function
+/// Adds one to a number.
+pub fn inc(v: &mut vm::VM) -> Result<()> {
+    let num_val = v.pop_value();
+    if !num_val.is_number() {
+        Err(format!("argument to `inc' function must be a number (instead got {})", num_val.type_str()).into())
+    }
+    else {
+        v.push(apply_arithmetic(&num_val, &vm::Value::Integer(1), i64::checked_add, |a, b| a + b));
+        Ok(())
+    }
+}
+
+/// Builtin dec function
+/// Subtracts one from a number.
+pub fn dec(v: &mut vm::VM) -> Result<()> {
+    let num_val = v.pop_value();
+    if !num_val.is_number() {
+        Err(format!("argument to `dec' function must be a number (instead got {})", num_val.type_str()).into())
+    }
+    else {
+        v.push(apply_arithmetic(&num_val, &vm::Value::Integer(1), i64::checked_sub, |a, b| a - b));
+        Ok(())
+    }
+}
+
+/// Auxiliary function that pops a number off of the stack and pushes whether `pred` holds for it.
+fn number_predicate(v: &mut vm::VM, name: &str, pred: fn(f64) -> bool) -> Result<()> {
+    let num_val = v.pop_value();
+    if !num_val.is_number() {
+        Err(format!("argument to `{}' function must be a number (instead got {})", name, num_val.type_str()).into())
     }
     else {
-        Ok(v.push(vm::Value::Number(left_val.number() / right_val.number())))
+        v.push(vm::Value::Boolean(pred(num_val.number())));
+        Ok(())
     }
 }
 
+/// Builtin zero? function
+/// Gets whether a number is zero.
+pub fn is_zero(v: &mut vm::VM) -> Result<()> {
+    number_predicate(v, "zero?", |n| n == 0.0)
+}
+
+/// Builtin positive? function
+/// Gets whether a number is greater than zero.
+pub fn is_positive(v: &mut vm::VM) -> Result<()> {
+    number_predicate(v, "positive?", |n| n > 0.0)
+}
+
+/// Builtin negative? function
+/// Gets whether a number is less than zero.
+pub fn is_negative(v: &mut vm::VM) -> Result<()> {
+    number_predicate(v, "negative?", |n| n < 0.0)
+}
+
+/// Builtin nan? function
+/// Gets whether a number is NaN, e.g. the result of `(/ 0.0 0.0)`. NaN isn't equal to anything,
+/// even itself, so `=` can't be used to check for it.
+pub fn is_nan(v: &mut vm::VM) -> Result<()> {
+    number_predicate(v, "nan?", |n| n.is_nan())
+}
+
+/// Builtin inf? function
+/// Gets whether a number is infinite, either positive or negative.
+pub fn is_inf(v: &mut vm::VM) -> Result<()> {
+    number_predicate(v, "inf?", |n| n.is_infinite())
+}
+
+/// Auxiliary function that pops an integer off of the stack and pushes whether it has the given
+/// parity (0 for even, 1 for odd). Floats with no fractional part, e.g. `4.0`, count as integers.
+fn parity_predicate(v: &mut vm::VM, name: &str, parity: i64) -> Result<()> {
+    let num_val = v.pop_value();
+    match num_val {
+        vm::Value::Integer(n) => {
+            v.push(vm::Value::Boolean(n.rem_euclid(2) == parity));
+            Ok(())
+        },
+        vm::Value::Number(n) if n.is_finite() && n.floor() == n => {
+            v.push(vm::Value::Boolean(n.rem_euclid(2.0) == parity as f64));
+            Ok(())
+        },
+        _ => Err(format!("argument to `{}' function must be an integer (instead got {})", name, num_val.type_str()).into()),
+    }
+}
+
+/// Builtin even? function
+/// Gets whether an integer is even.
+pub fn is_even(v: &mut vm::VM) -> Result<()> {
+    parity_predicate(v, "even?", 0)
+}
+
+/// Builtin odd? function
+/// Gets whether an integer is odd.
+pub fn is_odd(v: &mut vm::VM) -> Result<()> {
+    parity_predicate(v, "odd?", 1)
+}
+
 /// Builtin function for opening files.
 /// The open function takes a path string and a mode string.
 /// Leaves the new file descriptor on the stack.
@@ -296,7 +1162,27 @@ pub fn rasp_open(v: &mut vm::VM) -> Result<()> {
         let fd = unsafe {
             open(CString::new(path).unwrap().as_ptr(), open_flags, 0o644)
         };
-        Ok(v.push(vm::Value::Number(fd as f64)))
+        Ok(v.push(vm::Value::Integer(fd as i64)))
+    }
+}
+
+/// Gets a file descriptor from a value, which must be a non-negative integer.
+fn as_fd(fd_val: &vm::Value) -> Result<c_int> {
+    if !fd_val.is_number() {
+        return Err(format!("file descriptor must be a number (instead got {})", fd_val.type_str()).into());
+    }
+    let fd_num = fd_val.number();
+    if fd_num.floor() != fd_num {
+        Err("file descriptor must be an integer".into())
+    }
+    else if fd_num < 0.0 {
+        Err(format!("file descriptor must not be negative (got {})", fd_num).into())
+    }
+    else if fd_num > c_int::max_value() as f64 {
+        Err(format!("file descriptor {} is out of range", fd_num).into())
+    }
+    else {
+        Ok(fd_num as c_int)
     }
 }
 
@@ -304,23 +1190,63 @@ pub fn rasp_open(v: &mut vm::VM) -> Result<()> {
 /// The close function takes a file descriptor int.
 /// Leaves the close result on the stack.
 pub fn rasp_close(v: &mut vm::VM) -> Result<()> {
-    let fd_val = v.pop_value();
-    if !fd_val.is_number() {
-        Err("file descriptor must be a number".into())
+    let fd = as_fd(&v.pop_value())?;
+    v.flush_fd(fd);
+    let result = unsafe {
+        close(fd)
+    };
+    v.push(vm::Value::Integer(result as i64));
+    Ok(())
+}
+
+/// Builtin function for reading environment variables.
+/// The getenv function takes the name of the variable.
+/// Leaves the variable's value on the stack, or nil (an empty list) if it isn't set.
+pub fn rasp_getenv(v: &mut vm::VM) -> Result<()> {
+    let name_val = v.pop_value();
+    let name = env_name(&name_val)?;
+    v.push(env_value(name));
+    Ok(())
+}
+
+/// Builtin function for setting environment variables.
+/// The setenv function takes the name of the variable, and the string to set it to.
+/// Leaves the variable's old value on the stack, or nil (an empty list) if it wasn't set.
+pub fn rasp_setenv(v: &mut vm::VM) -> Result<()> {
+    let value_val = v.pop_value();
+    let name_val = v.pop_value();
+    let name = env_name(&name_val)?;
+    if !value_val.is_string() {
+        return Err("environment variable value must be a string".into());
+    }
+    else if value_val.string().contains('\0') {
+        return Err("environment variable value must not contain a NUL character".into());
+    }
+    v.push(env_value(name));
+    env::set_var(name, value_val.string());
+    Ok(())
+}
+
+/// Gets an environment variable name from a value, checking that the environment would accept it.
+fn env_name(name_val: &vm::Value) -> Result<&str> {
+    if !name_val.is_string() {
+        return Err("environment variable name must be a string".into());
+    }
+    let name = name_val.string();
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        Err(format!("invalid environment variable name {:?}", name).into())
     }
     else {
-        let fd_num = fd_val.number();
-        if fd_num.floor() != fd_num {
-            Err("file descriptor must be an integer".into())
-        }
-        else {
-            let fd = fd_num as c_int;
-            let result = unsafe {
-                close(fd)
-            };
-            v.push(vm::Value::Number(result as f64));
-            Ok(())
-        }
+        Ok(name)
+    }
+}
+
+/// Gets the value of an environment variable as a string, or nil if it isn't set. Values that
+/// aren't valid unicode have their invalid parts replaced.
+fn env_value(name: &str) -> vm::Value {
+    match env::var_os(name) {
+        Some(value) => vm::Value::String(value.to_string_lossy().into_owned()),
+        None => vm::Value::new_list(Vec::new()),
     }
 }
 
@@ -329,64 +1255,49 @@ pub fn rasp_close(v: &mut vm::VM) -> Result<()> {
 /// Leaves the write result on the stack.
 pub fn rasp_write(v: &mut vm::VM) -> Result<()> {
     let buffer_val = v.pop_value();
-    let fd_val = v.pop_value();
+    let fd = as_fd(&v.pop_value())?;
     if !buffer_val.is_string() {
         Err("buffer must be a string".into())
     }
-    else if !fd_val.is_number() {
-        Err("file descriptor must be a number".into())
-    }
     else {
-        let fd_num = fd_val.number();
-        if fd_num.floor() != fd_num {
-            Err("file descriptor must be an integer".into())
-        }
-        else if fd_num.is_sign_negative() {
-            Err("file descriptor must be positive".into())
-        }
-        else {
-            let fd = fd_num as c_int;
-            let buffer = buffer_val.string();
-            let result = unsafe {
-                let buffer_cstr = CString::new(buffer)
-                    .unwrap();
-                write(fd, buffer_cstr.as_ptr() as *const c_void, buffer.len() + 1)
-            };
-            v.push(vm::Value::Number(result as f64));
-            Ok(())
-        }
+        let buffer_cstr = match CString::new(buffer_val.string()) {
+            Ok(buffer_cstr) => buffer_cstr,
+            Err(_) => return Err("string contains a NUL byte".into()),
+        };
+        let result = v.write_fd(fd, buffer_cstr.as_bytes_with_nul());
+        v.push(vm::Value::Integer(result as i64));
+        Ok(())
     }
 }
 
+/// Builtin flush function
+/// Writes out anything buffered for a file descriptor, when writes are buffered.
+/// Leaves the write result on the stack, which is 0 if nothing was buffered.
+pub fn flush(v: &mut vm::VM) -> Result<()> {
+    let fd = as_fd(&v.pop_value())?;
+    let result = v.flush_fd(fd);
+    v.push(vm::Value::Integer(result as i64));
+    Ok(())
+}
+
 /// Builtin function for reading from files.
 /// The read function takes a file descriptor and the number of characters to read.
 /// Leaves a list of the result and the contents on the stack.
 pub fn rasp_read(v: &mut vm::VM) -> Result<()> {
     let count_val = v.pop_value();
-    let fd_val = v.pop_value();
+    let fd = as_fd(&v.pop_value())?;
     if !count_val.is_number() {
         Err("count must be a number ".into())
     }
-    else if !fd_val.is_number() {
-        Err("file descriptor must be a number".into())
-    }
     else {
-        let fd_num = fd_val.number();
         let count_num = count_val.number();
-        if fd_num.floor() != fd_num {
-            Err("file descriptor must be an integer".into())
-        }
-        else if fd_num.is_sign_negative() {
-            Err("file descriptor must be positive".into())
-        }
-        else if count_num.floor() != count_num {
+        if count_num.floor() != count_num {
             Err("count must be an integer".into())
         }
         else if count_num.is_sign_negative() {
             Err("count must be positive".into())
         }
         else {
-            let fd = fd_num as c_int;
             let count = count_num as usize;
             let mut buffer_vec = Vec::new();
             buffer_vec.resize(count, 0 as u8);
@@ -397,12 +1308,515 @@ pub fn rasp_read(v: &mut vm::VM) -> Result<()> {
             };
             let result_vec = buffer_cstr.into_bytes()
                 .into_iter()
-                .map(|x| vm::Value::Number(x as f64))
+                .map(|x| vm::Value::Integer(x as i64))
                 .collect();
-            v.push(vm::Value::List(vec![
-                                   vm::Value::Number(result as f64),
-                                   vm::Value::List(result_vec)]));
+            v.push(vm::Value::new_list(vec![
+                                   vm::Value::Integer(result as i64),
+                                   vm::Value::new_list(result_vec)]));
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BUILTIN_FUNCTIONS, BUILTIN_ARITIES, BUILTIN_DOCS, as_fd, as_index};
+    use vm::Value;
+    use vm::tests::{run_source, run_value, run_error, temp_path, temp_source};
+
+    #[test]
+    fn integer_math_is_exact() {
+        match run_value("(+ 9007199254740993 1)") {
+            Value::Integer(i) => assert_eq!(i, 9007199254740994),
+            v => panic!("expected an integer, but got {:?}", v),
+        }
+        match run_value("(* 3 4)") {
+            Value::Integer(i) => assert_eq!(i, 12),
+            v => panic!("expected an integer, but got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn mixed_math_promotes_to_float() {
+        match run_value("(+ 1 0.5)") {
+            Value::Number(n) => assert_eq!(n, 1.5),
+            v => panic!("expected a float, but got {:?}", v),
+        }
+        match run_value("(/ 3 2)") {
+            Value::Number(n) => assert_eq!(n, 1.5),
+            v => panic!("expected a float, but got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn integer_overflow_promotes_to_float() {
+        match run_value("(+ 9223372036854775807 1)") {
+            Value::Number(n) => assert_eq!(n, 9223372036854775808.0),
+            v => panic!("expected a float, but got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn arithmetic_rejects_non_numbers() {
+        assert!(run_error("(+ 1 \"a\")").contains("+ function may only be used on numbers"));
+    }
+
+    #[test]
+    fn load_makes_functions_callable() {
+        let path = temp_source("other.rasp", "(&define greet () (list 1 2))");
+        let source = format!("(&define call-greet () (greet)) (load \"{}\") (call-greet)", path);
+        assert_eq!(run_value(&source), Value::from(vec![Value::Integer(1), Value::Integer(2)]));
+    }
+
+    #[test]
+    fn load_only_loads_once() {
+        let path = temp_source("once.rasp", "(&define once () 1)");
+        let source = format!("(list (load \"{0}\") (load \"{0}\"))", path);
+        assert_eq!(run_value(&source), Value::from(vec![Value::Boolean(true), Value::Boolean(false)]));
+    }
+
+    #[test]
+    fn load_rejects_recursion() {
+        let path = temp_source("self.rasp", &format!("(load \"{}\")", temp_path("self.rasp")));
+        assert!(run_error(&format!("(load \"{}\")", path)).contains("recursive load"));
+    }
+
+    #[test]
+    fn car_of_string_is_char() {
+        assert_eq!(run_value("(car \"abc\")"), Value::Char('a'));
+    }
+
+    #[test]
+    fn chars_round_trip_through_numbers() {
+        assert_eq!(run_value("(char->number (car \"a\"))"), Value::Integer(97));
+        assert_eq!(run_value("(number->char 955)"), Value::Char('λ'));
+        assert_eq!(run_value("(number->char (char->number (car \"z\")))"), Value::Char('z'));
+        assert!(run_error("(number->char 55296)").contains("55296 is not a valid character code"));
+    }
+
+    #[test]
+    fn chars_change_case() {
+        assert_eq!(run_value("(char-upcase (car \"q\"))"), Value::Char('Q'));
+        assert_eq!(run_value("(char-downcase (car \"Q\"))"), Value::Char('q'));
+        // the uppercase of ß is two chars, so it's left alone
+        assert_eq!(run_value("(char-upcase (car \"ß\"))"), Value::Char('ß'));
+    }
+
+    #[test]
+    fn not_equals_negates_equality() {
+        assert_eq!(run_value("(!= 1 1)"), Value::Boolean(false));
+        assert_eq!(run_value("(!= 1 2)"), Value::Boolean(true));
+        assert_eq!(run_value("(!= 1 1.0)"), Value::Boolean(false));
+        assert_eq!(run_value("(!= \"ab\" \"ab\")"), Value::Boolean(false));
+        assert_eq!(run_value("(!= \"ab\" \"ba\")"), Value::Boolean(true));
+        assert_eq!(run_value("(!= (list 1 (list 2)) (list 1 (list 2)))"), Value::Boolean(false));
+        assert_eq!(run_value("(!= (list 1 2) (list 1 2 3))"), Value::Boolean(true));
+        assert_eq!(run_value("(!= 1 \"1\")"), Value::Boolean(true));
+    }
+
+    #[test]
+    fn append_keeps_source_order() {
+        assert_eq!(run_value("(append (list 1 2) (list 3 4))"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Integer(4)]));
+        assert_eq!(run_value("(append \"ab\" \"cd\")"), Value::from("abcd"));
+        assert_eq!(run_value("(append \"a\" \"b\" \"c\")"), Value::from("abc"));
+        assert_eq!(run_value("(append)"), Value::new_list(vec![]));
+    }
+
+    #[test]
+    fn append_rejects_mixed_arguments() {
+        assert!(run_error("(append (list 1) \"a\")").contains("append arguments either must be both Lists or Strings"));
+        assert!(run_error("(append (list 1) 2)").contains("append takes only listy items"));
+    }
+
+    #[test]
+    fn set_nth_replaces_an_item() {
+        assert_eq!(run_value("(set-nth (list 1 2 3) 1 5)"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(5), Value::Integer(3)]));
+    }
+
+    #[test]
+    fn set_nth_leaves_the_original_list() {
+        assert_eq!(run_value("(let ((l (list 1 2))) (set-nth l 0 9) l)"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2)]));
+    }
+
+    #[test]
+    fn set_nth_rejects_out_of_range_indices() {
+        assert!(run_error("(set-nth (list 1 2 3) 3 5)").contains("index 3 is out of range for a length of 3"));
+        assert!(run_error("(set-nth (list) 0 5)").contains("index 0 is out of range for a length of 0"));
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        assert_eq!(run_value("(set-nth (list 1 2 3) (- 0 1) 9)"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(9)]));
+        assert_eq!(run_value("(set-nth (list 1 2 3) (- 0 3) 9)"),
+                   Value::from(vec![Value::Integer(9), Value::Integer(2), Value::Integer(3)]));
+        assert!(run_error("(set-nth (list 1 2 3) (- 0 4) 9)").contains("index -4 is out of range for a length of 3"));
+    }
+
+    #[test]
+    fn indices_must_be_integers() {
+        assert_eq!(as_index(&Value::Integer(1), 2).unwrap(), 1);
+        assert_eq!(as_index(&Value::Integer(-2), 2).unwrap(), 0);
+        assert!(as_index(&Value::Number(1.0), 2).is_err());
+        assert!(as_index(&Value::Integer(2), 2).is_err());
+    }
+
+    #[test]
+    fn flatten_splices_nested_lists() {
+        assert_eq!(run_value("(flatten (list 1 (list 2 3) (list (list 4))))"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Integer(4)]));
+        assert_eq!(run_value("(flatten (list \"ab\" (list) (list (list))))"), Value::from(vec![Value::from("ab")]));
+    }
+
+    #[test]
+    fn list_takes_exact_argument_count() {
+        assert_eq!(run_value("(list)"), Value::new_list(vec![]));
+        assert_eq!(run_value("(list 1)"), Value::from(vec![Value::Integer(1)]));
+        let many = (1 .. 101).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        assert_eq!(run_value(&format!("(list {})", many)),
+                   Value::from((1 .. 101).map(Value::Integer).collect::<Vec<_>>()));
+        // the values under the arguments are left alone
+        assert_eq!(run_source("1 (list 2 3)").unwrap(),
+                   vec![Value::Integer(1), Value::from(vec![Value::Integer(2), Value::Integer(3)])]);
+    }
+
+    #[test]
+    fn every_builtin_has_arity_and_docs() {
+        for name in BUILTIN_FUNCTIONS.keys() {
+            assert!(BUILTIN_ARITIES.contains_key(name), "builtin {} has no arity", name);
+            assert!(BUILTIN_DOCS.contains_key(name), "builtin {} has no docs", name);
+        }
+    }
+
+    #[test]
+    fn fixed_arity_builtins_check_argument_count() {
+        assert!(run_error("(inc)").contains("builtin function inc takes exactly 1 arguments, but was called with 0"));
+        assert!(run_error("(list (inc 1 2) 9)").contains("builtin function inc takes exactly 1 arguments, but was called with 2"));
+        assert!(run_error("(car (list 1) 2)").contains("builtin function car takes exactly 1 arguments"));
+        assert!(run_error("(parse-int \"1\" 10 3)").contains("builtin function parse-int takes 1 to 2 arguments"));
+        assert!(run_error("(-)").contains("builtin function - takes at least 1 arguments, but was called with 0"));
+    }
+
+    #[test]
+    fn builtin_values_check_argument_count() {
+        let err = run_error("(&define apply2 (f a b) (f a b)) (apply2 even? 1 2)");
+        assert!(err.contains("builtin function even? takes exactly 1 arguments, but was called with 2"));
+    }
+
+    #[test]
+    fn variadic_builtins_take_any_count() {
+        assert_eq!(run_value("(+)"), Value::Integer(0));
+        assert_eq!(run_value("(+ 1 2 3 4)"), Value::Integer(10));
+        assert_eq!(run_value("(min 3)"), Value::Integer(3));
+        assert_eq!(run_value("(max 3 9 4)"), Value::Integer(9));
+        assert_eq!(run_value("(append (list 1) (list 2) (list 3))"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
+    }
+
+    #[test]
+    fn repeat_makes_copies() {
+        assert_eq!(run_value("(repeat 3 0)"), Value::from(vec![Value::Integer(0); 3]));
+        assert_eq!(run_value("(repeat 0 0)"), Value::new_list(vec![]));
+        assert_eq!(run_value("(repeat 2 \"ab\")"), Value::from("abab"));
+        assert_eq!(run_value("(repeat 9223372036854775807 \"\")"), Value::from(""));
+        assert!(run_error("(repeat (- 0 1) 0)").contains("must not be negative"));
+    }
+
+    #[test]
+    fn repeat_rejects_huge_counts() {
+        assert!(run_error("(repeat 9223372036854775807 0)").contains("count for `repeat' function is too large"));
+        assert!(run_error("(repeat 9223372036854775807 \"ab\")").contains("count for `repeat' function is too large"));
+    }
+
+    #[test]
+    fn inc_and_dec_step_by_one() {
+        match run_value("(inc 4)") {
+            Value::Integer(i) => assert_eq!(i, 5),
+            v => panic!("expected an integer, but got {:?}", v),
+        }
+        match run_value("(dec 4)") {
+            Value::Integer(i) => assert_eq!(i, 3),
+            v => panic!("expected an integer, but got {:?}", v),
+        }
+        assert_eq!(run_value("(inc 0.5)"), Value::Number(1.5));
+        assert!(run_error("(inc \"a\")").contains("argument to `inc' function must be a number (instead got string)"));
+        assert!(run_error("(dec (list))").contains("argument to `dec' function must be a number (instead got list)"));
+    }
+
+    #[test]
+    fn sign_predicates() {
+        let negative_one = "(- 0 1)";
+        let cases = [("zero?", [false, true, false]), ("positive?", [false, false, true]), ("negative?", [true, false, false])];
+        for &(name, expected) in &cases {
+            for (arg, &want) in [negative_one, "0", "1"].iter().zip(expected.iter()) {
+                assert_eq!(run_value(&format!("({} {})", name, arg)), Value::Boolean(want), "({} {})", name, arg);
+            }
+            assert!(run_error(&format!("({} \"a\")", name)).contains(&format!("argument to `{}' function must be a number", name)));
+        }
+        assert_eq!(run_value("(zero? 0.0)"), Value::Boolean(true));
+        assert_eq!(run_value("(negative? (- 0 0.5))"), Value::Boolean(true));
+    }
+
+    #[test]
+    fn parity_predicates() {
+        assert_eq!(run_value("(even? 4)"), Value::Boolean(true));
+        assert_eq!(run_value("(odd? 4)"), Value::Boolean(false));
+        assert_eq!(run_value("(odd? (- 0 3))"), Value::Boolean(true));
+        assert_eq!(run_value("(even? (- 0 3))"), Value::Boolean(false));
+        // floats with no fractional part are integers too
+        assert_eq!(run_value("(even? 4.0)"), Value::Boolean(true));
+        assert_eq!(run_value("(odd? (- 0 3.0))"), Value::Boolean(true));
+        assert!(run_error("(even? 1.5)").contains("argument to `even?' function must be an integer (instead got number)"));
+        assert!(run_error("(odd? \"a\")").contains("argument to `odd?' function must be an integer (instead got string)"));
+    }
+
+    #[test]
+    fn nil_is_false_for_non_listy_values() {
+        assert_eq!(run_value("(nil? 5)"), Value::Boolean(false));
+        assert_eq!(run_value("(nil? (= 1 1))"), Value::Boolean(false));
+        assert_eq!(run_value("(nil? \"\")"), Value::Boolean(true));
+        assert_eq!(run_value("(nil? (list))"), Value::Boolean(true));
+        assert_eq!(run_value("(nil? (list 1))"), Value::Boolean(false));
+    }
+
+    #[test]
+    fn empty_checks_collections_only() {
+        assert_eq!(run_value("(empty? \"\")"), Value::Boolean(true));
+        assert_eq!(run_value("(empty? (list))"), Value::Boolean(true));
+        assert_eq!(run_value("(empty? \"a\")"), Value::Boolean(false));
+        assert_eq!(run_value("(empty? (list 1))"), Value::Boolean(false));
+        assert!(run_error("(empty? 5)").contains("argument to `empty?' function must be listy (instead got integer)"));
+    }
+
+    #[test]
+    fn member_finds_list_items() {
+        assert_eq!(run_value("(member? 2 (list 1 2 3))"), Value::Boolean(true));
+        assert_eq!(run_value("(member? 4 (list 1 2 3))"), Value::Boolean(false));
+        // same equality as `=`
+        assert_eq!(run_value("(member? 2.0 (list 1 2 3))"), Value::Boolean(true));
+        assert_eq!(run_value("(member? (list 1) (list (list 1) 2))"), Value::Boolean(true));
+        assert_eq!(run_value("(member? 1 (list))"), Value::Boolean(false));
+    }
+
+    #[test]
+    fn member_finds_string_chars() {
+        assert_eq!(run_value("(member? (car \"b\") \"abc\")"), Value::Boolean(true));
+        assert_eq!(run_value("(member? (car \"d\") \"abc\")"), Value::Boolean(false));
+        // only chars can be in strings
+        assert_eq!(run_value("(member? \"b\" \"abc\")"), Value::Boolean(false));
+        assert!(run_error("(member? 1 2)").contains("second argument to `member?' function must be listy (instead got integer)"));
+    }
+
+    #[test]
+    fn sort_orders_numbers_and_strings() {
+        assert_eq!(run_value("(sort (list 3 1.5 2 (- 0 1)))"),
+                   Value::from(vec![Value::Integer(-1), Value::Number(1.5), Value::Integer(2), Value::Integer(3)]));
+        assert_eq!(run_value("(sort (list \"b\" \"c\" \"a\"))"),
+                   Value::from(vec![Value::from("a"), Value::from("b"), Value::from("c")]));
+        assert!(run_error("(sort (list 1 \"a\"))").contains("can only sort a list of all numbers or all strings (instead got integer, string)"));
+    }
+
+    #[test]
+    fn sort_rejects_nan() {
+        let err = run_error("(sort (list 3 (/ 0.0 0.0) 1))");
+        assert!(err.contains("`sort' function cannot sort NaN (the item at index 1 is NaN)"), "{}", err);
+    }
+
+    #[test]
+    fn conj_adds_items_as_they_are() {
+        assert_eq!(run_value("(conj (list 1 2) 3 4)"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Integer(4)]));
+        assert_eq!(run_value("(conj (list 1) (list 2))"),
+                   Value::from(vec![Value::Integer(1), Value::from(vec![Value::Integer(2)])]));
+        assert_eq!(run_value("(conj (list 1))"), Value::from(vec![Value::Integer(1)]));
+        assert!(run_error("(conj 1 2)").contains("conj expects a list to add items to, but got a integer"));
+    }
+
+    #[test]
+    fn conj_leaves_the_original_list() {
+        assert_eq!(run_value("(let ((xs (list 1))) (conj xs 2) xs)"), Value::from(vec![Value::Integer(1)]));
+    }
+
+    #[test]
+    fn arithmetic_folds_over_every_argument() {
+        assert_eq!(run_value("(+ 1 2 3)"), Value::Integer(6));
+        assert_eq!(run_value("(* 2 3 4)"), Value::Integer(24));
+        assert_eq!(run_value("(- 10 1 2)"), Value::Integer(7));
+        assert_eq!(run_value("(/ 12 2 3)"), Value::Integer(2));
+        assert_eq!(run_value("(+)"), Value::Integer(0));
+        assert_eq!(run_value("(*)"), Value::Integer(1));
+    }
+
+    #[test]
+    fn unary_minus_and_divide() {
+        assert_eq!(run_value("(- 5)"), Value::Integer(-5));
+        assert_eq!(run_value("(/ 2)"), Value::Number(0.5));
+    }
+
+    #[test]
+    fn fds_are_non_negative_integers() {
+        assert_eq!(as_fd(&Value::Integer(2)).unwrap(), 2);
+        assert_eq!(as_fd(&Value::Number(3.0)).unwrap(), 3);
+        let fd_error = |value| as_fd(&value).unwrap_err().to_string();
+        assert_eq!(fd_error(Value::from("1")), "file descriptor must be a number (instead got string)");
+        assert_eq!(fd_error(Value::Number(1.5)), "file descriptor must be an integer");
+        assert_eq!(fd_error(Value::Integer(-1)), "file descriptor must not be negative (got -1)");
+        assert_eq!(fd_error(Value::Integer(1 << 40)), format!("file descriptor {} is out of range", 1i64 << 40));
+    }
+
+    #[test]
+    fn file_builtins_check_fds() {
+        assert!(run_error("(stdclose (- 0 1))").contains("file descriptor must not be negative"));
+        assert!(run_error("(stdwrite \"1\" \"hi\")").contains("file descriptor must be a number"));
+        assert!(run_error("(stdread 0.5 1)").contains("file descriptor must be an integer"));
+    }
+
+    #[test]
+    fn write_rejects_nul_bytes() {
+        assert_eq!(run_error("(stdwrite 1 (string (number->char 0)))"), "string contains a NUL byte");
+    }
+
+    #[test]
+    fn string_replace_replaces_every_occurrence() {
+        assert_eq!(run_value("(string-replace \"a-b-c\" \"-\" \"+\")"), Value::from("a+b+c"));
+        assert_eq!(run_value("(string-replace \"aaa\" \"aa\" \"b\")"), Value::from("ba"));
+        assert_eq!(run_value("(string-replace \"abc\" \"x\" \"y\")"), Value::from("abc"));
+        assert_eq!(run_value("(string-replace \"abc\" \"b\" \"\")"), Value::from("ac"));
+    }
+
+    #[test]
+    fn string_replace_checks_arguments() {
+        assert!(run_error("(string-replace \"abc\" \"\" \"x\")").contains("pattern given to `string-replace' function must not be empty"));
+        assert!(run_error("(string-replace 1 \"a\" \"b\")").contains("string given to `string-replace' function must be a string (instead got integer)"));
+        assert!(run_error("(string-replace \"a\" \"a\" (list))").contains("replacement given to `string-replace' function must be a string"));
+    }
+
+    #[test]
+    fn string_case_and_trim() {
+        assert_eq!(run_value("(trim \" \\t a b \\n\")"), Value::from("a b"));
+        assert_eq!(run_value("(upper \"straße\")"), Value::from("STRASSE"));
+        assert_eq!(run_value("(lower \"ABC def\")"), Value::from("abc def"));
+        assert_eq!(run_value("(lower \"ÀÉÎ ΣΑΣ\")"), Value::from("àéî σας"));
+        assert!(run_error("(trim 5)").contains("argument to `trim' function must be a string (instead got integer)"));
+        assert!(run_error("(upper (car \"a\"))").contains("argument to `upper' function must be a string (instead got char)"));
+        assert!(run_error("(lower (list))").contains("argument to `lower' function must be a string (instead got list)"));
+    }
+
+    #[test]
+    fn parse_int_reads_integers() {
+        assert_eq!(run_value("(parse-int \"42\")"), Value::Integer(42));
+        assert_eq!(run_value("(parse-int \"-17\")"), Value::Integer(-17));
+        assert_eq!(run_value("(parse-int \"ff\" 16)"), Value::Integer(255));
+        assert_eq!(run_value("(parse-int \"101\" 2)"), Value::Integer(5));
+        assert_eq!(run_value("(parse-int \"zz\" 36)"), Value::Integer(1295));
+    }
+
+    #[test]
+    fn parse_int_checks_arguments() {
+        assert!(run_error("(parse-int \"12a\")").contains("could not parse \"12a\" as an integer in radix 10"));
+        assert!(run_error("(parse-int \"2\" 2)").contains("could not parse \"2\" as an integer in radix 2"));
+        assert!(run_error("(parse-int \"1\" 1)").contains("radix given to `parse-int' function must be from 2 to 36 (instead got 1)"));
+        assert!(run_error("(parse-int \"1\" 37)").contains("must be from 2 to 36 (instead got 37)"));
+        assert!(run_error("(parse-int \"1\" 2.0)").contains("radix given to `parse-int' function must be an integer (instead got number)"));
+        assert!(run_error("(parse-int 1)").contains("argument to `parse-int' function must be a string (instead got integer)"));
+        assert!(run_error("(parse-int)").contains("builtin function parse-int takes 1 to 2 arguments, but was called with 0"));
+        assert!(run_error("(parse-int \"1\" 2 3)").contains("builtin function parse-int takes 1 to 2 arguments, but was called with 3"));
+    }
+
+    #[test]
+    fn parse_float_reads_numbers() {
+        assert_eq!(run_value("(parse-float \"2.5\")"), Value::Number(2.5));
+        assert_eq!(run_value("(parse-float \"3\")"), Value::Number(3.0));
+        assert_eq!(run_value("(parse-float \"-1e3\")"), Value::Number(-1000.0));
+        assert!(run_error("(parse-float \"abc\")").contains("could not parse \"abc\" as a number"));
+        assert!(run_error("(parse-float 2)").contains("argument to `parse-float' function must be a string (instead got integer)"));
+    }
+
+    #[test]
+    fn enumerate_pairs_items_with_indices() {
+        assert_eq!(run_value("(enumerate (list \"a\" \"b\"))"),
+                   Value::from(vec![Value::from(vec![Value::Integer(0), Value::from("a")]),
+                                    Value::from(vec![Value::Integer(1), Value::from("b")])]));
+        assert_eq!(run_value("(enumerate (list))"), Value::from(Vec::new()));
+        assert!(run_error("(enumerate \"ab\")").contains("argument to `enumerate' function must be a list (instead got string)"));
+    }
+
+    #[test]
+    fn take_and_drop_split_lists() {
+        let ints = |items: &[i64]| Value::from(items.iter().map(|&i| Value::Integer(i)).collect::<Vec<_>>());
+        assert_eq!(run_value("(take 2 (list 1 2 3))"), ints(&[1, 2]));
+        assert_eq!(run_value("(drop 2 (list 1 2 3))"), ints(&[3]));
+        assert_eq!(run_value("(take 5 (list 1 2 3))"), ints(&[1, 2, 3]));
+        assert_eq!(run_value("(drop 5 (list 1 2 3))"), ints(&[]));
+        assert_eq!(run_value("(take 0 (list 1 2 3))"), ints(&[]));
+    }
+
+    #[test]
+    fn take_and_drop_split_strings_by_char() {
+        assert_eq!(run_value("(take 2 \"héllo\")"), Value::from("hé"));
+        assert_eq!(run_value("(drop 2 \"héllo\")"), Value::from("llo"));
+        assert_eq!(run_value("(drop 9 \"héllo\")"), Value::from(""));
+    }
+
+    #[test]
+    fn take_and_drop_check_arguments() {
+        assert!(run_error("(take (- 0 1) (list 1))").contains("count for `take' function must not be negative (instead got -1)"));
+        assert!(run_error("(drop 1.0 (list 1))").contains("first argument to `drop' function must be an integer (instead got number)"));
+        assert!(run_error("(take 1 5)").contains("second argument to `take' function must be listy (instead got integer)"));
+    }
+
+    #[test]
+    fn nan_and_inf_predicates() {
+        assert_eq!(run_value("(list (nan? (/ 0.0 0.0)) (nan? 1.5) (nan? 1))"),
+                   Value::from(vec![Value::Boolean(true), Value::Boolean(false), Value::Boolean(false)]));
+        assert_eq!(run_value("(list (inf? (/ 1.0 0.0)) (inf? (/ (- 0.0 1.0) 0.0)) (inf? (/ 0.0 0.0)) (inf? 1))"),
+                   Value::from(vec![Value::Boolean(true), Value::Boolean(true), Value::Boolean(false), Value::Boolean(false)]));
+        assert!(run_error("(nan? \"x\")").contains("nan?"));
+    }
+
+    #[test]
+    fn non_finite_numbers_convert_to_names() {
+        assert_eq!(run_value("(list (string (/ 1.0 0.0)) (string (/ (- 0.0 1.0) 0.0)) (string (/ 0.0 0.0)))"),
+                   Value::from(vec![Value::from("inf"), Value::from("-inf"), Value::from("nan")]));
+    }
+
+    #[test]
+    fn values_go_to_and_from_json() {
+        assert_eq!(run_value("(to-json (list 1 2.5 \"a\" (car \"b\") (= 1 1) (list)))"),
+                   Value::String(String::from("[1,2.5,\"a\",\"b\",true,[]]")));
+        assert_eq!(run_value("(from-json \"{\\\"a\\\": [1, 2.5, null], \\\"b\\\": false}\")"),
+                   Value::from(vec![
+                       Value::from(vec![Value::String(String::from("a")),
+                                        Value::from(vec![Value::Integer(1), Value::Number(2.5), Value::from(Vec::new())])]),
+                       Value::from(vec![Value::String(String::from("b")), Value::Boolean(false)]),
+                   ]));
+    }
+
+    #[test]
+    fn json_builtins_check_arguments() {
+        assert!(run_error("(from-json 1)").contains("argument to `from-json' function must be a string (instead got integer)"));
+        assert!(run_error("(from-json \"[1,\")").contains("invalid JSON at character 3"));
+        assert!(run_error("(&define f () 1) (to-json (list f))")
+                .contains("`to-json' function can't write a function as JSON"));
+    }
+
+    #[test]
+    fn setenv_gives_the_old_value() {
+        let nil = Value::from(Vec::new());
+        assert_eq!(run_value("(getenv \"RASP_TEST_SETENV\")"), nil);
+        assert_eq!(run_value("(setenv \"RASP_TEST_SETENV\" \"one\")"), nil);
+        assert_eq!(run_value("(setenv \"RASP_TEST_SETENV\" \"two\")"), Value::String(String::from("one")));
+        assert_eq!(run_value("(getenv \"RASP_TEST_SETENV\")"), Value::String(String::from("two")));
+    }
+
+    #[test]
+    fn env_builtins_check_names_and_values() {
+        assert!(run_error("(getenv 1)").contains("environment variable name must be a string"));
+        assert!(run_error("(getenv \"\")").contains("invalid environment variable name \"\""));
+        assert!(run_error("(setenv \"A=B\" \"x\")").contains("invalid environment variable name \"A=B\""));
+        assert!(run_error("(setenv \"RASP_TEST_BAD\" 1)").contains("environment variable value must be a string"));
+    }
+}
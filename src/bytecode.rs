@@ -2,21 +2,51 @@ use ast::AST;
 use vm::Value;
 use internal::*;
 use errors::*;
-use builtins::BUILTIN_FUNCTIONS;
+use builtins::{BUILTIN_FUNCTIONS, check_arity};
 
-#[derive(Clone, Debug)]
+/// Names that are compiled specially rather than called, so they can't be the names of functions.
+pub const SPECIAL_FORMS: &'static [&'static str] = &["let", "def", "if", "when", "unless", "define", "case", "match", "and",
+                                                          "or", THREADING_FORM];
+/// The threading form, which the preprocessor expands into nested calls before compiling.
+pub const THREADING_FORM: &'static str = "->";
+/// The marker in a `match` list pattern after which the pattern for the rest of the list comes.
+pub const MATCH_REST_MARKER: &'static str = "&";
+/// The pattern in a `match` that matches anything without binding it.
+pub const MATCH_WILDCARD: &'static str = "_";
+/// The variable that holds the value being matched by a `match`. It has a space in it, so it can't
+/// be named in source code.
+const MATCH_VALUE_VAR: &'static str = " match value";
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Bytecode {
     //Nop,
     /// Calls a function with the given parameters.
     Call(String, usize),
+    /// Pops a function value off of the stack, and calls it with the given parameters.
+    CallValue(usize),
     /// Pushes a value onto the current stack frame.
     Push(Value),
     /// Pops a value off of the stack into a variable name
     Pop(String),
-    //// Pops N values off of the stack into oblivion.
-    //PopN(usize),
+    /// Pops a value off of the stack into a global variable, which every scope can see
+    PopGlobal(String),
+    /// Pops N values off of the stack into oblivion.
+    PopN(usize),
+    /// Pushes a copy of the value on top of the stack
+    Dup,
+    /// Swaps the top two values on the stack
+    #[allow(dead_code)] // not emitted by the compiler yet
+    Swap,
+    /// Rotates the top three values on the stack, bringing the third value to the top: (a b c -- b c a)
+    #[allow(dead_code)] // not emitted by the compiler yet
+    Rot,
     /// Loads a given variable value onto the stack
     Load(String),
+    /// Pushes a named function (either user-defined or builtin) onto the stack as a value
+    LoadGlobal(String),
+    /// Pushes a function with the given name, parameters and body onto the stack as a value,
+    /// capturing the variables currently in scope
+    MakeClosure(String, Vec<String>, Vec<Bytecode>),
     /// Stores a given value in a variable value
     Store(String, Value),
     /// Special VM bytecode for creating a new variable stack
@@ -27,11 +57,40 @@ pub enum Bytecode {
     Skip(usize),
     /// Special VM bytecode that pops a value off the stack and skips N instructions if the value is falsy
     SkipFalse(usize),
+    /// Pops a value off of the stack, and pushes whether it's a list of exactly N items, or of at
+    /// least N items if the flag is set
+    MatchList(usize, bool),
+    /// Checks that the top-level statement before it, which is described by the string, left at most
+    /// one value on the stack (its result), and no fewer than were there before it ran. Only
+    /// emitted when the stack is being checked.
+    CheckStack(String),
+}
+
+/// Where a sequence of expressions is, which decides which of them are statements. A statement's
+/// value isn't used, so it may be a `def`, which doesn't give one.
+#[derive(Clone, Copy, PartialEq)]
+enum Sequence {
+    /// A whole program, or an input to the REPL, where every expression is a statement
+    TopLevel,
+    /// The body of a `let` or a function, where every expression but the last is a statement; the
+    /// last one gives the value of the body
+    Body,
+    /// Values that are all used, like the arguments of a call
+    Values,
 }
 
 pub struct ToBytecode<'a> {
     fun_table: &'a FunTable,
     type_table: &'a TypeTable,
+    /// Names of the local variables in scope, which shadow function names
+    locals: Vec<String>,
+    /// Whether calls to functions that always give the same constant are replaced by the constant
+    inline_constants: bool,
+    /// Whether each top-level statement is followed by a `CheckStack`
+    check_stack: bool,
+    /// Whether `def` makes global variables, which is only the case outside of any scope that `let`,
+    /// `match` or a function body makes
+    global_defs: bool,
 }
 
 impl<'a> ToBytecode<'a> {
@@ -40,72 +99,276 @@ impl<'a> ToBytecode<'a> {
         ToBytecode {
             fun_table: fun_table,
             type_table: type_table,
+            locals: Vec::new(),
+            inline_constants: false,
+            check_stack: false,
+            global_defs: true,
+        }
+    }
+
+    /// Creates a new ToBytecode object for the body of the given function, whose parameters are in
+    /// scope as local variables.
+    pub fn for_function(fun_table: &'a FunTable, type_table: &'a TypeTable, fun: &Function) -> ToBytecode<'a> {
+        ToBytecode {
+            fun_table: fun_table,
+            type_table: type_table,
+            locals: fun.params
+                .iter()
+                .map(|p| p.name.clone())
+                .collect(),
+            inline_constants: false,
+            check_stack: false,
+            global_defs: false,
+        }
+    }
+
+    /// Gets a copy of this ToBytecode object with more local variables in scope, for code that runs
+    /// in a new scope.
+    fn with_locals(&self, names: Vec<String>) -> ToBytecode<'a> {
+        let mut scope = self.with_defined(names);
+        scope.global_defs = false;
+        scope
+    }
+
+    /// Gets a copy of this ToBytecode object with more local variables in scope, for code that runs
+    /// in the same scope, e.g. after a `def`.
+    fn with_defined(&self, names: Vec<String>) -> ToBytecode<'a> {
+        let mut locals = self.locals.clone();
+        locals.extend(names);
+        ToBytecode {
+            fun_table: self.fun_table,
+            type_table: self.type_table,
+            locals: locals,
+            inline_constants: self.inline_constants,
+            // statements inside of other expressions aren't top-level
+            check_stack: false,
+            global_defs: self.global_defs,
+        }
+    }
+
+    /// Sets whether calls with constant arguments to functions whose bodies are a single constant
+    /// are replaced by that constant. This is done when optimizing.
+    pub fn set_inline_constants(&mut self, inline_constants: bool) {
+        self.inline_constants = inline_constants;
+    }
+
+    /// Sets whether each top-level statement is followed by a check that it didn't leave extra values
+    /// on the stack, or take away values it didn't push. This is for finding bugs in the compiler.
+    pub fn set_check_stack(&mut self, check_stack: bool) {
+        self.check_stack = check_stack;
+    }
+
+    /// Gets the name that an identifier refers to: aliases resolve to the functions they name,
+    /// unless a local variable shadows them.
+    fn resolve_name(&self, name: &str) -> String {
+        if self.locals.iter().any(|l| l == name) {
+            name.to_string()
+        }
+        else {
+            self.fun_table
+                .resolve_alias(name)
+                .to_string()
+        }
+    }
+
+    /// Gets the bytecode that loads an identifier as a value. Local variables are loaded as-is,
+    /// and the names of functions are loaded as function values.
+    fn load_identifier(&self, name: &str) -> Bytecode {
+        let is_local = self.locals.iter().any(|l| l == name);
+        let name = &self.resolve_name(name);
+        if !is_local && (self.fun_table.has_fun(name) || BUILTIN_FUNCTIONS.contains_key(name.as_str())) {
+            Bytecode::LoadGlobal(name.to_string())
+        }
+        else {
+            Bytecode::Load(name.to_string())
         }
     }
 
     /// Converts an abstract syntax tree to bytecode.
     pub fn to_bytecode(&self, ast: &Vec<AST>) -> Result<Vec<Bytecode>> {
+        self.sequence_to_bytecode(ast, Sequence::TopLevel)
+    }
+
+    /// Converts the body of a function to bytecode, which gives the value of its last expression.
+    pub fn body_to_bytecode(&self, ast: &Vec<AST>) -> Result<Vec<Bytecode>> {
+        self.sequence_to_bytecode(ast, Sequence::Body)
+    }
+
+    /// Converts expressions to bytecode that pushes each of their values.
+    fn values_to_bytecode(&self, ast: &Vec<AST>) -> Result<Vec<Bytecode>> {
+        self.sequence_to_bytecode(ast, Sequence::Values)
+    }
+
+    fn sequence_to_bytecode(&self, ast: &Vec<AST>, sequence: Sequence) -> Result<Vec<Bytecode>> {
         let mut code = Vec::new();
-        for expr in ast {
+        // variables made with `define` and `def` are locals for the rest of the expressions
+        let mut scope = self.with_defined(Vec::new());
+        for (index, expr) in ast.iter().enumerate() {
+            let is_statement = match sequence {
+                Sequence::TopLevel => true,
+                Sequence::Body => index + 1 < ast.len(),
+                Sequence::Values => false,
+            };
             match expr {
                 &AST::Expr(ref r, _) => {
-                    match self.expr_to_bytecode(expr) {
+                    let result = if is_statement {
+                        scope.statement_to_bytecode(expr)
+                    }
+                    else {
+                        scope.expr_to_bytecode(expr)
+                    };
+                    match result {
                         Ok(mut codez) => code.append(&mut codez),
                         e => { // braces necessary because of some rust weirdness
-                            e.chain_err(|| format!("{}", r))?;
+                            e.chain_err(|| diagnostic(r, ""))?;
                         },
                     }
+                    if let Some(name) = defined_name(expr) {
+                        scope = scope.with_defined(vec![name.to_string()]);
+                    }
                 },
                 &AST::StringLit(_, ref s) => code.push(Bytecode::Push(Value::String(s.to_string()))),
-                &AST::Identifier(_, ref s) => code.push(Bytecode::Load(s.to_string())),
+                &AST::Identifier(_, ref s) => code.push(scope.load_identifier(s)),
                 &AST::Number(_, n) => code.push(Bytecode::Push(Value::Number(n))),
+                &AST::Integer(_, n) => code.push(Bytecode::Push(Value::Integer(n))),
+                &AST::Comment(_, _) => continue,
+            }
+            if self.check_stack {
+                code.push(Bytecode::CheckStack(format!("statement at {}", expr.range())));
             }
         }
         Ok(code)
     }
 
+    /// Converts an expression whose value isn't used into bytecode. Unlike anywhere else, this may
+    /// be a `def`, and a `define` doesn't give the function that it makes.
+    fn statement_to_bytecode(&self, expr: &AST) -> Result<Vec<Bytecode>> {
+        match expr.exprs().first() {
+            Some(&AST::Identifier(ref r, ref name)) if name == "def" => match self.def_builtin(expr) {
+                Ok(codez) => Ok(codez),
+                e => e.chain_err(|| diagnostic(r, "")),
+            },
+            Some(&AST::Identifier(ref r, ref name)) if name == "define" => match self.define_builtin(expr, false) {
+                Ok(codez) => Ok(codez),
+                e => e.chain_err(|| diagnostic(r, "")),
+            },
+            _ => self.expr_to_bytecode(expr),
+        }
+    }
+
     /// Converts an expression into bytecode
+    /// `()` is the empty list wherever it appears, so it can't be called: `(())` is an error.
+    /// The name at the head of a call is looked up in this order: local variables (which are called
+    /// as function values), aliases, the special forms like `if` and `let`, user-defined functions,
+    /// and finally builtin functions.
     fn expr_to_bytecode(&self, expr: &AST) -> Result<Vec<Bytecode>> {
         assert!(expr.is_expr());
         let mut codez = Vec::new();
         let exprs = expr.exprs();
         if exprs.len() == 0 {
             // push empty list
-            codez.push(Bytecode::Push(Value::List(Vec::new())));
+            codez.push(Bytecode::Push(Value::new_list(Vec::new())));
         }
         else {
             let ref first = exprs[0];
             match first {
-                // if it's an expression, get what that expression is;
-                // TODO(alek): add function stack so we can just use "pushfn" and "call" instructions
-                &AST::Expr(_, _) =>
-                    return Err("attempt to call expression as a function (not yet supported)".into()),
+                &AST::Expr(ref r, ref inner) if inner.is_empty() =>
+                    return Err(diagnostic(r, "attempt to call the empty list `()' as a function").into()),
+                // if it's an expression, call the function value that it evaluates to
+                &AST::Expr(ref r, _) => {
+                    let args = exprs.iter()
+                        .skip(1)
+                        .map(|x| x.clone())
+                        .collect::<Vec<AST>>();
+                    match self.values_to_bytecode(&args) {
+                        Ok(mut inner_codez) => codez.append(&mut inner_codez),
+                        e => return e.chain_err(|| diagnostic(r, "")),
+                    }
+                    match self.expr_to_bytecode(first) {
+                        Ok(mut inner_codez) => codez.append(&mut inner_codez),
+                        e => return e.chain_err(|| diagnostic(r, "")),
+                    }
+                    codez.push(Bytecode::CallValue(args.len()));
+                },
                 // honestly, just treat string literals as identifiers in this context
                 &AST::StringLit(ref r, ref name) | &AST::Identifier(ref r, ref name) => {
+                    let name = &self.resolve_name(name);
                     if name == "let" {
                         match self.let_builtin(expr) {
                             Ok(mut inner) => codez.append(&mut inner),
                             e => {
-                                e.chain_err(|| format!("{}", r))?;
+                                e.chain_err(|| diagnostic(r, ""))?;
                             }
                         }
                     }
-                    else if name == "list" {
-                        match self.list_builtin(expr) {
+                    else if name == "def" {
+                        return Err(diagnostic(r, "def function doesn't give a value, so it may only be used at the top level \
+                                                   or before the end of a body").into());
+                    }
+                    else if name == "if" {
+                        match self.if_builtin(expr) {
                             Ok(mut inner) => codez.append(&mut inner),
                             e => {
-                                e.chain_err(|| format!("{}", r))?;
+                                e.chain_err(|| diagnostic(r, ""))?;
                             }
                         }
                     }
-                    else if name == "if" {
-                        match self.if_builtin(expr) {
+                    else if name == "when" || name == "unless" {
+                        match self.when_unless_builtin(expr) {
                             Ok(mut inner) => codez.append(&mut inner),
                             e => {
-                                e.chain_err(|| format!("{}", r))?;
+                                e.chain_err(|| diagnostic(r, ""))?;
                             }
                         }
                     }
+                    else if name == "define" {
+                        match self.define_builtin(expr, true) {
+                            Ok(mut inner) => codez.append(&mut inner),
+                            e => {
+                                e.chain_err(|| diagnostic(r, ""))?;
+                            }
+                        }
+                    }
+                    else if name == "case" {
+                        match self.case_builtin(expr) {
+                            Ok(mut inner) => codez.append(&mut inner),
+                            e => {
+                                e.chain_err(|| diagnostic(r, ""))?;
+                            }
+                        }
+                    }
+                    else if name == "match" {
+                        match self.match_builtin(expr) {
+                            Ok(mut inner) => codez.append(&mut inner),
+                            e => {
+                                e.chain_err(|| diagnostic(r, ""))?;
+                            }
+                        }
+                    }
+                    else if name == "and" || name == "or" {
+                        match self.and_or_builtin(expr) {
+                            Ok(mut inner) => codez.append(&mut inner),
+                            e => {
+                                e.chain_err(|| diagnostic(r, ""))?;
+                            }
+                        }
+                    }
+                    else if self.locals.iter().any(|l| l == name) {
+                        // calling a variable, which should hold a function value
+                        let args = exprs.iter()
+                            .skip(1)
+                            .map(|x| x.clone())
+                            .collect::<Vec<AST>>();
+                        match self.values_to_bytecode(&args) {
+                            Ok(mut inner_codez) => codez.append(&mut inner_codez),
+                            e => return e.chain_err(|| diagnostic(r, "")),
+                        }
+                        codez.push(Bytecode::Load(name.to_string()));
+                        codez.push(Bytecode::CallValue(args.len()));
+                    }
+                    else if self.type_table.get_type(name).is_some() {
+                        return Err(diagnostic(&expr.range(), format!("`{}' is a type, not a function", name)).into());
+                    }
                     else if !self.fun_table.has_fun(name) && !BUILTIN_FUNCTIONS.contains_key(name.as_str()) {
                         return Err(format!("attempt to call non-existent function `{}'", name).into());
                     }
@@ -115,18 +378,19 @@ impl<'a> ToBytecode<'a> {
                             .skip(1)
                             .collect::<Vec<&AST>>();
                         let arg_count = args.len();
-                        if BUILTIN_FUNCTIONS.contains_key(name.as_str()) {
-                            // TODO(alek): Check args for builtin functions
+                        // user functions shadow builtins of the same name, like the VM does
+                        if !self.fun_table.has_fun(name) {
+                            check_arity(name, arg_count)?;
                             for arg in args {
                                 count += 1;
                                 if arg.is_expr() {
                                     match self.expr_to_bytecode(arg) {
                                         Ok(mut inner_codez) => codez.append(&mut inner_codez),
-                                        e => return e.chain_err(|| format!("{}", r)),
+                                        e => return e.chain_err(|| diagnostic(r, "")),
                                     }
                                 }
                                 else if arg.is_identifier() {
-                                    codez.push(Bytecode::Load(arg.identifier().to_string()));
+                                    codez.push(self.load_identifier(arg.identifier()));
                                 }
                                 else {
                                     codez.push(Bytecode::Push(arg.to_value()));
@@ -141,12 +405,24 @@ impl<'a> ToBytecode<'a> {
                             let max_args = self.max_function_args(&fun);
                             if arg_count > max_args || arg_count < min_args {
                                 return if max_args == min_args {
-                                    Err(format!("no variant of function {} takes {} arguments (takes exactly {} arguments)", 
-                                                fun.name, arg_count, min_args).into())
+                                    Err(format!("no variant of function {} takes {} arguments (takes exactly {} arguments: {})", 
+                                                fun.name, arg_count, min_args, fun.signature_string()).into())
                                 }
                                 else {
-                                    Err(format!("no variant of function {} takes {} arguments (takes {} to {} arguments)", 
-                                                fun.name, arg_count, min_args, max_args).into())
+                                    Err(format!("no variant of function {} takes {} arguments (takes {} to {} arguments: {})", 
+                                                fun.name, arg_count, min_args, max_args, fun.signature_string()).into())
+                                }
+                            }
+                            // evaluating constant arguments can't do anything, so the call can be
+                            // skipped if the function always gives the same value
+                            if self.inline_constants && args.iter().all(|arg| is_constant(arg)) {
+                                // a constant of the wrong type is left to fail when called
+                                let well_typed = |value: &Value| fun.return_type
+                                    .as_ref()
+                                    .map_or(true, |t| value.has_type(t, self.type_table));
+                                if let Some(value) = constant_body(fun).filter(well_typed) {
+                                    codez.push(Bytecode::Push(value));
+                                    return Ok(codez);
                                 }
                             }
 
@@ -159,11 +435,11 @@ impl<'a> ToBytecode<'a> {
                                 if arg.is_expr() {
                                     match self.expr_to_bytecode(arg) {
                                         Ok(mut inner_codez) => codez.append(&mut inner_codez),
-                                        e => return e.chain_err(|| format!("{}", r)),
+                                        e => return e.chain_err(|| diagnostic(r, "")),
                                     }
                                 }
                                 else if arg.is_identifier() {
-                                    codez.push(Bytecode::Load(arg.identifier().to_string()));
+                                    codez.push(self.load_identifier(arg.identifier()));
                                 }
                                 else {
                                     codez.push(Bytecode::Push(arg.to_value()));
@@ -175,8 +451,10 @@ impl<'a> ToBytecode<'a> {
                     }
                 },
                 // if it's a number, throw an error;
-                &AST::Number(_, _) =>
+                &AST::Number(_, _) | &AST::Integer(_, _) =>
                     return Err("attempt to call number literal as a function".into()),
+                // the preprocessor removes comments inside of expressions
+                &AST::Comment(_, _) => unreachable!(),
             }
         }
         Ok(codez)
@@ -211,6 +489,10 @@ impl<'a> ToBytecode<'a> {
     fn let_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
         assert!(ast.is_expr());
         let exprs = ast.exprs();
+        if exprs.len() < 3 {
+            return Err("let function requires a list of assignments and a body (use `def' to define a variable without a body)"
+                       .into());
+        }
         let ref first = exprs[0];
         let ref setz = exprs[1];
         let the_rest = exprs
@@ -228,6 +510,8 @@ impl<'a> ToBytecode<'a> {
             let mut codez = Vec::new();
             assert!(first.identifier() == "let");
             codez.push(Bytecode::NewVarStack);
+            // each assignment can see the ones before it
+            let mut scope = self.with_locals(Vec::new());
             for set in setz.exprs() {
                 if !set.is_expr() || set.exprs().len() != 2 {
                     return Err("assignments must be a list of two items".into())
@@ -241,17 +525,23 @@ impl<'a> ToBytecode<'a> {
                 }
                 // handles function calls
                 if assign[1].is_expr() {
-                    match self.expr_to_bytecode(&assign[1]) {
+                    match scope.expr_to_bytecode(&assign[1]) {
                         Ok(mut v) => codez.append(&mut v),
                         e => return e.chain_err(|| "invalid function call"),
                     }
                     codez.push(Bytecode::Pop(assign[0].identifier().to_string()));
                 }
+                // handles variables and function names
+                else if assign[1].is_identifier() {
+                    codez.push(scope.load_identifier(assign[1].identifier()));
+                    codez.push(Bytecode::Pop(assign[0].identifier().to_string()));
+                }
                 else {
                     codez.push(Bytecode::Store(assign[0].identifier().to_string(), assign[1].to_value()));
                 }
+                scope = scope.with_locals(vec![assign[0].identifier().to_string()]);
             }
-            match self.to_bytecode(&the_rest) {
+            match scope.body_to_bytecode(&the_rest) {
                 Ok(mut inner_codez) => codez.append(&mut inner_codez),
                 e => return e,
             }
@@ -260,35 +550,53 @@ impl<'a> ToBytecode<'a> {
         }
     }
 
-    fn list_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
+    /// Compiles a `def` statement, which looks like `(def name value)`.
+    /// Unlike `let`, this has no body: the variable is set in the enclosing scope, so the rest of
+    /// the expressions there can use it. It doesn't give a value, so it can't be used where one is
+    /// needed, like in the arguments of a call or at the end of a body. At the top level (outside
+    /// of any `let`, `match` or function body), this makes a global variable, which functions can
+    /// see too, and which lasts for as long as the VM does, e.g. between inputs to the REPL.
+    fn def_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
         assert!(ast.is_expr());
         let exprs = ast.exprs();
         let ref first = exprs[0];
         if !first.is_identifier() {
-            Err("list function must be called as an identifier".into())
+            return Err("def function must be called as an identifier".into());
         }
-        else {
-            assert!(first.identifier() == "list");
-            let the_rest = exprs
-                .iter()
-                .skip(1)
-                .map(|x| x.clone())
-                .rev()
-                .collect::<Vec<AST>>();
-            let mut codez = Vec::new();
-            codez.push(Bytecode::Push(Value::EndArgs));
-            match self.to_bytecode(&the_rest) {
-                Ok(mut l) => codez.append(&mut l),
-                e => return e.chain_err(|| "list function call"),
-            }
-            let size = (codez.len() - 1) as i64;
-            codez.push(Bytecode::Push(Value::StartArgs(size)));
-            codez.push(Bytecode::Call("list".to_string(), 0));
-            Ok(codez)
+        assert!(first.identifier() == "def");
+        if exprs.len() != 3 {
+            return Err(format!("def function requires a name and a value (instead got {} arguments)", exprs.len() - 1)
+                       .into());
+        }
+        if !exprs[1].is_identifier() {
+            return Err(diagnostic(exprs[1].range(), "name of defined variable must be an identifier").into());
         }
+        let name = exprs[1].identifier().to_string();
+        if SPECIAL_FORMS.contains(&name.as_str()) {
+            return Err(diagnostic(exprs[1].range(), format!("cannot define a variable named `{}', since it is a special form",
+                                                            name)).into());
+        }
+        if BUILTIN_FUNCTIONS.contains_key(name.as_str()) {
+            return Err(diagnostic(exprs[1].range(), format!("cannot define a variable named `{}', since it is a builtin function",
+                                                            name)).into());
+        }
+        let mut codez = match self.values_to_bytecode(&vec![exprs[2].clone()]) {
+            Ok(l) => l,
+            e => return e.chain_err(|| format!("value of defined variable `{}'", name)),
+        };
+        codez.push(if self.global_defs { Bytecode::PopGlobal(name) } else { Bytecode::Pop(name) });
+        Ok(codez)
     }
 
-
+    /// Compiles `(if cond a b)` to:
+    ///
+    /// ```text
+    /// <cond>
+    /// SkipFalse(len(a) + 1)   ; past a, and the Skip after it
+    /// <a>
+    /// Skip(len(b))            ; past b
+    /// <b>
+    /// ```
     fn if_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
         assert!(ast.is_expr());
         let exprs = ast.exprs();
@@ -312,27 +620,771 @@ impl<'a> ToBytecode<'a> {
                 let third = exprs[3].clone();
 
                 let mut codez = Vec::new();
-                let mut first_codez = match self.to_bytecode(&vec![first]) {
+                let mut first_codez = match self.values_to_bytecode(&vec![first]) {
                     Ok(l) => l,
                     e => return e.chain_err(|| "condition of if function call"),
                 };
-                let mut second_codez = match self.to_bytecode(&vec![second]) {
+                let mut second_codez = match self.values_to_bytecode(&vec![second]) {
                     Ok(l) => l,
                     e => return e.chain_err(|| "first expression of if function call"),
                 };
-                let mut third_codez = match self.to_bytecode(&vec![third]) {
+                let mut third_codez = match self.values_to_bytecode(&vec![third]) {
                     Ok(l) => l,
-                    e => return e.chain_err(|| "first expression of if function call"),
+                    e => return e.chain_err(|| "second expression of if function call"),
                 };
 
                 codez.append(&mut first_codez);
                 codez.push(Bytecode::SkipFalse(second_codez.len() + 1));
                 codez.append(&mut second_codez);
-                codez.push(Bytecode::Skip(third_codez.len() + 1));
+                codez.push(Bytecode::Skip(third_codez.len()));
                 codez.append(&mut third_codez);
                 Ok(codez)
             }
         }
     }
+
+    /// Compiles `(when cond a)` and `(unless cond a)`, which give nil (an empty list) when `a`
+    /// isn't run. `when` compiles to:
+    ///
+    /// ```text
+    /// <cond>
+    /// SkipFalse(len(a) + 1)   ; past a, and the Skip after it
+    /// <a>
+    /// Skip(1)                 ; past the nil
+    /// Push(nil)
+    /// ```
+    ///
+    /// and `unless` to:
+    ///
+    /// ```text
+    /// <cond>
+    /// SkipFalse(2)            ; past the nil, and the Skip after it
+    /// Push(nil)
+    /// Skip(len(a))            ; past a
+    /// <a>
+    /// ```
+    fn when_unless_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
+        assert!(ast.is_expr());
+        let exprs = ast.exprs();
+        let ref first = exprs[0];
+        if !first.is_identifier() {
+            return Err("when/unless function must be called as an identifier".into());
+        }
+        let name = first.identifier();
+        let is_when = name == "when";
+        assert!(is_when || name == "unless");
+        if exprs.len() != 3 {
+            return Err(format!("{} function requires exactly 2 arguments, got {} instead", name, exprs.len() - 1).into());
+        }
+
+        let mut codez = match self.values_to_bytecode(&vec![exprs[1].clone()]) {
+            Ok(l) => l,
+            e => return e.chain_err(|| format!("condition of {} function call", name)),
+        };
+        let mut body_codez = match self.values_to_bytecode(&vec![exprs[2].clone()]) {
+            Ok(l) => l,
+            e => return e.chain_err(|| format!("expression of {} function call", name)),
+        };
+        let nil = Bytecode::Push(Value::new_list(Vec::new()));
+        if is_when {
+            codez.push(Bytecode::SkipFalse(body_codez.len() + 1));
+            codez.append(&mut body_codez);
+            codez.push(Bytecode::Skip(1));
+            codez.push(nil);
+        }
+        else {
+            codez.push(Bytecode::SkipFalse(2));
+            codez.push(nil);
+            codez.push(Bytecode::Skip(body_codez.len()));
+            codez.append(&mut body_codez);
+        }
+        Ok(codez)
+    }
+
+    /// Compiles a short-circuiting `and` or `or` expression.
+    /// Operands are evaluated left to right, stopping at the first falsy (for `and`) or truthy (for
+    /// `or`) value, which becomes the result; otherwise, the result is the last operand. With no
+    /// operands, `and` is true and `or` is false.
+    fn and_or_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
+        assert!(ast.is_expr());
+        let exprs = ast.exprs();
+        let ref first = exprs[0];
+        if !first.is_identifier() {
+            return Err("and/or function must be called as an identifier".into());
+        }
+        let is_and = first.identifier() == "and";
+        assert!(is_and || first.identifier() == "or");
+
+        let mut operands = Vec::new();
+        for (index, operand) in exprs.iter().skip(1).enumerate() {
+            match self.values_to_bytecode(&vec![operand.clone()]) {
+                Ok(l) => operands.push(l),
+                e => return e.chain_err(|| format!("operand {} of {} function call", index + 1, first.identifier())),
+            }
+        }
+
+        let mut last = match operands.pop() {
+            Some(l) => l,
+            None => return Ok(vec![Bytecode::Push(Value::Boolean(is_and))]),
+        };
+        if operands.is_empty() {
+            return Ok(last);
+        }
+
+        // Every operand but the last is tested on a copy of itself; short-circuiting jumps to the
+        // end, leaving the operand on the stack as the result. Otherwise it's discarded.
+        //
+        //   <operand> Dup SkipFalse(->end) PopN(1)              (and)
+        //   <operand> Dup SkipFalse(1) Skip(->end) PopN(1)      (or)
+        //   ...
+        //   <last operand>
+        //   end:
+        let mut remaining = last.len();
+        let mut blocks = Vec::new();
+        for mut operand in operands.into_iter().rev() {
+            operand.push(Bytecode::Dup);
+            if is_and {
+                operand.push(Bytecode::SkipFalse(remaining + 1));
+            }
+            else {
+                operand.push(Bytecode::SkipFalse(1));
+                operand.push(Bytecode::Skip(remaining + 1));
+            }
+            operand.push(Bytecode::PopN(1));
+            remaining += operand.len();
+            blocks.push(operand);
+        }
+
+        let mut codez = Vec::new();
+        for mut block in blocks.into_iter().rev() {
+            codez.append(&mut block);
+        }
+        codez.append(&mut last);
+        Ok(codez)
+    }
+
+    /// Compiles a `case` expression, which looks like
+    /// `(case scrutinee (key1 expr1) (key2 expr2) ... (else default))`.
+    /// The scrutinee is evaluated once, and compared against each literal key in order; the first
+    /// matching key's expression is the result. If no key matches, the result is the `else`
+    /// expression, or an empty list if there is no `else` clause.
+    fn case_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
+        assert!(ast.is_expr());
+        let exprs = ast.exprs();
+        let ref first = exprs[0];
+        if !first.is_identifier() {
+            return Err("case function must be called as an identifier".into());
+        }
+        assert!(first.identifier() == "case");
+        if exprs.len() < 2 {
+            return Err("case function requires a value to match against".into());
+        }
+
+        let mut codez = match self.values_to_bytecode(&vec![exprs[1].clone()]) {
+            Ok(l) => l,
+            e => return e.chain_err(|| "value of case function call"),
+        };
+
+        // each clause is compiled to a (key, body) pair; a key of None is the default
+        let mut clauses = Vec::new();
+        let mut default_codez = None;
+        for clause in exprs.iter().skip(2) {
+            if !clause.is_expr() || clause.exprs().len() != 2 {
+                return Err(diagnostic(clause.range(), "case clause must be a list of a key and an expression").into());
+            }
+            if default_codez.is_some() {
+                return Err(diagnostic(clause.range(), "case clause after else clause").into());
+            }
+            let ref key = clause.exprs()[0];
+            let body_codez = match self.values_to_bytecode(&vec![clause.exprs()[1].clone()]) {
+                Ok(l) => l,
+                e => return e.chain_err(|| diagnostic(clause.range(), "case clause")),
+            };
+            match key {
+                &AST::Identifier(_, ref name) if name == "else" => default_codez = Some(body_codez),
+                &AST::StringLit(_, _) | &AST::Number(_, _) | &AST::Integer(_, _) => clauses.push((key.to_value(), body_codez)),
+                _ => return Err(diagnostic(key.range(), "case key must be a literal or `else'").into()),
+            }
+        }
+
+        // The scrutinee stays on the stack while it's being compared, and is discarded once a
+        // branch is taken.
+        //
+        //   <scrutinee>
+        //   Dup Push(key) Call(=, 2) SkipFalse(->next) PopN(1) <body> Skip(->end)
+        //   ...
+        //   next: PopN(1) <default>
+        //   end:
+        let mut tail = vec![Bytecode::PopN(1)];
+        tail.append(&mut default_codez.unwrap_or_else(|| vec![Bytecode::Push(Value::new_list(vec![]))]));
+        let mut remaining = tail.len();
+        let mut blocks = Vec::new();
+        for (key, mut body_codez) in clauses.into_iter().rev() {
+            let mut block = vec![
+                Bytecode::Dup,
+                Bytecode::Push(key),
+                Bytecode::Call("=".to_string(), 2),
+                Bytecode::SkipFalse(body_codez.len() + 2),
+                Bytecode::PopN(1),
+            ];
+            block.append(&mut body_codez);
+            block.push(Bytecode::Skip(remaining));
+            remaining += block.len();
+            blocks.push(block);
+        }
+
+        for mut block in blocks.into_iter().rev() {
+            codez.append(&mut block);
+        }
+        codez.append(&mut tail);
+        Ok(codez)
+    }
+
+    /// Compiles a `match` expression, which looks like `(match value (pattern1 expr1) ...)`.
+    /// The value is evaluated once, and checked against each pattern in order; the first matching
+    /// pattern's expression is the result, with the names in the pattern bound to the parts of the
+    /// value that they matched. If no pattern matches, the result is an empty list.
+    ///
+    /// Patterns can be:
+    /// * `_`, which matches anything
+    /// * a name, which matches anything and binds it to the name
+    /// * a string or number literal, which matches values equal to it
+    /// * a list of patterns, which matches lists of the same length whose items match; `()` matches
+    ///   the empty list. A list pattern may end with `& rest`, in which case it matches lists with
+    ///   at least as many items as come before the `&`, and the rest of the list is matched by the
+    ///   pattern after it.
+    fn match_builtin(&self, ast: &AST) -> Result<Vec<Bytecode>> {
+        assert!(ast.is_expr());
+        let exprs = ast.exprs();
+        let ref first = exprs[0];
+        if !first.is_identifier() {
+            return Err("match function must be called as an identifier".into());
+        }
+        assert!(first.identifier() == "match");
+        if exprs.len() < 2 {
+            return Err("match function requires a value to match against".into());
+        }
+
+        let mut codez = match self.values_to_bytecode(&vec![exprs[1].clone()]) {
+            Ok(l) => l,
+            e => return e.chain_err(|| "value of match function call"),
+        };
+
+        // each clause is compiled to the tests of its pattern, and its bindings and body; a
+        // pattern without any tests always matches, so it's the default
+        let mut clauses = Vec::new();
+        let mut default_codez = None;
+        for clause in exprs.iter().skip(2) {
+            if !clause.is_expr() || clause.exprs().len() != 2 {
+                return Err(diagnostic(clause.range(), "match clause must be a list of a pattern and an expression").into());
+            }
+            if default_codez.is_some() {
+                return Err(diagnostic(clause.range(), "match clause after a pattern that always matches").into());
+            }
+            let mut tests = Vec::new();
+            let mut bindings = Vec::new();
+            compile_pattern(&clause.exprs()[0], vec![Bytecode::Load(MATCH_VALUE_VAR.to_string())], &mut tests,
+                            &mut bindings)?;
+            let names = bindings.iter()
+                .map(|&(ref name, _)| name.clone())
+                .collect::<Vec<String>>();
+            let mut body_codez = Vec::new();
+            for (name, mut load_codez) in bindings {
+                body_codez.append(&mut load_codez);
+                body_codez.push(Bytecode::Pop(name));
+            }
+            match self.with_locals(names).values_to_bytecode(&vec![clause.exprs()[1].clone()]) {
+                Ok(mut l) => body_codez.append(&mut l),
+                e => return e.chain_err(|| diagnostic(clause.range(), "match clause")),
+            }
+            if tests.is_empty() {
+                default_codez = Some(body_codez);
+            }
+            else {
+                clauses.push((tests, body_codez));
+            }
+        }
+
+        // The value is kept in a variable of its own scope, which the bindings go in too.
+        //
+        //   <value> NewVarStack Pop(value)
+        //   <test> SkipFalse(->next) ... <bindings> <body> Skip(->end)
+        //   ...
+        //   next: <default>
+        //   end: PopVarStack
+        codez.push(Bytecode::NewVarStack);
+        codez.push(Bytecode::Pop(MATCH_VALUE_VAR.to_string()));
+        let tail = default_codez.unwrap_or_else(|| vec![Bytecode::Push(Value::new_list(vec![]))]);
+        let mut remaining = tail.len();
+        let mut blocks = Vec::new();
+        for (tests, mut block) in clauses.into_iter().rev() {
+            block.push(Bytecode::Skip(remaining));
+            for mut test in tests.into_iter().rev() {
+                test.push(Bytecode::SkipFalse(block.len()));
+                test.append(&mut block);
+                block = test;
+            }
+            remaining += block.len();
+            blocks.push(block);
+        }
+
+        for mut block in blocks.into_iter().rev() {
+            codez.append(&mut block);
+        }
+        codez.extend(tail);
+        codez.push(Bytecode::PopVarStack);
+        Ok(codez)
+    }
+
+    /// Compiles a `define` expression, which looks like `(define name (params...) body...)`.
+    /// This makes a function at runtime, which can use the variables in scope where it's defined,
+    /// and stores it in a local variable of the same name. Unlike `&define`, this can be used
+    /// anywhere, and its parameters are untyped. Unless it's a statement, the function is also its
+    /// value, e.g. `(list (define f () 1))` is a list of `f`.
+    fn define_builtin(&self, ast: &AST, gives_value: bool) -> Result<Vec<Bytecode>> {
+        assert!(ast.is_expr());
+        let exprs = ast.exprs();
+        let ref first = exprs[0];
+        if !first.is_identifier() {
+            return Err("define function must be called as an identifier".into());
+        }
+        assert!(first.identifier() == "define");
+        if exprs.len() < 3 {
+            return Err("define function requires a name, a list of parameters, and a body".into());
+        }
+        if !exprs[1].is_identifier() {
+            return Err(diagnostic(exprs[1].range(), "name of defined function must be an identifier").into());
+        }
+        if !exprs[2].is_expr() {
+            return Err(diagnostic(exprs[2].range(), "parameters of defined function must be a list").into());
+        }
+        let name = exprs[1].identifier().to_string();
+        let mut params = Vec::new();
+        for param in exprs[2].exprs() {
+            if !param.is_identifier() {
+                return Err(diagnostic(param.range(), "parameter of defined function must be an identifier").into());
+            }
+            params.push(param.identifier().to_string());
+        }
+
+        // the body sees the enclosing scope, the function itself, and its parameters
+        let mut body_locals = params.clone();
+        body_locals.push(name.clone());
+        let body = exprs.iter()
+            .skip(3)
+            .map(|x| x.clone())
+            .collect::<Vec<AST>>();
+        let body_codez = match self.with_locals(body_locals).body_to_bytecode(&body) {
+            Ok(l) => l,
+            e => return e.chain_err(|| format!("body of defined function `{}'", name)),
+        };
+        let mut codez = vec![Bytecode::MakeClosure(name.clone(), params, body_codez)];
+        if gives_value {
+            codez.push(Bytecode::Dup);
+        }
+        codez.push(Bytecode::Pop(name));
+        Ok(codez)
+    }
+}
+
+/// Gets whether an expression is a literal, including the empty list.
+fn is_constant(expr: &AST) -> bool {
+    match expr {
+        &AST::StringLit(_, _) | &AST::Number(_, _) | &AST::Integer(_, _) => true,
+        &AST::Expr(_, ref exprs) => exprs.is_empty(),
+        &AST::Identifier(_, _) | &AST::Comment(_, _) => false,
+    }
+}
+
+/// Compiles a `match` pattern. `load` is the code that pushes the part of the matched value that
+/// the pattern is for. Each test pushes whether one part of the pattern matches; they're run in
+/// order, so a test may assume that the ones before it passed. Each binding is a name, and the code
+/// that pushes the value that it's bound to.
+fn compile_pattern(pattern: &AST, load: Vec<Bytecode>, tests: &mut Vec<Vec<Bytecode>>,
+                   bindings: &mut Vec<(String, Vec<Bytecode>)>) -> Result<()> {
+    match pattern {
+        &AST::Identifier(_, ref name) if name == MATCH_WILDCARD => { },
+        &AST::Identifier(ref r, ref name) if name == MATCH_REST_MARKER =>
+            return Err(diagnostic(r, format!("`{}' may only come before the last pattern in a list pattern",
+                                             MATCH_REST_MARKER)).into()),
+        &AST::Identifier(ref r, ref name) => {
+            if bindings.iter().any(|&(ref bound, _)| bound == name) {
+                return Err(diagnostic(r, format!("`{}' is bound more than once in the same pattern", name)).into());
+            }
+            bindings.push((name.clone(), load));
+        },
+        &AST::StringLit(_, _) | &AST::Number(_, _) | &AST::Integer(_, _) => {
+            let mut test = load;
+            test.push(Bytecode::Push(pattern.to_value()));
+            test.push(Bytecode::Call("=".to_string(), 2));
+            tests.push(test);
+        },
+        &AST::Expr(_, ref items) => {
+            let rest_index = items.iter()
+                .position(|item| item.is_identifier() && item.identifier() == MATCH_REST_MARKER);
+            let (items, rest) = match rest_index {
+                Some(index) if index + 2 == items.len() => (&items[.. index], Some(&items[index + 1])),
+                Some(index) => return Err(diagnostic(items[index].range(),
+                                                     format!("`{}' must be followed by exactly one pattern",
+                                                             MATCH_REST_MARKER)).into()),
+                None => (&items[..], None),
+            };
+            let mut test = load.clone();
+            test.push(Bytecode::MatchList(items.len(), rest.is_some()));
+            tests.push(test);
+            // each item is the first item of what's left after the ones before it
+            let mut item_load = load;
+            for item in items {
+                let mut first_load = item_load.clone();
+                first_load.push(Bytecode::Call("car".to_string(), 1));
+                compile_pattern(item, first_load, tests, bindings)?;
+                item_load.push(Bytecode::Call("cdr".to_string(), 1));
+            }
+            if let Some(rest) = rest {
+                compile_pattern(rest, item_load, tests, bindings)?;
+            }
+        },
+        &AST::Comment(_, _) => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Gets the value of a function's body, if the body is a single literal.
+fn constant_body(fun: &Function) -> Option<Value> {
+    match fun.body.as_slice() {
+        [ref expr] if is_constant(expr) => Some(expr.to_value()),
+        _ => None,
+    }
+}
+
+/// Gets the name of the variable that an expression defines, if it's a `define` or `def` expression.
+fn defined_name(expr: &AST) -> Option<&str> {
+    let exprs = expr.exprs();
+    if exprs.len() > 1 && exprs[0].is_identifier() && (exprs[0].identifier() == "define" || exprs[0].identifier() == "def")
+        && exprs[1].is_identifier() {
+        Some(exprs[1].identifier())
+    }
+    else {
+        None
+    }
 }
 
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use vm::Value;
+    use vm::tests::{run_value, run_error};
+    use internal::{FunTable, TypeTable};
+    use lexer::Lexer;
+    use parser::Parser;
+    use preprocessor::Preprocessor;
+
+    /// Compiles some source code, giving the bytecode for its top level.
+    pub fn compile(source: &str) -> Vec<Bytecode> {
+        compile_with(source, |_| { })
+    }
+
+    /// Compiles some source code like `compile`, but with calls to constant functions inlined.
+    fn compile_inlined(source: &str) -> Vec<Bytecode> {
+        compile_with(source, |generator| generator.set_inline_constants(true))
+    }
+
+    /// Compiles some source code like `compile`, but with the stack checked after each statement.
+    fn compile_checked(source: &str) -> Vec<Bytecode> {
+        compile_with(source, |generator| generator.set_check_stack(true))
+    }
+
+    fn compile_with<F: FnOnce(&mut ToBytecode)>(source: &str, configure: F) -> Vec<Bytecode> {
+        let mut ast = Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap();
+        let (mut fun_table, mut type_table) = (FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        Preprocessor::new("<test>", &mut ast, &mut fun_table, &mut type_table)
+            .preprocess()
+            .unwrap();
+        let mut generator = ToBytecode::new(&fun_table, &type_table);
+        configure(&mut generator);
+        generator.to_bytecode(&ast)
+            .unwrap()
+    }
+
+    #[test]
+    fn and_short_circuits() {
+        // `(car 1)` would fail if it ran
+        assert_eq!(run_value("(and (= 1 2) (car 1))"), Value::Boolean(false));
+        assert_eq!(run_value("(and 1 (= 1 2) (car 1))"), Value::Boolean(false));
+        assert_eq!(run_value("(and 1 2 3)"), Value::Integer(3));
+        assert_eq!(run_value("(and)"), Value::Boolean(true));
+        assert!(run_error("(and 1 (car 1))").contains("argument to `car' function must be listy"));
+    }
+
+    #[test]
+    fn or_short_circuits() {
+        assert_eq!(run_value("(or 5 (car 1))"), Value::Integer(5));
+        assert_eq!(run_value("(or (= 1 2) 6 (car 1))"), Value::Integer(6));
+        assert_eq!(run_value("(or (= 1 2) (= 1 3))"), Value::Boolean(false));
+        assert_eq!(run_value("(or)"), Value::Boolean(false));
+        assert!(run_error("(or (= 1 2) (car 1))").contains("argument to `car' function must be listy"));
+    }
+
+    #[test]
+    fn and_or_leave_one_value() {
+        assert_eq!(run_value("(list (and 1 2) (or (= 1 2) 3) 4)"),
+                   Value::from(vec![Value::Integer(2), Value::Integer(3), Value::Integer(4)]));
+    }
+
+    #[test]
+    fn case_matches_keys() {
+        assert_eq!(run_value("(case (+ 1 1) (1 \"one\") (2 \"two\") (else \"many\"))"), Value::from("two"));
+        assert_eq!(run_value("(case \"b\" (\"a\" 1) (\"b\" 2))"), Value::Integer(2));
+    }
+
+    #[test]
+    fn case_falls_through_to_default() {
+        assert_eq!(run_value("(case 7 (1 \"one\") (2 \"two\") (else \"many\"))"), Value::from("many"));
+        assert_eq!(run_value("(case 7 (1 \"one\"))"), Value::new_list(vec![]));
+        assert_eq!(run_value("(list (case 7 (1 1) (else 2)) 3)"), Value::from(vec![Value::Integer(2), Value::Integer(3)]));
+    }
+
+    #[test]
+    fn case_rejects_bad_clauses() {
+        assert!(run_error("(case 1 ((list 1) 2))").contains("case key must be a literal or `else'"));
+        assert!(run_error("(case 1 (else 1) (1 2))").contains("case clause after else clause"));
+    }
+
+    #[test]
+    fn define_makes_local_functions() {
+        let source = "(&define outer (x) (define add-x (y) (+ x y)) (add-x 10)) (outer 5)";
+        assert_eq!(run_value(source), Value::Integer(15));
+    }
+
+    #[test]
+    fn defined_functions_can_recurse() {
+        let source = "(&define count-down (n) (define down (i acc) (if (zero? i) acc (down (- i 1) (conj acc i)))) (down n (list)))
+                      (count-down 3)";
+        assert_eq!(run_value(source), Value::from(vec![Value::Integer(3), Value::Integer(2), Value::Integer(1)]));
+    }
+
+    #[test]
+    fn defined_functions_stay_local() {
+        let source = "(&define outer () (define helper () 1) (helper)) (&define other () (helper)) (outer) (other)";
+        assert!(run_error(source).contains("helper"));
+    }
+
+    #[test]
+    fn define_gives_the_function_where_a_value_is_needed() {
+        assert_eq!(run_value("(if (define g (x) x) 1 2)"), Value::Integer(1));
+        match run_value("(list 1 (define g (x) x) 3)") {
+            Value::List(ref items) if items.len() == 3 => match items[1] {
+                Value::Closure(ref closure) => assert_eq!(closure.name, "g"),
+                ref v => panic!("expected a closure, but got {:?}", v),
+            },
+            v => panic!("expected a list of 3 items, but got {:?}", v),
+        }
+        assert_eq!(run_value("((define square (x) (* x x)) 4)"), Value::Integer(16));
+        // including at the end of a body
+        assert_eq!(run_value("(&define adder (n) (define add (x) (+ x n))) ((adder 2) 3)"), Value::Integer(5));
+        // but not as a statement
+        assert!(!compile("(define g () 1)").iter().any(|b| match b { &Bytecode::Dup => true, _ => false }));
+    }
+
+    #[test]
+    fn calling_type_is_error() {
+        assert!(run_error("(:int 5)").contains("`:int' is a type, not a function"));
+        assert!(run_error("(&type :int count) (count 5)").contains("`count' is a type, not a function"));
+        assert!(run_error("(nope 5)").contains("attempt to call non-existent function `nope'"));
+    }
+
+    #[test]
+    fn if_with_literal_branches() {
+        assert_eq!(compile("(if 0 1 2)"), vec![
+            Bytecode::Push(Value::Integer(0)),
+            Bytecode::SkipFalse(2),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Skip(1),
+            Bytecode::Push(Value::Integer(2)),
+        ]);
+    }
+
+    #[test]
+    fn if_with_expression_branches() {
+        // the true branch is longer than the false one
+        assert_eq!(compile("(if (= 1 2) (list 1 2 3) (+ 4 5))"), vec![
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Call("=".to_string(), 2),
+            Bytecode::SkipFalse(5),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Push(Value::Integer(3)),
+            Bytecode::Call("list".to_string(), 3),
+            Bytecode::Skip(3),
+            Bytecode::Push(Value::Integer(4)),
+            Bytecode::Push(Value::Integer(5)),
+            Bytecode::Call("+".to_string(), 2),
+        ]);
+        // a literal and an expression, and the other way around
+        assert_eq!(compile("(if 0 1 (+ 2 3))"), vec![
+            Bytecode::Push(Value::Integer(0)),
+            Bytecode::SkipFalse(2),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Skip(3),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Push(Value::Integer(3)),
+            Bytecode::Call("+".to_string(), 2),
+        ]);
+        assert_eq!(compile("(if x (+ 2 3) 1)"), vec![
+            Bytecode::Load("x".to_string()),
+            Bytecode::SkipFalse(4),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Push(Value::Integer(3)),
+            Bytecode::Call("+".to_string(), 2),
+            Bytecode::Skip(1),
+            Bytecode::Push(Value::Integer(1)),
+        ]);
+    }
+
+    #[test]
+    fn if_runs_one_branch() {
+        assert_eq!(run_value("(if (= 1 1) (list 1 2 3) (+ 4 5))"),
+                   Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
+        assert_eq!(run_value("(if (= 1 2) (list 1 2 3) (+ 4 5))"), Value::Integer(9));
+        assert_eq!(run_value("(list (if (= 1 2) 1 (+ 2 3)) 6)"), Value::from(vec![Value::Integer(5), Value::Integer(6)]));
+    }
+
+    #[test]
+    fn calling_empty_list_is_error() {
+        assert!(run_error("(())").contains("1:2-1:3: attempt to call the empty list `()' as a function"));
+        assert!(run_error("(list 1 (() 2))").contains("attempt to call the empty list `()' as a function"));
+        // it's still the empty list anywhere else
+        assert_eq!(run_value("(list ())"), Value::from(vec![Value::from(Vec::new())]));
+    }
+
+    #[test]
+    fn let_needs_a_body() {
+        assert!(run_error("(let ((x 1)))").contains("let function requires a list of assignments and a body"));
+    }
+
+    #[test]
+    fn def_binds_for_the_rest_of_the_scope() {
+        assert_eq!(run_value("(def x 5) (+ x 1)"), Value::Integer(6));
+        assert_eq!(run_value("(let ((a 1)) (def b 2) (+ a b))"), Value::Integer(3));
+        assert!(run_error("(def 1 2)").contains("name of defined variable must be an identifier"));
+        assert!(run_error("(def x)").contains("def function requires a name and a value (instead got 1 arguments)"));
+    }
+
+    #[test]
+    fn def_is_only_a_statement() {
+        let sources = [
+            "(if (def q 2) 1 2)",
+            "(+ 1 (def q 2))",
+            "(list 1 (def q 2) 3)",
+            "(list (when 1 (def a 1)) 2)",
+            "(list (match 1 (1 (def a 2)) (_ 3)) 4)",
+            // the end of a body gives its value
+            "(let ((a 1)) (def b 2))",
+            "(&define f () (def a 1)) (f)",
+        ];
+        for source in &sources {
+            assert!(run_error(source)
+                    .contains("def function doesn't give a value, so it may only be used at the top level or before the end of a body"),
+                    "{}", source);
+        }
+    }
+
+    #[test]
+    fn def_names_cannot_be_special_forms_or_builtins() {
+        assert!(run_error("(def if 1)").contains("1:5-1:6: cannot define a variable named `if', since it is a special form"));
+        assert!(run_error("(let () (def match 1) 2)").contains("cannot define a variable named `match', since it is a special form"));
+        assert!(run_error("(def car 1)").contains("cannot define a variable named `car', since it is a builtin function"));
+        // names that only start like them are fine
+        assert_eq!(run_value("(def iffy 1) (def cars 2) (+ iffy cars)"), Value::Integer(3));
+    }
+
+    #[test]
+    fn only_top_level_defs_are_global() {
+        let is_global_pop = |b: &Bytecode| match b { &Bytecode::PopGlobal(_) => true, _ => false };
+        assert!(compile("(def x 1)").iter().any(is_global_pop));
+        assert!(!compile("(let ((a 1)) (def b 2) b)").iter().any(is_global_pop));
+        assert!(!compile("(match 1 (x (let () (def y x) y)))").iter().any(is_global_pop));
+        assert!(run_error("(let ((a 1)) (def b 2) b) b").contains("unknown variable or function name: b"));
+        assert!(run_error("(&define f () (def y 1) y) (f) y").contains("unknown variable or function name: y"));
+    }
+
+    #[test]
+    fn top_level_defs_are_visible_to_functions() {
+        assert_eq!(run_value("(&define f () (+ x 1)) (def x 5) (f)"), Value::Integer(6));
+        // but locals and parameters shadow them
+        assert_eq!(run_value("(def x 5) (&define f (x) x) (f 7)"), Value::Integer(7));
+    }
+
+    #[test]
+    fn constant_functions_are_inlined() {
+        let code = compile_inlined("(&define f (a) 42) (f 1)");
+        assert_eq!(format!("{:?}", code), "[Push(Integer(42))]");
+        // but not when an argument might do something
+        let code = compile_inlined("(&define f (a) 42) (f (car \"x\"))");
+        assert!(code.iter().any(|b| match b { &Bytecode::Call(ref name, _) => name == "f", _ => false }));
+        // or when the constant is the wrong type, so calling it fails
+        let code = compile_inlined("(&define f () :int \"s\") (f)");
+        assert!(code.iter().any(|b| match b { &Bytecode::Call(ref name, _) => name == "f", _ => false }));
+        let code = compile_inlined("(&define f () :string \"s\") (f)");
+        assert_eq!(format!("{:?}", code), "[Push(String(\"s\"))]");
+    }
+
+    #[test]
+    fn when_and_unless_run_one_arm() {
+        let nil = Value::from(Vec::new());
+        assert_eq!(run_value("(when (= 1 1) (+ 1 2))"), Value::Integer(3));
+        assert_eq!(run_value("(when (= 1 2) (+ 1 2))"), nil);
+        assert_eq!(run_value("(unless (= 1 1) (+ 1 2))"), nil);
+        assert_eq!(run_value("(unless (= 1 2) (+ 1 2))"), Value::Integer(3));
+        // and leave the stack as it was for what comes after
+        assert_eq!(run_value("(list (when (= 1 2) 1) (unless (= 1 2) (list 2 3)) 4)"),
+                   Value::from(vec![nil, Value::from(vec![Value::Integer(2), Value::Integer(3)]), Value::Integer(4)]));
+    }
+
+    #[test]
+    fn when_and_unless_take_two_arguments() {
+        assert!(run_error("(when (= 1 1))").contains("when function requires exactly 2 arguments, got 1 instead"));
+        assert!(run_error("(unless (= 1 1) 1 2)").contains("unless function requires exactly 2 arguments, got 3 instead"));
+    }
+
+    #[test]
+    fn only_top_level_statements_are_checked() {
+        let checks = compile_checked("; comment\n(list (+ 1 2) 3)\n(if 1 (+ 1 1) 2)")
+            .into_iter()
+            .filter_map(|b| match b { Bytecode::CheckStack(s) => Some(s), _ => None })
+            .collect::<Vec<String>>();
+        assert_eq!(checks, vec!["statement at 2:1-2:16", "statement at 3:1-3:16"]);
+        assert!(!compile("(list 1)").iter().any(|b| match b { &Bytecode::CheckStack(_) => true, _ => false }));
+    }
+
+    #[test]
+    fn match_picks_the_first_matching_pattern() {
+        let source = "(&define m (v) (match v (() \"empty\") ((1 x) x) ((a & rest) rest) (\"s\" 0) (other other)))";
+        assert_eq!(run_value(&format!("{} (m (list))", source)), Value::String(String::from("empty")));
+        assert_eq!(run_value(&format!("{} (m (list 1 2))", source)), Value::Integer(2));
+        assert_eq!(run_value(&format!("{} (m (list 3 4 5))", source)),
+                   Value::from(vec![Value::Integer(4), Value::Integer(5)]));
+        assert_eq!(run_value(&format!("{} (m \"s\")", source)), Value::Integer(0));
+        assert_eq!(run_value(&format!("{} (m 2.5)", source)), Value::Number(2.5));
+    }
+
+    #[test]
+    fn match_without_a_match_is_nil() {
+        assert_eq!(run_value("(match (list 1) ((a b) a) (2 3))"), Value::from(Vec::new()));
+        // the bindings don't outlive their clause
+        assert!(run_error("(match 1 (x x)) x").contains("unknown variable or function name: x"));
+    }
+
+    #[test]
+    fn match_checks_its_patterns() {
+        assert!(run_error("(match 1 (_ 1) (2 2))").contains("match clause after a pattern that always matches"));
+        assert!(run_error("(match (list 1 1) ((a a) a))").contains("`a' is bound more than once in the same pattern"));
+        assert!(run_error("(match (list 1) ((& a b) a))").contains("`&' must be followed by exactly one pattern"));
+        assert!(run_error("(match 1 (& 1))").contains("`&' may only come before the last pattern in a list pattern"));
+        assert!(run_error("(match 1 (1))").contains("match clause must be a list of a pattern and an expression"));
+        assert!(run_error("(match)").contains("match function requires a value to match against"));
+    }
+}
@@ -0,0 +1,156 @@
+use ast::AST;
+
+/// Width that formatted lines are kept within, where possible.
+const MAX_WIDTH: usize = 100;
+/// Number of spaces that the items of a broken-up expression are indented by.
+const INDENT: usize = 2;
+
+/// Formats parsed source code canonically.
+/// Each top-level item goes on its own line. Expressions are separated by blank lines, but comments
+/// stay attached to whatever directly follows them, since they may be docstrings.
+pub fn format_source(ast: &Vec<AST>) -> String {
+    let mut out = String::new();
+    let mut prev: Option<&AST> = None;
+    for item in ast {
+        if let Some(prev) = prev {
+            let had_gap = item.range().start.line_number() > prev.range().end.line_number() + 1;
+            if !prev.is_comment() || had_gap {
+                out.push('\n');
+            }
+        }
+        format_item(item, 0, &mut out);
+        out.push('\n');
+        prev = Some(item);
+    }
+    out
+}
+
+/// Formats an item that starts at the given column.
+/// Expressions that fit on the rest of the line stay on one line; otherwise, their head (and any
+/// plain values right after it) go on the first line, and each other item goes on its own line.
+fn format_item(item: &AST, indent: usize, out: &mut String) {
+    let exprs = match item {
+        &AST::Expr(_, ref exprs) => exprs,
+        &AST::Comment(_, ref s) => {
+            out.push_str(&format!(";{}", s.trim_end()));
+            return;
+        },
+        _ => {
+            out.push_str(&format_flat(item).unwrap());
+            return;
+        },
+    };
+    if let Some(flat) = format_flat(item) {
+        if indent + flat.len() <= MAX_WIDTH {
+            out.push_str(&flat);
+            return;
+        }
+    }
+
+    out.push('(');
+    let mut on_first_line = true;
+    let mut column = indent + 1;
+    for (index, expr) in exprs.iter().enumerate() {
+        let is_atom = !expr.is_expr() && !expr.is_comment();
+        if index > 0 {
+            let flat_len = format_flat(expr).map(|s| s.len()).unwrap_or(0);
+            if on_first_line && is_atom && column + 1 + flat_len <= MAX_WIDTH {
+                out.push(' ');
+                column += 1;
+            }
+            else {
+                on_first_line = false;
+                out.push('\n');
+                push_spaces(indent + INDENT, out);
+                column = indent + INDENT;
+            }
+        }
+        let start = out.len();
+        format_item(expr, column, out);
+        column += out.len() - start;
+        // anything after a comment or a broken-up expression has to go on the next line
+        if expr.is_comment() || out[start..].contains('\n') {
+            on_first_line = false;
+        }
+    }
+    if exprs.last().map(|e| e.is_comment()).unwrap_or(false) {
+        out.push('\n');
+        push_spaces(indent, out);
+    }
+    out.push(')');
+}
+
+/// Formats an item on a single line, if it has no comments in it.
+fn format_flat(item: &AST) -> Option<String> {
+    match item {
+        &AST::Expr(_, ref exprs) => {
+            let mut items = Vec::new();
+            for expr in exprs {
+                items.push(format_flat(expr)?);
+            }
+            Some(format!("({})", items.join(" ")))
+        },
+        &AST::StringLit(_, ref s) => Some(format_string(s)),
+        &AST::Identifier(_, ref s) => Some(s.clone()),
+        // floats always keep their decimal point, so they stay floats
+        &AST::Number(_, n) => Some(if n.fract() == 0.0 { format!("{:.1}", n) } else { format!("{}", n) }),
+        &AST::Integer(_, n) => Some(n.to_string()),
+        // comments run to the end of the line
+        &AST::Comment(_, _) => None,
+    }
+}
+
+/// Formats a string literal, escaping the characters that need it.
+fn format_string(s: &str) -> String {
+    let mut formatted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => formatted.push_str("\\\""),
+            '\\' => formatted.push_str("\\\\"),
+            '\n' => formatted.push_str("\\n"),
+            '\r' => formatted.push_str("\\r"),
+            '\t' => formatted.push_str("\\t"),
+            c => formatted.push(c),
+        }
+    }
+    formatted.push('"');
+    formatted
+}
+
+fn push_spaces(count: usize, out: &mut String) {
+    for _ in 0 .. count {
+        out.push(' ');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+
+    fn format(source: &str) -> String {
+        let ast = Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap();
+        format_source(&ast)
+    }
+
+    const MESSY: &'static str = "; adds one\n(&define add1 (x)   (+ x\n 1))\n\n\n(stdwrite 1   \"tab\\there\\n \\\"quoted\\\" \\\\\")\n\
+                                 (list 1.0 2 (list \"a long string that keeps going\" \"and another long string\" \"and one more\") ; why\n 3)\n";
+
+    #[test]
+    fn format_is_canonical() {
+        assert_eq!(format(MESSY),
+                   "; adds one\n(&define add1 (x) (+ x 1))\n\n(stdwrite 1 \"tab\\there\\n \\\"quoted\\\" \\\\\")\n\n\
+                    (list 1.0 2\n  (list \"a long string that keeps going\" \"and another long string\" \"and one more\")\n  ; why\n  3)\n");
+    }
+
+    #[test]
+    fn format_is_idempotent() {
+        let formatted = format(MESSY);
+        assert_eq!(format(&formatted), formatted);
+        let basic = format(include_str!("../basic.rasp"));
+        assert_eq!(format(&basic), basic);
+    }
+}
@@ -1,24 +1,29 @@
 use ast::AST;
 use internal::*;
-use lexer;
+use lexer::{self, DEFAULT_TAB_WIDTH};
 use parser;
 use preprocessor::Preprocessor;
 use util;
+use bytecode::SPECIAL_FORMS;
 use errors::*;
 
+use std::fmt;
+use std::mem;
 use std::path::Path;
 
 const DEFINE_KEYWORD: &'static str = "&define";
 const EXTERN_KEYWORD: &'static str = "&extern";
 const TYPE_KEYWORD: &'static str = "&type";
 const INCLUDE_KEYWORD: &'static str = "&include";
+const ALIAS_KEYWORD: &'static str = "&alias";
 const OPTIONAL_TOKEN: &'static str = "?";
 
 pub fn is_builtin(keyword: &str) -> bool {
     keyword == DEFINE_KEYWORD   ||
     keyword == EXTERN_KEYWORD   ||
     keyword == TYPE_KEYWORD     ||
-    keyword == INCLUDE_KEYWORD
+    keyword == INCLUDE_KEYWORD  ||
+    keyword == ALIAS_KEYWORD
 }
 
 pub trait Gatherer<T> {
@@ -29,7 +34,7 @@ pub trait Gatherer<T> {
             if let &AST::Expr(ref range, ref exprs) = ast_item {
                 let visit_result = self.visit_exprs(exprs);
                 if visit_result.is_err() {
-                    visit_result.chain_err(|| format!("builtin expression at {}", range))?;
+                    visit_result.chain_err(|| diagnostic(range, "builtin expression"))?;
                 }
                 else if let Ok(i) = visit_result {
                     if i.is_some() {
@@ -72,8 +77,17 @@ pub trait Gatherer<T> {
 
 /// Gathers include directives
 pub struct IncludeGatherer<'a> {
+    source_file: &'a str,
     fun_table: &'a mut FunTable,
     type_table: &'a mut TypeTable,
+    include_depth: usize,
+    max_include_depth: usize,
+    /// Number of columns that a tab takes up in the included files
+    tab_width: usize,
+    /// Names of the functions that the included files defined
+    defined_funs: Vec<String>,
+    /// Warnings found in the included files, each of which names the file that it's in
+    warnings: Vec<Error>,
 }
 
 impl<'a> Gatherer<Vec<AST>> for IncludeGatherer<'a> {
@@ -108,6 +122,11 @@ impl<'a> Gatherer<Vec<AST>> for IncludeGatherer<'a> {
             index += 1;
         }
 
+        // this also stops include cycles
+        if self.include_depth >= self.max_include_depth {
+            return Err(format!("includes are nested too deeply (the limit is {})", self.max_include_depth).into());
+        }
+
         // attempt to compile all paths collected thus far
         let mut asts = Vec::new();
         for path in paths {
@@ -115,11 +134,13 @@ impl<'a> Gatherer<Vec<AST>> for IncludeGatherer<'a> {
             let mut type_table = TypeTable::new(Vec::new());
             let compile_result = self.compile_path(path, &mut fun_table, &mut type_table);
             if compile_result.is_err() {
-                compile_result.chain_err(|| format!("included file {}", path.display()))?;
+                compile_result.chain_err(|| format!("while including {} (from {})", path.display(), self.source_file))?;
             }
             else if let Ok(mut a) = compile_result {
                 asts.append(&mut a);
             }
+            self.defined_funs
+                .extend(fun_table.iter().map(|f| f.name.clone()));
             self.fun_table.merge(fun_table);
             self.type_table.merge(type_table)?;
         }
@@ -130,24 +151,50 @@ impl<'a> Gatherer<Vec<AST>> for IncludeGatherer<'a> {
 impl<'a> IncludeGatherer<'a> {
 
     /// Creates a new IncludeGatherer.
+    /// `source_file` is the file that the includes are in.
     /// `fun_table` is a mutable reference to a `FunTable`.
     /// `type_table` is a mutable reference to a `TypeTable`.
-    pub fn new(fun_table: &'a mut FunTable, type_table: &'a mut TypeTable) -> IncludeGatherer<'a> {
+    /// `include_depth` is how many includes deep `source_file` is, which may not reach
+    /// `max_include_depth`.
+    pub fn new(source_file: &'a str, fun_table: &'a mut FunTable, type_table: &'a mut TypeTable,
+               include_depth: usize, max_include_depth: usize) -> IncludeGatherer<'a> {
         IncludeGatherer {
+            source_file: source_file,
             fun_table: fun_table,
             type_table: type_table,
+            include_depth: include_depth,
+            max_include_depth: max_include_depth,
+            tab_width: DEFAULT_TAB_WIDTH as usize,
+            defined_funs: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 
+    /// Sets the number of columns that a tab takes up in the included files.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width;
+    }
+
+    /// Gets the names of the functions that the included files defined.
+    pub fn defined_funs(&self) -> &Vec<String> {
+        &self.defined_funs
+    }
+
+    /// Takes the warnings found in the included files, leaving none behind.
+    pub fn take_warnings(&mut self) -> Vec<Error> {
+        mem::replace(&mut self.warnings, Vec::new())
+    }
+
     /// Utility function that attempts to turn a path into an AST
     /// `funtbl` is a mutable reference to a `FunTable`.
     /// `typetbl` is a mutable reference to a `TypeTable`.
     fn compile_path(&mut self, path: &Path, mut funtbl: &mut FunTable, mut typetbl: &mut TypeTable) -> Result<Vec<AST>> {
         // I implore you to find a messier method
-        let file_contents = util::read_file(path.to_str().expect("Got a weird filename"))
-            .expect("Failed to load the file (permissions issues probably)");
+        let file_contents = util::read_file(path.to_str().expect("Got a weird filename"))?;
+        let mut lexer = lexer::Lexer::new(&file_contents);
+        lexer.set_tab_width(self.tab_width);
         let mut parser = parser::Parser
-            ::new(lexer::Lexer::new(&file_contents));
+            ::new(lexer);
         let parse_result = parser.parse();
         if parse_result.is_err() {
             return parse_result;
@@ -156,10 +203,25 @@ impl<'a> IncludeGatherer<'a> {
         // preprocess *this* AST
         {
             let mut preprocessor = Preprocessor::new(path.to_str().unwrap(), &mut ast, &mut funtbl, &mut typetbl);
+            preprocessor.set_include_depth(self.include_depth + 1, self.max_include_depth);
+            preprocessor.set_tab_width(self.tab_width);
             let preproc_result = preprocessor.preprocess();
             if let Err(e) = preproc_result {
                 return Err(e);
             }
+            // warnings from files that this one includes already name the file that they're in
+            for warning in preprocessor.take_warnings() {
+                let warning = if warning.included_file().is_some() {
+                    warning
+                }
+                else {
+                    let included: Result<()> = Err(warning);
+                    included.chain_err(|| ErrorKind::Included(path.display().to_string()))
+                        .unwrap_err()
+                };
+                self.warnings
+                    .push(warning);
+            }
         }
         Ok(ast)
     }
@@ -174,6 +236,8 @@ impl<'a> IncludeGatherer<'a> {
 pub struct FunGatherer<'a> {
     source_file: &'a str,
     type_table: &'a TypeTable,
+    /// Comment lines directly above the definition being visited, used if it has no docstring
+    leading_comment: String,
 }
 
 impl<'a> FunGatherer<'a> {
@@ -181,6 +245,7 @@ impl<'a> FunGatherer<'a> {
         FunGatherer {
             source_file: source_file,
             type_table: type_table,
+            leading_comment: String::new(),
         }
     }
 
@@ -211,6 +276,10 @@ impl<'a> FunGatherer<'a> {
                     optional = true;
                 }
             }
+            else if self.type_table.get_type(name).is_some() {
+                // types are eaten along with the name before them, so this one has no name
+                return Err(format!("expected a parameter name, but instead got the type `{}'", name).into());
+            }
             else {
                 let param = if i + 1 == limit {
                     // last item
@@ -237,6 +306,14 @@ impl<'a> FunGatherer<'a> {
             }
             i += 1;
         }
+        if optional && !params.iter().any(|p| p.optional) {
+            return Err(if params.is_empty() {
+                format!("`{}' must be followed by the optional parameters, but there are no parameters", OPTIONAL_TOKEN)
+            }
+            else {
+                format!("`{}' must be followed by at least one optional parameter", OPTIONAL_TOKEN)
+            }.into());
+        }
         Ok(params)
     }
 }
@@ -247,6 +324,42 @@ impl<'a> Gatherer<Function> for FunGatherer<'a> {
         DEFINE_KEYWORD
     }
 
+    /// Gathers function definitions, keeping track of the comments directly above each one.
+    fn gather(&mut self, ast: &Vec<AST>) -> Result<Vec<Function>> {
+        let mut funs = Vec::new();
+        // a run of comments on consecutive lines, and the line of the last one
+        let mut comment_lines: Vec<&str> = Vec::new();
+        let mut last_comment_line = 0;
+
+        for ast_item in ast {
+            match ast_item {
+                &AST::Comment(ref range, ref text) => {
+                    if range.start.line_number() != last_comment_line + 1 {
+                        comment_lines.clear();
+                    }
+                    comment_lines.push(text.trim_start_matches(';').trim());
+                    last_comment_line = range.start.line_number();
+                },
+                &AST::Expr(ref range, ref exprs) => {
+                    self.leading_comment = if range.start.line_number() == last_comment_line + 1 {
+                        comment_lines.join("\n")
+                    }
+                    else {
+                        String::new()
+                    };
+                    comment_lines.clear();
+                    match self.visit_exprs(exprs) {
+                        Ok(Some(fun)) => funs.push(fun),
+                        Ok(None) => { },
+                        Err(e) => return Err(e).chain_err(|| diagnostic(range, "builtin expression")),
+                    }
+                },
+                _ => comment_lines.clear(),
+            }
+        }
+        Ok(funs)
+    }
+
     fn visit_expr(&mut self, exprs: &Vec<AST>) -> Result<Function> {
         assert!(exprs[0].is_identifier() && exprs[0].identifier() == DEFINE_KEYWORD);
         if exprs.len() < 3 {
@@ -255,34 +368,54 @@ impl<'a> Gatherer<Function> for FunGatherer<'a> {
         }
 
         let name = exprs[1].identifier();
+        if SPECIAL_FORMS.contains(&name) {
+            return Err(diagnostic(exprs[1].range(), format!("cannot define a function named `{}', since it is a special form", name))
+                       .into());
+        }
         let params = match &exprs[2] {
             &AST::Expr(ref r, ref expr_list) => match self.get_params(expr_list) {
                 Ok(params) => params,
-                e => e.chain_err(|| format!("{}", r))?,
+                e => e.chain_err(|| diagnostic(r, ""))?,
             },
             ref t => return Err(format!("expected params list, but instead got a {} item", t).into()),
         };
 
-        if exprs.len() == 3 {
-            Ok(Function::new(name.to_string(), params, String::new(), Vec::new(), self.source_file))
+        // a type right after the params is the type of the value the function gives
+        let mut start = 3;
+        let return_type = match exprs.get(start) {
+            Some(&AST::Identifier(_, ref type_name)) => self.type_table
+                .get_type(type_name)
+                .cloned(),
+            _ => None,
+        };
+        if return_type.is_some() {
+            start += 1;
+        }
+
+        if exprs.len() == start {
+            Ok(Function::new(name.to_string(), params, return_type, self.leading_comment.clone(), Vec::new(),
+                             self.source_file))
         }
         else {
-            assert!(exprs.len() >= 4);
-            // get whether this is the docstring, or if it's the start of the body
-            let mut start = 3;
-            let docstring = if let AST::StringLit(_, ref s) = exprs[start] {
-                start += 1;
-                s.to_string()
-            }
-            else {
-                String::new()
+            assert!(exprs.len() > start);
+            // get whether this is the docstring, or if it's the start of the body; a string on its
+            // own is the body, since the function has to give something
+            let docstring = match exprs[start] {
+                AST::StringLit(_, ref s) if exprs.len() > start + 1 => {
+                    start += 1;
+                    s.to_string()
+                },
+                _ => {
+                    // fall back on the comment above the definition
+                    self.leading_comment.clone()
+                },
             };
 
             let mut body = Vec::new();
             for expr in exprs.iter().skip(start) {
                  body.push(expr.clone());
             }
-            Ok(Function::new(name.to_string(), params, docstring, body, self.source_file))
+            Ok(Function::new(name.to_string(), params, return_type, docstring, body, self.source_file))
         }
     }
 }
@@ -340,104 +473,277 @@ impl Gatherer<Function> for ExternGatherer {
 }
 */
 
+/*******************************
+ * ALIASGATHERER
+ */
+/// Gathers `(&alias FUNCTION ALIAS)` directives, as (function name, alias, range) triples.
+/// The function names aren't checked here, since they may be aliases gathered at the same time.
+pub struct AliasGatherer;
+
+impl Gatherer<(String, String, lexer::Range)> for AliasGatherer {
+    fn keyword(&self) -> &'static str {
+        ALIAS_KEYWORD
+    }
+
+    fn visit_expr(&mut self, exprs: &Vec<AST>) -> Result<(String, String, lexer::Range)> {
+        assert!(exprs[0].is_identifier() && exprs[0].identifier() == ALIAS_KEYWORD);
+        if exprs.len() != 3 {
+            return Err(format!("{kw} must be at exactly 3 items long: I found {} items ({kw} FUNCTION ALIAS)", exprs.len(), kw=ALIAS_KEYWORD)
+                       .into());
+        }
+        if !exprs[1].is_identifier() {
+            return Err(format!("param 1: expected identifier, but instead got {}", exprs[1]).into());
+        }
+        if !exprs[2].is_identifier() {
+            return Err(format!("param 2: expected identifier, but instead got {}", exprs[2]).into());
+        }
+        let range = exprs[0].range().merge(exprs[2].range());
+        Ok((exprs[1].identifier().to_string(), exprs[2].identifier().to_string(), range))
+    }
+}
+
 /*******************************
  * TYPEGATHERER
  */
+
+/// The type on the left side of a `&type` directive, before the type names in it are resolved.
+#[derive(Clone, Debug)]
+pub enum TypeSpec {
+    /// An existing type name, which the new type becomes an alias of
+    Name(String),
+    /// `(list-of TYPE)`
+    ListOf(Box<TypeSpec>),
+    /// `(record (FIELD TYPE) ...)`
+    Record(Vec<(String, TypeSpec)>),
+}
+
+impl TypeSpec {
+    /// Parses a type spec out of an expression.
+    fn from_ast(ast: &AST) -> Result<TypeSpec> {
+        if ast.is_identifier() {
+            return Ok(TypeSpec::Name(ast.identifier().to_string()));
+        }
+        let exprs = match ast {
+            &AST::Expr(_, ref exprs) if exprs.len() > 0 && exprs[0].is_identifier() => exprs,
+            _ => return Err(format!("expected a type name, list-of or record, but instead got {}", ast).into()),
+        };
+        match exprs[0].identifier() {
+            "list-of" => {
+                if exprs.len() != 2 {
+                    return Err(format!("list-of must be exactly 2 items long: I found {} items (list-of TYPE)", exprs.len())
+                               .into());
+                }
+                Ok(TypeSpec::ListOf(Box::new(TypeSpec::from_ast(&exprs[1])?)))
+            },
+            "record" => {
+                let mut fields: Vec<(String, TypeSpec)> = Vec::new();
+                for field in &exprs[1..] {
+                    if !field.is_expr() || field.exprs().len() != 2 || !field.exprs()[0].is_identifier() {
+                        return Err(format!("record fields must be a name and a type (NAME TYPE), but instead got {}", field)
+                                   .into());
+                    }
+                    let name = field.exprs()[0].identifier();
+                    if fields.iter().any(|&(ref n, _)| n == name) {
+                        return Err(format!("record field {} is defined more than once", name).into());
+                    }
+                    fields.push((name.to_string(), TypeSpec::from_ast(&field.exprs()[1])?));
+                }
+                Ok(TypeSpec::Record(fields))
+            },
+            other => Err(format!("unknown type constructor {} (expected list-of or record)", other).into()),
+        }
+    }
+
+    /// Gets the type names that this spec refers to.
+    fn names(&self) -> Vec<&str> {
+        match self {
+            &TypeSpec::Name(ref name) => vec![name],
+            &TypeSpec::ListOf(ref item_spec) => item_spec.names(),
+            &TypeSpec::Record(ref fields) => fields.iter()
+                .flat_map(|&(_, ref field_spec)| field_spec.names())
+                .collect(),
+        }
+    }
+
+    /// Converts this spec into a type, if all of the type names it refers to are in the table.
+    fn resolve(&self, type_table: &TypeTable) -> Option<Type> {
+        match self {
+            &TypeSpec::Name(ref name) => type_table.get_type(name).cloned(),
+            &TypeSpec::ListOf(ref item_spec) => item_spec.resolve(type_table)
+                .map(|t| Type::ListOf(Box::new(t))),
+            &TypeSpec::Record(ref fields) => {
+                let mut field_types = Vec::new();
+                for &(ref name, ref field_spec) in fields {
+                    field_types.push((name.clone(), field_spec.resolve(type_table)?));
+                }
+                Some(Type::Record(field_types))
+            },
+        }
+    }
+}
+
+impl fmt::Display for TypeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TypeSpec::Name(ref name) => write!(f, "{}", name),
+            &TypeSpec::ListOf(ref item_spec) => write!(f, "(list-of {})", item_spec),
+            &TypeSpec::Record(ref fields) => {
+                write!(f, "(record")?;
+                for &(ref name, ref field_spec) in fields {
+                    write!(f, " ({} {})", name, field_spec)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
 pub struct TypeGatherer;
 
-impl Gatherer<(String, String, lexer::Range)> for TypeGatherer {
+impl Gatherer<(TypeSpec, String, lexer::Range)> for TypeGatherer {
 
     fn keyword(&self) -> &'static str {
         TYPE_KEYWORD
     }
 
-    fn visit_expr(&mut self, exprs: &Vec<AST>) -> Result<(String, String, lexer::Range)> {
+    fn visit_expr(&mut self, exprs: &Vec<AST>) -> Result<(TypeSpec, String, lexer::Range)> {
         assert!(exprs[0].is_identifier() && exprs[0].identifier() == TYPE_KEYWORD);
-        let start = exprs[0].range()
-            .start;
         if exprs.len() != 3 {
             return Err(format!("{kw} must be at exactly 3 items long: I found {} items ({kw} TYPE NEWTYPE)", exprs.len(), kw=TYPE_KEYWORD)
                        .into());
         }
-        if !exprs[1].is_identifier() {
-            return Err(format!("param 1: expected identifier, but instead got {}", exprs[1]).into());
-        }
+        let oldtype = TypeSpec::from_ast(&exprs[1])
+            .chain_err(|| "param 1")?;
         if !exprs[2].is_identifier() {
             return Err(format!("param 2: expected identifier, but instead got {}", exprs[2]).into());
         }
-        let oldtype = exprs[1].identifier();
         let newtype = exprs[2].identifier();
-        if oldtype == newtype {
-            return Err(format!("illegal type definition: cannot define a type to itself ({} to {})", 
+        if oldtype.names().contains(&newtype) {
+            return Err(format!("illegal type definition: cannot define a type in terms of itself ({} to {})", 
                                oldtype, newtype).into());
         }
-        let end = exprs[2].range()
-            .end;
-        Ok((oldtype.to_string(), newtype.to_string(), lexer::Range::new(start, end)))
+        let range = exprs[0].range().merge(exprs[2].range());
+        Ok((oldtype, newtype.to_string(), range))
     }
 }
 
 impl<'b> TypeGatherer {
+    /// Makes the error for type mappings that could not be resolved.
+    /// Starting from each unresolved type, this follows the types that it is defined in terms of
+    /// until it either comes back around to a type it has already been through, which is reported as
+    /// a cycle (e.g. `a -> b -> c -> a`), or reaches a type that is never defined at all.
+    fn unresolved_error(proto_types: &Vec<(TypeSpec, String, lexer::Range)>, type_table: &TypeTable) -> Error {
+        for &(_, ref start, _) in proto_types {
+            if let Some(err) = TypeGatherer::find_unresolved(start, proto_types, type_table, &mut Vec::new()) {
+                return err;
+            }
+        }
+        unreachable!("unresolved type mappings always either form a cycle or name an unknown type")
+    }
+
+    /// Searches the definition of `name` for a cycle or an unknown type, given the mappings that
+    /// the search has gone through to get to it.
+    fn find_unresolved<'a>(name: &'a str, proto_types: &'a Vec<(TypeSpec, String, lexer::Range)>,
+                           type_table: &TypeTable, path: &mut Vec<&'a (TypeSpec, String, lexer::Range)>)
+        -> Option<Error> {
+        if let Some(index) = path.iter().position(|&&(_, ref new, _)| new == name) {
+            let cycle = path[index..]
+                .iter()
+                .map(|&&(_, ref new, _)| new.as_str())
+                .chain(Some(name))
+                .collect::<Vec<_>>();
+            return Some(diagnostic(&path[index].2, format!("type definitions form a cycle: {}", cycle.join(" -> ")))
+                        .into());
+        }
+        let mapping = match proto_types.iter().find(|&&(_, ref new, _)| new == name) {
+            Some(mapping) => mapping,
+            None if type_table.has_type(name) => return None,
+            None => {
+                // the last mapping refers to a type that doesn't exist
+                let &&(_, ref new, ref range) = path.last().unwrap();
+                return Some(diagnostic(range, format!("unknown type {} in the definition of {}", name, new)).into());
+            },
+        };
+        path.push(mapping);
+        for dep in mapping.0.names() {
+            if let Some(err) = TypeGatherer::find_unresolved(dep, proto_types, type_table, path) {
+                return Some(err);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    /// Adds the type defined by a mapping to the table, if the types that it refers to are all
+    /// there already. Returns whether the type was added.
+    fn link(spec: &TypeSpec, new: &str, range: &lexer::Range, type_table: &mut TypeTable) -> Result<bool> {
+        if type_table.has_type(new) { // check that the types match before producing an error
+            // a definition that's repeated exactly is fine, so compare what both sides are made of
+            let pointing_to = type_table.get_type(new)
+                .unwrap()
+                .underlying();
+            let resolved = match spec.resolve(type_table) {
+                Some(resolved) => resolved,
+                None => return Ok(false),
+            };
+            if resolved.underlying() != pointing_to {
+                return Err(diagnostic(range, format!("invalid type mapping from {} to {}: was already set to {}",
+                                                     new, spec, pointing_to)).into());
+            }
+            Ok(true)
+        }
+        else if let &TypeSpec::Name(ref old) = spec {
+            if type_table.has_type(old) {
+                type_table.add_typedef(new, old);
+                Ok(true)
+            }
+            else {
+                Ok(false)
+            }
+        }
+        else if let Some(resolved) = spec.resolve(type_table) {
+            type_table.add_type(Type::Named(new.to_string(), Box::new(resolved)));
+            Ok(true)
+        }
+        else {
+            Ok(false)
+        }
+    }
+
     pub fn gather_and_link(&mut self, exprs: &Vec<AST>) -> Result<TypeTable> {
         let mut type_table = TypeTable::new(vec![Type::Number, Type::Str, Type::Listy]);
         match self.gather(exprs) {
             Ok(type_mappings) => {
                 let mut proto_types = Vec::new();
-                for (old, new, range) in type_mappings {
-                    if type_table.has_type(&new) { // check that the types match before producing an error
-                        let pointing_to = type_table.get_type(&new)
-                                                    .unwrap();
-                        if old != pointing_to.name() {
-                            return Err(format!("invalid type mapping from {} to {}: was already set to {} at {}",
-                                               new, old, pointing_to.name(), range)
-                                       .into());
-                        }
-                    }
-                    else if type_table.has_type(&old) {
-                        type_table.add_typedef(&new, &old);
-                    }
-                    else {
-                        proto_types.push((old, new, range));
+                for (spec, new, range) in type_mappings {
+                    if !TypeGatherer::link(&spec, &new, &range, &mut type_table)? {
+                        proto_types.push((spec, new, range));
                     }
                 }
 
-                let mut last_size = 0;
-                loop {
-                    if proto_types.len() == 0 {
-                        break;
+                // every pass links at least one type, so there can't be more passes than types;
+                // this is a backstop in case that ever stops being true
+                let max_passes = proto_types.len();
+                let mut passes = 0;
+                while proto_types.len() > 0 {
+                    if passes == max_passes {
+                        return Err(format!("type resolution did not converge after {} passes ({} types still unresolved)",
+                                           passes, proto_types.len()).into());
                     }
-                    else if last_size == proto_types.len() {
-                        // TODO(alek) better error message for this type deduction
-                        // TODO(alek) tell user what to do if there is *not* a cycle and it's a compiler bug
-                        let mut err_msg = String::from("Went one cycle without deducing a type; I am assuming there is a cycle or an invalid type specified. Here are the types I could not deduce:\n");
-                        for (old, new, range) in proto_types {
-                            err_msg += &format!("    {} -> {} (defined at {})\n", old, new, range);
+                    passes += 1;
+                    let last_size = proto_types.len();
+                    // add types to table, and remove any types that were added
+                    let mut unlinked = Vec::new();
+                    for (spec, new, range) in proto_types {
+                        if !TypeGatherer::link(&spec, &new, &range, &mut type_table)? {
+                            unlinked.push((spec, new, range));
                         }
-                        return Err(err_msg.into());
                     }
-
-                    // add types to table
-                    for &(ref old, ref new, ref range) in &proto_types {
-                        if type_table.has_type(new) { // check that the types match before producing an error
-                            let pointing_to = type_table.get_type(new)
-                                .unwrap();
-                            if old != pointing_to.name() {
-                                return Err(format!("invalid type mapping from {} to {} at {}: was already set to {}",
-                                                   new, old, range, pointing_to.name()).into());
-                            }
-                        }
-                        else if type_table.has_type(old) {
-                            type_table.add_typedef(new, old);
-                        }
+                    proto_types = unlinked;
+                    // went a whole pass without resolving anything
+                    if last_size == proto_types.len() {
+                        return Err(TypeGatherer::unresolved_error(&proto_types, &type_table));
                     }
-
-                    // remove any types that were added
-                    proto_types = proto_types.into_iter()
-                                             .filter(|&(_, ref new, _)| {
-                                                 !type_table.has_type(new)
-                                             })
-                                             .collect::<Vec<_>>();
-                    last_size = proto_types.len();
                 }
 
                 Ok(type_table)
@@ -448,3 +754,186 @@ impl<'b> TypeGatherer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use internal::{FunTable, TypeTable};
+    use vm::{Value, VM};
+    use vm::tests::{error_text, run_error, run_value, temp_path, temp_source};
+
+    /// Writes a chain of files that each include the next, where the last one defines `deepest`.
+    /// Gives the path of the first file.
+    fn include_chain(name: &str, length: usize) -> String {
+        let file_name = |index| format!("{}{}.rasp", name, index);
+        temp_source(&file_name(length - 1), "(&define deepest () 42)");
+        for index in (0 .. length - 1).rev() {
+            temp_source(&file_name(index), &format!("(&include \"{}\")", temp_path(&file_name(index + 1))));
+        }
+        temp_path(&file_name(0))
+    }
+
+    #[test]
+    fn includes_within_depth_limit() {
+        let path = include_chain("shallow", 3);
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_include_depth(3);
+        let values = vm.eval("<test>", &format!("(&include \"{}\") (deepest)", path))
+            .unwrap();
+        assert_eq!(values, vec![Value::Integer(42)]);
+    }
+
+    #[test]
+    fn includes_past_depth_limit() {
+        let path = include_chain("deep", 4);
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_include_depth(3);
+        let err = vm.eval("<test>", &format!("(&include \"{}\") (deepest)", path))
+            .unwrap_err();
+        let text = error_text(&err);
+        assert!(text.contains("includes are nested too deeply (the limit is 3)"));
+        // every file on the way is in the trace
+        assert!(text.contains(&format!("while including {} (from <test>)", path)));
+        assert!(text.contains(&format!("while including {} (from {})", temp_path("deep2.rasp"), temp_path("deep1.rasp"))));
+    }
+
+    /// Gets the docstring of a function defined in some source.
+    fn docstring(source: &str, name: &str) -> String {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.eval("<test>", source)
+            .unwrap();
+        vm.fun_table()
+            .get_fun(name)
+            .unwrap()
+            .docstring
+            .clone()
+    }
+
+    #[test]
+    fn comment_above_definition_is_docstring() {
+        assert_eq!(docstring("; adds one\n; to a number\n(&define add1 (x) (+ x 1))", "add1"), "adds one\nto a number");
+    }
+
+    #[test]
+    fn detached_comment_is_not_docstring() {
+        assert_eq!(docstring("; about something else\n\n(&define add1 (x) (+ x 1))", "add1"), "");
+        assert_eq!(docstring("; about the list\n(list 1)\n(&define add1 (x) (+ x 1))", "add1"), "");
+    }
+
+    #[test]
+    fn string_docstring_beats_comment() {
+        assert_eq!(docstring("; the comment\n(&define add1 (x) \"the string\" (+ x 1))", "add1"), "the string");
+    }
+
+    #[test]
+    fn lone_string_is_the_body() {
+        assert_eq!(docstring("; the comment\n(&define name () \"the string\")", "name"), "the comment");
+        assert_eq!(run_value("(&define name () \"the string\") (name)"), Value::String(String::from("the string")));
+    }
+
+    #[test]
+    fn declared_return_types_are_checked() {
+        assert_eq!(run_value("(&define f (x) :int \"gives x\" x) (f 2)"), Value::Integer(2));
+        assert!(run_error("(&define f (x) :int x) (f \"a\")")
+                .contains("function f should give a value of type :int, but gave a string"));
+        // a string on its own is what the function gives, not its docstring
+        assert!(run_error("(&define f () :int \"s\") (f)")
+                .contains("function f should give a value of type :int, but gave a string"));
+        assert!(run_error("(&define f () :int) (f)").contains("function f should give a value of type :int, but gave nothing"));
+    }
+
+    #[test]
+    fn return_types_are_part_of_usage() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.eval("<test>", "(&define f (a :string) :int 1) (&define g () 1)")
+            .unwrap();
+        assert_eq!(vm.fun_table().get_fun("f").unwrap().usage(), "(f a :string) :int");
+        assert_eq!(vm.fun_table().get_fun("g").unwrap().usage(), "(g)");
+    }
+
+    #[test]
+    fn types_defined_backwards_resolve() {
+        // each pass can only link the last type defined, so this takes as many passes as types
+        let mut source = (1 .. 20)
+            .rev()
+            .map(|n| format!("(&type t{} t{})", n - 1, n))
+            .collect::<String>();
+        source += "(&type :int t0) (&define f (x t19) x) (f 3)";
+        assert_eq!(run_value(&source), Value::Integer(3));
+    }
+
+    #[test]
+    fn type_cycle_is_named() {
+        let err = run_error("(&type a b) (&type b c) (&type c a)");
+        assert!(err.contains("type definitions form a cycle: b -> a -> c -> b"), "{}", err);
+    }
+
+    #[test]
+    fn type_cycle_through_list_of_is_named() {
+        let err = run_error("(&type (list-of y) x) (&type (record (item x)) y)");
+        assert!(err.contains("type definitions form a cycle: x -> y -> x"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_type_in_definition_is_named() {
+        let err = run_error("(&type :int a) (&type nope b)");
+        assert!(err.contains("unknown type nope in the definition of b"), "{}", err);
+    }
+
+    #[test]
+    fn type_defined_as_itself_is_error() {
+        assert!(run_error("(&type (list-of a) a)").contains("cannot define a type in terms of itself"));
+    }
+
+    #[test]
+    fn list_of_type_checks_items() {
+        let define = "(&type (list-of :int) list-of-int) (&define twice (x) list-of-int (list x x))";
+        assert_eq!(run_value(&format!("{} (twice 1)", define)), Value::new_list(vec![Value::Integer(1), Value::Integer(1)]));
+        let err = run_error(&format!("{} (twice \"a\")", define));
+        assert!(err.contains("function twice should give a value of type list-of-int, but gave a list"), "{}", err);
+    }
+
+    #[test]
+    fn repeated_type_definition_is_not_conflict() {
+        let record = "(&type (record (x :int) (y :int)) pt)";
+        assert_eq!(run_value(&format!("{} {} (list 1)", record, record)), Value::new_list(vec![Value::Integer(1)]));
+        let list_of = "(&type (list-of :int) list-of-int)";
+        assert_eq!(run_value(&format!("{} {} (list 1)", list_of, list_of)), Value::new_list(vec![Value::Integer(1)]));
+        assert_eq!(run_value("(&type :int a) (&type a b) (&type a b) (list 1)"), Value::new_list(vec![Value::Integer(1)]));
+    }
+
+    #[test]
+    fn different_type_definition_is_conflict() {
+        let err = run_error("(&type (record (x :int)) pt) (&type (record (x :string)) pt)");
+        assert!(err.contains("invalid type mapping from pt to (record (x :string)): was already set to (record (x :int))"), "{}", err);
+    }
+
+    #[test]
+    fn optional_params_get_defaults() {
+        let define = "(&define f (a ? b :int c) (list a b c))";
+        assert_eq!(run_value(&format!("{} (f 1)", define)),
+                   Value::new_list(vec![Value::Integer(1), Value::Integer(0), Value::new_list(vec![])]));
+        assert_eq!(run_value(&format!("{} (f 1 2 3)", define)),
+                   Value::new_list(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
+    }
+
+    #[test]
+    fn optional_token_needs_optional_params() {
+        assert!(run_error("(&define f (?) 1)").contains("`?' must be followed by the optional parameters, but there are no parameters"));
+        assert!(run_error("(&define f (a ?) a)").contains("`?' must be followed by at least one optional parameter"));
+        assert!(run_error("(&define f (a ? ? b) a)").contains("only one `?' token is allowed in parameter declarations"));
+    }
+
+    #[test]
+    fn param_names_cannot_be_types() {
+        assert!(run_error("(&define f (a :int :int) a)").contains("expected a parameter name, but instead got the type `:int'"));
+        assert!(run_error("(&define f (? :int) 1)").contains("expected a parameter name, but instead got the type `:int'"));
+    }
+
+    #[test]
+    fn functions_cannot_be_named_after_special_forms() {
+        assert!(run_error("(&define if (a) a)").contains("1:9-1:10: cannot define a function named `if', since it is a special form"));
+        assert!(run_error("(&define let () 1)").contains("cannot define a function named `let', since it is a special form"));
+        // names that only start like special forms are fine
+        assert_eq!(run_value("(&define iffy () 1) (iffy)"), Value::Integer(1));
+    }
+}
@@ -1,6 +1,8 @@
 use ast::AST;
 use errors::*;
 
+use std::fmt;
+
 pub const INT_TYPE: &'static str = ":int";
 pub const STRING_TYPE: &'static str = ":string";
 pub const LISTY_TYPE: &'static str = ":listy";
@@ -27,8 +29,23 @@ impl Param {
     }
 }
 
+impl fmt::Display for Param {
+    /// Writes this parameter as its name and type, e.g. `y? :string`. Optional parameters have a
+    /// `?` after their name, and parameters of any type leave the type out.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.optional {
+            write!(f, "?")?;
+        }
+        match self.param_type {
+            Type::Any => Ok(()),
+            ref t => write!(f, " {}", t),
+        }
+    }
+}
+
 /// Defines an internal type.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Type {
     /// A number
     Number,
@@ -45,6 +62,12 @@ pub enum Type {
        for some reason, the lifetimes for it weren't working. With the string layout, we have
        potential for cycles, which are not good eats.
     */
+    /// A list whose items all have the given type
+    ListOf(Box<Type>),
+    /// A list of named fields, each with its own type
+    Record(Vec<(String, Type)>),
+    /// A user-defined name for a list-of or record type
+    Named(String, Box<Type>),
 }
 
 impl Type {
@@ -71,6 +94,17 @@ impl Type {
             &Type::Str => STRING_TYPE,
             &Type::TypeDef(ref name, _) => name,
             &Type::Any => ANY_TYPE,
+            // composite types are lists underneath
+            &Type::ListOf(_) | &Type::Record(_) => LISTY_TYPE,
+            &Type::Named(ref name, _) => name,
+        }
+    }
+
+    /// Gets the type that a user-defined name stands for, or this type if it isn't named.
+    pub fn underlying(&self) -> &Type {
+        match self {
+            &Type::Named(_, ref inner) => inner,
+            t => t,
         }
     }
 
@@ -85,6 +119,23 @@ impl Type {
     }
 }
 
+impl fmt::Display for Type {
+    /// Writes this type the way it would be written in a `&type` directive.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Type::ListOf(ref item_type) => write!(f, "(list-of {})", item_type),
+            &Type::Record(ref fields) => {
+                write!(f, "(record")?;
+                for &(ref name, ref field_type) in fields {
+                    write!(f, " ({} {})", name, field_type)?;
+                }
+                write!(f, ")")
+            },
+            t => write!(f, "{}", t.name()),
+        }
+    }
+}
+
 pub struct TypeTable {
     types: Vec<Type>,
 }
@@ -110,13 +161,11 @@ impl TypeTable {
         None
     }
 
-    /*
     pub fn add_type(&mut self, target: Type) {
         assert!(!self.has_type(target.name()), "Type aready exists in type table");
         self.types
             .push(target);
     }
-    */
 
     /// Merges two type tables, consuming the other typetable.
     /// This will result in an error if there are any mismatched types.
@@ -159,6 +208,20 @@ impl TypeTable {
         type_result.is_some()
     }
 
+    /// Describes every type in the table, one per line. Types that are defined in terms of another
+    /// type are written as `name = underlying type`.
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for t in &self.types {
+            match t {
+                &Type::TypeDef(ref name, ref to) => description += &format!("{} = {}\n", name, to),
+                &Type::Named(ref name, ref inner) => description += &format!("{} = {}\n", name, inner),
+                t => description += &format!("{}\n", t),
+            }
+        }
+        description
+    }
+
     pub fn dump_debug(&self) {
         for t in &self.types {
             debug!("- TYPE -------------------------------------------------------------------------");
@@ -174,7 +237,12 @@ impl TypeTable {
                         .name();
                     debug!("type: typedef");
                     debug!("underlying type: {}", to);
-                }
+                },
+                &Type::ListOf(_) | &Type::Record(_) => debug!("type: {}", t),
+                &Type::Named(_, ref inner) => {
+                    debug!("type: named");
+                    debug!("underlying type: {}", inner);
+                },
             }
         }
         debug!("--------------------------------------------------------------------------------");
@@ -184,6 +252,8 @@ impl TypeTable {
 /// A function table.
 pub struct FunTable {
     funs: Vec<Function>,
+    /// Alternate names for functions, as (alias, function name) pairs
+    aliases: Vec<(String, String)>,
 }
 
 impl FunTable {
@@ -191,18 +261,67 @@ impl FunTable {
     pub fn new(funs: Vec<Function>) -> FunTable {
         FunTable {
             funs: funs,
+            aliases: Vec::new(),
         }
     }
     
     /// Appends an entire vector of functions to the table.
-    pub fn append(&mut self, mut funs: Vec<Function>) {
-        self.funs
-            .append(&mut funs);
+    /// A function replaces any function in the table with the same name, so the latest definition
+    /// of a name is the one that's used.
+    pub fn append(&mut self, funs: Vec<Function>) {
+        for fun in funs {
+            match self.funs.iter().position(|f| f.name == fun.name) {
+                Some(index) => self.funs[index] = fun,
+                None => self.funs.push(fun),
+            }
+        }
     }
 
     pub fn merge(&mut self, mut other: FunTable) {
+        self.append(other.funs);
+        self.aliases
+            .append(&mut other.aliases);
+    }
+
+    /// Adds an alternate name for a function or builtin.
+    /// The function name should already be resolved, so that aliases never point at other aliases.
+    pub fn add_alias(&mut self, alias: &str, fun_name: &str) {
+        assert!(!self.has_alias(alias), "Alias already exists in function table");
+        self.aliases
+            .push((alias.to_string(), fun_name.to_string()));
+    }
+
+    /// Gets whether a name is an alias for a function.
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.aliases
+            .iter()
+            .any(|&(ref alias, _)| alias == name)
+    }
+
+    /// Gets the name of the function that a name is an alias for, or the name itself if it isn't an
+    /// alias.
+    pub fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases
+            .iter()
+            .find(|&&(ref alias, _)| alias == name)
+            .map(|&(_, ref fun_name)| fun_name.as_str())
+            .unwrap_or(name)
+    }
+
+    /// Gets the names of the functions and aliases in the table that start with a prefix.
+    pub fn names_with_prefix(&self, prefix: &str) -> Vec<&str> {
         self.funs
-            .append(&mut other.funs);
+            .iter()
+            .map(|f| f.name.as_str())
+            .chain(self.aliases.iter().map(|&(ref alias, _)| alias.as_str()))
+            .filter(|name| name.starts_with(prefix))
+            .collect()
+    }
+
+    /// Iterates over all functions in the table.
+    pub fn iter(&self) -> ::std::slice::Iter<'_, Function> {
+        self.funs
+            .iter()
     }
 
     /// Does a linear search for if a function exists in the table.
@@ -229,6 +348,22 @@ impl FunTable {
         }
     }
 
+    /// Describes every function in the table by its usage, followed by its docstring, and then
+    /// every alias as `alias -> function`.
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for fun in &self.funs {
+            description += &format!("{}\n", fun.usage());
+            for line in fun.docstring.lines() {
+                description += &format!("    {}\n", line.trim());
+            }
+        }
+        for &(ref alias, ref fun_name) in &self.aliases {
+            description += &format!("{} -> {}\n", alias, fun_name);
+        }
+        description
+    }
+
     /// Dumps debug information about all functions in the table.
     pub fn dump_debug(&self) {
         for fun in &self.funs {
@@ -237,6 +372,11 @@ impl FunTable {
             debug!("params: {:?}", fun.params);
             debug!("docstring: {}", fun.docstring);
         }
+        for &(ref alias, ref fun_name) in &self.aliases {
+            debug!("- ALIAS ------------------------------------------------------------------------");
+            debug!("name: {}", alias);
+            debug!("function: {}", fun_name);
+        }
         debug!("--------------------------------------------------------------------------------");
     }
 
@@ -252,20 +392,97 @@ impl FunTable {
 pub struct Function {
     pub name: String,
     pub params: Vec<Param>,
+    /// The type of the value that this function gives, if it was declared
+    pub return_type: Option<Type>,
     pub docstring: String,
     pub body: Vec<AST>,
     pub source_file: String,
 }
 
 impl Function {
-    /// Creates a new function, with a name, its parameters, its docstring, and the body.
-    pub fn new(name: String, params: Vec<Param>, docstring: String, body: Vec<AST>, source_file: &str) -> Function {
+    /// Creates a new function, with a name, its parameters, the type it gives (if declared), its
+    /// docstring, and the body.
+    pub fn new(name: String, params: Vec<Param>, return_type: Option<Type>, docstring: String, body: Vec<AST>,
+               source_file: &str) -> Function {
         Function {
             name: name,
             params: params,
+            return_type: return_type,
             docstring: docstring,
             body: body,
             source_file: source_file.to_string(),
         }
     }
+
+    /// Gets how this function is called, written like its parameter list, e.g. `(f a :int ? b)`.
+    /// The type it gives comes after, if it was declared, e.g. `(f a :int ? b) :string`.
+    pub fn usage(&self) -> String {
+        let mut usage = format!("({}", self.name);
+        let mut optional = false;
+        for param in &self.params {
+            if param.optional && !optional {
+                usage += " ?";
+                optional = true;
+            }
+            usage += &format!(" {}", param.name);
+            if let Type::Any = param.param_type {
+                continue;
+            }
+            usage += &format!(" {}", param.param_type);
+        }
+        usage += ")";
+        if let Some(ref return_type) = self.return_type {
+            usage += &format!(" {}", return_type);
+        }
+        usage
+    }
+
+    /// Gets the parameters this function expects, separated by commas, e.g. `(x :int, y? :string)`.
+    pub fn signature_string(&self) -> String {
+        let params = self.params
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>();
+        format!("({})", params.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+    use preprocessor::Preprocessor;
+
+    /// Preprocesses some source, giving the functions and types that it defines.
+    fn tables(source: &str) -> (FunTable, TypeTable) {
+        let mut ast = Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap();
+        let (mut fun_table, mut type_table) = (FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        Preprocessor::new("<test>", &mut ast, &mut fun_table, &mut type_table)
+            .preprocess()
+            .unwrap();
+        (fun_table, type_table)
+    }
+
+    #[test]
+    fn fun_table_describes_functions_and_aliases() {
+        let (fun_table, _) = tables("(&define f (a ? b) \"does f\nover lines\" (list a b)) (&define g () 1) (&alias f eff)");
+        assert_eq!(fun_table.describe(), "(f a ? b)\n    does f\n    over lines\n(g)\neff -> f\n");
+    }
+
+    #[test]
+    fn type_table_describes_types() {
+        let (_, type_table) = tables("(&type :int count)");
+        // after the builtin types
+        assert_eq!(type_table.describe(), ":int\n:string\n:listy\ncount = :int\n");
+    }
+
+    #[test]
+    fn signatures_list_params_with_their_types() {
+        let (fun_table, _) = tables("(&define f (x :int ? y :string z) (list x y z)) (&define g () 1)");
+        assert_eq!(fun_table.get_fun("f").unwrap().signature_string(), "(x :int, y? :string, z?)");
+        assert_eq!(fun_table.get_fun("g").unwrap().signature_string(), "()");
+    }
 }
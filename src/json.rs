@@ -0,0 +1,361 @@
+use ast::AST;
+use lexer::Pos;
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A JSON value, used for talking to external tools.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// An object; the keys are kept in the order they were added.
+    Object(Vec<(String, Json)>),
+}
+
+impl fmt::Display for Json {
+    /// Writes the compact JSON representation of this value.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Json::Null => write!(f, "null"),
+            &Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no representation for infinity or NaN
+            &Json::Number(n) => if n.is_finite() {
+                write!(f, "{}", n)
+            }
+            else {
+                write!(f, "null")
+            },
+            &Json::String(ref s) => write_string(f, s),
+            &Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            &Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (index, &(ref key, ref value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/// Writes a quoted and escaped JSON string.
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Reads a JSON value from text. The whole text must be the one value, aside from whitespace
+/// around it.
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut reader = JsonReader {
+        chars: text.chars().peekable(),
+        offset: 0,
+    };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    match reader.chars.peek() {
+        Some(&c) => Err(reader.error(&format!("unexpected `{}' after the end of the value", c))),
+        None => Ok(value),
+    }
+}
+
+/// Reads JSON values from characters, keeping track of how far in it is for error messages.
+struct JsonReader<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Number of characters that have been read
+    offset: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("invalid JSON at character {}: {}", self.offset, message)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c.is_some() {
+            self.offset += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c != ' ' && c != '\t' && c != '\n' && c != '\r' {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    /// Reads the rest of a keyword whose first character has been peeked, e.g. `true`.
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        for expected in keyword.chars() {
+            if self.next() != Some(expected) {
+                return Err(self.error(&format!("expected `{}'", keyword)));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(self.error(&format!("unexpected `{}'", c))),
+            None => Err(self.error("expected a value, but reached the end of the text")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '0' ..= '9' | '-' | '+' | '.' | 'e' | 'E' => text.push(c),
+                _ => break,
+            }
+            self.next();
+        }
+        text.parse::<f64>()
+            .map(Json::Number)
+            .map_err(|_| self.error(&format!("invalid number `{}'", text)))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        assert_eq!(self.next(), Some('"'));
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.unicode_escape()?),
+                    Some(c) => return Err(self.error(&format!("unknown escape sequence `\\{}'", c))),
+                    None => return Err(self.error("reached the end of the text inside of a string")),
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("reached the end of the text inside of a string")),
+            }
+        }
+    }
+
+    /// Reads the hex digits of a `\u` escape, and the low half of a surrogate pair if it's the
+    /// high half of one.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex_code()?;
+        let code = if high >= 0xd800 && high < 0xdc00 {
+            if self.next() != Some('\\') || self.next() != Some('u') {
+                return Err(self.error("expected the second half of a surrogate pair"));
+            }
+            let low = self.hex_code()?;
+            if low < 0xdc00 || low >= 0xe000 {
+                return Err(self.error("expected the second half of a surrogate pair"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        }
+        else {
+            high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| self.error(&format!("invalid code point {:x}", code)))
+    }
+
+    fn hex_code(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0 .. 4 {
+            match self.next().and_then(|c| c.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(self.error("expected four hex digits after `\\u'")),
+            }
+        }
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        assert_eq!(self.next(), Some('['));
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => { },
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,' or `]' after an array item")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        assert_eq!(self.next(), Some('{'));
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek() != Some(&'"') {
+                return Err(self.error("expected a string as an object key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.next() != Some(':') {
+                return Err(self.error("expected `:' after an object key"));
+            }
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => { },
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected `,' or `}' after an object field")),
+            }
+        }
+    }
+}
+
+/// Converts a position to a JSON object with its line and column numbers.
+pub fn pos_json(pos: Pos) -> Json {
+    Json::Object(vec![
+        (String::from("line"), Json::Number(pos.line_number() as f64)),
+        (String::from("col"), Json::Number(pos.col_number() as f64)),
+    ])
+}
+
+/// Converts a parsed item to a JSON object, with its kind, where it starts and ends, and either its
+/// value or the items inside of it.
+pub fn ast_json(ast: &AST) -> Json {
+    let (kind, value) = match ast {
+        &AST::Expr(_, ref exprs) => ("expr", Json::Array(exprs.iter().map(ast_json).collect())),
+        &AST::StringLit(_, ref s) => ("string", Json::String(s.clone())),
+        &AST::Identifier(_, ref s) => ("identifier", Json::String(s.clone())),
+        &AST::Number(_, n) => ("number", Json::Number(n)),
+        &AST::Integer(_, n) => ("integer", Json::Number(n as f64)),
+        &AST::Comment(_, ref s) => ("comment", Json::String(s.clone())),
+    };
+    let range = ast.range();
+    Json::Object(vec![
+        (String::from("kind"), Json::String(kind.to_string())),
+        (String::from("start"), pos_json(range.start)),
+        (String::from("end"), pos_json(range.end)),
+        (String::from(if ast.is_expr() { "items" } else { "value" }), value),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+
+    #[test]
+    fn values_are_compact_json() {
+        let json = Json::Object(vec![
+            (String::from("a"), Json::Array(vec![Json::Number(1.0), Json::Number(2.5), Json::Null])),
+            (String::from("b"), Json::Bool(true)),
+            (String::from("c"), Json::Array(vec![])),
+        ]);
+        assert_eq!(json.to_string(), "{\"a\":[1,2.5,null],\"b\":true,\"c\":[]}");
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(Json::String(String::from("a\"b\\c\nd\u{1}")).to_string(), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        assert_eq!(Json::Number(::std::f64::NAN).to_string(), "null");
+        assert_eq!(Json::Number(::std::f64::INFINITY).to_string(), "null");
+    }
+
+    #[test]
+    fn ast_has_kinds_positions_and_values() {
+        let ast = Parser::new(Lexer::new("(f 1) ; c"))
+            .parse()
+            .unwrap();
+        let pos = |line, col| format!("{{\"line\":{},\"col\":{}}}", line, col);
+        assert_eq!(ast_json(&ast[0]).to_string(),
+                   format!("{{\"kind\":\"expr\",\"start\":{},\"end\":{},\"items\":[\
+                            {{\"kind\":\"identifier\",\"start\":{},\"end\":{},\"value\":\"f\"}},\
+                            {{\"kind\":\"integer\",\"start\":{},\"end\":{},\"value\":1}}]}}",
+                           pos(1, 1), pos(1, 5), pos(1, 1), pos(1, 2), pos(1, 3), pos(1, 4)));
+        assert_eq!(ast_json(&ast[1]).to_string(),
+                   format!("{{\"kind\":\"comment\",\"start\":{},\"end\":{},\"value\":\" c\"}}", pos(1, 7), pos(1, 9)));
+    }
+
+    #[test]
+    fn parsing_reads_every_kind_of_value() {
+        assert_eq!(parse(" {\"a\": [1, -2.5e1, null], \"b\" : true, \"c\": false, \"d\": {}} "),
+                   Ok(Json::Object(vec![
+                       (String::from("a"), Json::Array(vec![Json::Number(1.0), Json::Number(-25.0), Json::Null])),
+                       (String::from("b"), Json::Bool(true)),
+                       (String::from("c"), Json::Bool(false)),
+                       (String::from("d"), Json::Object(vec![])),
+                   ])));
+        assert_eq!(parse("[]"), Ok(Json::Array(vec![])));
+    }
+
+    #[test]
+    fn parsing_reads_escapes() {
+        assert_eq!(parse("\"a\\\"\\\\\\/\\n\\t\\u00e9\\ud83d\\ude00\""), Ok(Json::String(String::from("a\"\\/\n\t\u{e9}\u{1f600}"))));
+        assert_eq!(parse("\"\\ud83d\""), Err(String::from("invalid JSON at character 8: expected the second half of a surrogate pair")));
+        assert_eq!(parse("\"\\q\""), Err(String::from("invalid JSON at character 3: unknown escape sequence `\\q'")));
+    }
+
+    #[test]
+    fn parsing_reports_where_it_failed() {
+        assert_eq!(parse("[1 2]"), Err(String::from("invalid JSON at character 4: expected `,' or `]' after an array item")));
+        assert_eq!(parse("{1: 2}"), Err(String::from("invalid JSON at character 1: expected a string as an object key")));
+        assert_eq!(parse("tru"), Err(String::from("invalid JSON at character 3: expected `true'")));
+        assert_eq!(parse("1 1"), Err(String::from("invalid JSON at character 2: unexpected `1' after the end of the value")));
+        assert_eq!(parse(""), Err(String::from("invalid JSON at character 0: expected a value, but reached the end of the text")));
+        assert_eq!(parse("\"abc"), Err(String::from("invalid JSON at character 4: reached the end of the text inside of a string")));
+    }
+}
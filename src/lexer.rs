@@ -1,6 +1,10 @@
-use std::str::Chars;
+use std::str::{self, Chars};
+use std::io::{self, BufRead, Read};
 use std::fmt;
 
+/// The default number of columns that a tab character takes up.
+pub const DEFAULT_TAB_WIDTH: i64 = 1;
+
 #[derive(Debug)]
 pub enum Token {
     None,
@@ -10,6 +14,7 @@ pub enum Token {
     Identifier(Range, String),
     StringLit(Range, String),
     Number(Range, f64),
+    Integer(Range, i64),
     Comment(Range, String),
     Unknown(Range, char),
     Error(Range, String),
@@ -23,9 +28,10 @@ impl fmt::Display for Token {
             &Token::Lparen(_) => String::from("left paren"),
             &Token::Rparen(_) => String::from("right paren"),
             &Token::Identifier(_, ref s) => format!("{}", s),
-            &Token::StringLit(_, _) => String::from("string literal"),
-            &Token::Number(_, _) => String::from("number"),
-            &Token::Comment(_, _) => String::from("comment"),
+            &Token::StringLit(_, ref s) => format!("string literal {:?}", truncate_lexeme(s)),
+            &Token::Number(_, n) => format!("number {}", n),
+            &Token::Integer(_, n) => format!("integer {}", n),
+            &Token::Comment(_, ref c) => format!("comment `;{}'", truncate_lexeme(c)),
             &Token::Unknown(_, ref c) => format!("unknown character `{}'", c),
             &Token::Error(_, ref e) => format!("syntax error: {}", e),
         };
@@ -64,6 +70,7 @@ impl Token {
             &Token::Identifier(r, _) => r,
             &Token::StringLit(r, _) => r,
             &Token::Number(r, _) => r,
+            &Token::Integer(r, _) => r,
             &Token::Comment(r, _) => r,
             &Token::Unknown(r, _) => r,
             &Token::Error(r, _) => r,
@@ -96,6 +103,16 @@ impl Pos {
         Pos::new(-1, 0, -1)
     }
 
+    /// Gets the line number of this position, starting at 1.
+    pub fn line_number(&self) -> i64 {
+        self.line_index + 1
+    }
+
+    /// Gets the column number of this position, starting at 1.
+    pub fn col_number(&self) -> i64 {
+        self.col_index + 1
+    }
+
     /// Advances the position by one character.
     /// Increments the src_index by 1 and col_index by 1.
     pub fn advance(&mut self) {
@@ -103,6 +120,15 @@ impl Pos {
         self.col_index += 1;
     }
 
+    /// Advances the position past a tab character, to the next multiple of `tab_width` columns.
+    /// The position should already be at the tab itself.
+    pub fn tab(&mut self, tab_width: i64) {
+        if tab_width > 1 {
+            let next_stop = (self.col_index / tab_width + 1) * tab_width;
+            self.col_index = next_stop - 1;
+        }
+    }
+
     /// Advances the position by a line.
     /// Sets the col_index to -1
     /// Increments the line index by 1
@@ -114,7 +140,7 @@ impl Pos {
 
 impl fmt::Display for Pos {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}", self.line_index + 1, self.col_index + 1)
+        write!(f, "{}:{}", self.line_number(), self.col_number())
     }
 }
 
@@ -137,9 +163,20 @@ impl Range {
         self.end.line();
     }
 
+    pub fn end_tab(&mut self, tab_width: i64) {
+        self.end.tab(tab_width);
+    }
+
     pub fn catchup(&mut self) {
         self.start = self.end;
     }
+
+    /// Gets the smallest range that covers both this range and another one.
+    pub fn merge(&self, other: &Range) -> Range {
+        let start = if other.start.src_index < self.start.src_index { other.start } else { self.start };
+        let end = if other.end.src_index > self.end.src_index { other.end } else { self.end };
+        Range::new(start, end)
+    }
 }
 
 impl fmt::Display for Range {
@@ -153,52 +190,188 @@ impl fmt::Display for Range {
     }
 }
 
+/// Where the lexer gets its characters from.
+enum CharSource<'a> {
+    /// Source text that is already in memory.
+    Text(Chars<'a>),
+    /// A reader that is decoded as UTF-8 as the lexer goes, so the whole source never has to be in
+    /// memory at once.
+    Reader(ReaderChars<'a>),
+}
+
+impl<'a> CharSource<'a> {
+    fn next(&mut self) -> Result<Option<char>, String> {
+        match self {
+            &mut CharSource::Text(ref mut chars) => Ok(chars.next()),
+            &mut CharSource::Reader(ref mut chars) => chars.next_char(),
+        }
+    }
+}
+
+/// Decodes UTF-8 characters from a reader one at a time.
+struct ReaderChars<'a> {
+    bytes: io::Bytes<Box<dyn BufRead + 'a>>,
+    /// Byte offset of the next character
+    offset: usize,
+}
+
+impl<'a> ReaderChars<'a> {
+    fn next_char(&mut self) -> Result<Option<char>, String> {
+        let start = self.offset;
+        let first = match self.next_byte()? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let width = match first {
+            0x00 ..= 0x7f => 1,
+            0xc0 ..= 0xdf => 2,
+            0xe0 ..= 0xef => 3,
+            0xf0 ..= 0xf7 => 4,
+            _ => return Err(invalid_utf8(start)),
+        };
+        let mut buf = [first, 0, 0, 0];
+        for b in buf.iter_mut().take(width).skip(1) {
+            *b = self.next_byte()?
+                .ok_or_else(|| invalid_utf8(start))?;
+        }
+        match str::from_utf8(&buf[.. width]) {
+            Ok(s) => Ok(s.chars().next()),
+            Err(_) => Err(invalid_utf8(start)),
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, String> {
+        match self.bytes.next() {
+            Some(Ok(b)) => {
+                self.offset += 1;
+                Ok(Some(b))
+            },
+            Some(Err(e)) => Err(format!("could not read source: {}", e)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The most characters of a string literal or comment that are shown when describing its token.
+const MAX_LEXEME_CHARS: usize = 20;
+
+/// Shortens a lexeme to `MAX_LEXEME_CHARS` characters so it fits in an error message, marking
+/// where it was cut off with `...`.
+fn truncate_lexeme(lexeme: &str) -> String {
+    if lexeme.chars().count() > MAX_LEXEME_CHARS {
+        let shortened = lexeme.chars()
+            .take(MAX_LEXEME_CHARS)
+            .collect::<String>();
+        shortened + "..."
+    }
+    else {
+        lexeme.to_string()
+    }
+}
+
+fn invalid_utf8(offset: usize) -> String {
+    format!("source is not valid UTF-8 text (invalid byte sequence at offset {})", offset)
+}
+
+/// Gets whether a character can start an identifier. This is every printable ASCII character
+/// except for whitespace, parens, double quotes (which start strings), digits (which start numbers)
+/// and semicolons (which start comments), so names like `<=>`, `%%` and `a.b` are all identifiers.
+fn is_identifier_pos(c: char) -> bool {
+    is_identifier_char(c) && !c.is_ascii_digit()
+}
+
+/// Gets whether a character can be part of an identifier after its first character; this is the
+/// same as the characters that can start one, plus digits.
+fn is_identifier_char(c: char) -> bool {
+    match c {
+        '!' | '#' ..= '\'' | '*' ..= ':' | '<' ..= '~' => true,
+        _ => false,
+    }
+}
+
 pub struct Lexer<'a> {
     pub range: Range,
-    //source_text: &'a str,
-    source_iter: Chars<'a>,
+    source: CharSource<'a>,
     curr: Option<char>,
     peek: Option<char>,
+    /// An error from reading the source, which is given out as an `Error` token in place of `Eof`
+    source_error: Option<String>,
+    /// Whether the EOF token (or a source error in its place) has been given out by the iterator
+    eof: bool,
+    /// Number of columns that a tab character takes up
+    tab_width: i64,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source_text: &'a str) -> Lexer{
+        Lexer::from_source(CharSource::Text(source_text.chars()))
+    }
+
+    /// Creates a lexer that reads its source incrementally, rather than needing all of it up front.
+    /// It gives the same tokens and positions as lexing the same text with `Lexer::new`. If the
+    /// reader fails, or gives invalid UTF-8, the last token is an `Error` instead of `Eof`.
+    pub fn from_reader<R: BufRead + 'a>(reader: R) -> Lexer<'a> {
+        let reader: Box<dyn BufRead + 'a> = Box::new(reader);
+        Lexer::from_source(CharSource::Reader(ReaderChars {
+            bytes: reader.bytes(),
+            offset: 0,
+        }))
+    }
+
+    fn from_source(source: CharSource<'a>) -> Lexer<'a> {
         let mut lexer = Lexer {
             range: Range::new(Pos::start(), Pos::start()),
-            //source_text: source_text,
-            source_iter: source_text.chars(),
+            source: source,
             curr: None,
             peek: None,
+            source_error: None,
+            eof: false,
+            tab_width: DEFAULT_TAB_WIDTH,
         };
-        /*
-        lexer.range
-            .start
-            .advance();
-        */
-        lexer.peek = lexer.source_iter.next();
+        lexer.peek = lexer.next_source_char();
         lexer
     }
 
+    /// Sets the number of columns that a tab character takes up in reported positions, so they can
+    /// match what an editor shows. Tabs count as one column by default.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width as i64;
+    }
+
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace();
-        self.next();
+        self.next_char();
         if let Some(c) = self.curr {
             let tok = match c {
-                ';' => Token::Comment(self.range, self.eat_comment()),
+                ';' => {
+                    // the comment's range starts at the semicolon, and ends at the end of the line
+                    let start = self.range.end;
+                    let comment = self.eat_comment();
+                    Token::Comment(Range::new(start, self.range.end), comment)
+                },
                 '(' => { 
                     self.range.catchup();
                     Token::Lparen(self.range)
                 },
                 ')' => Token::Rparen(self.range),
-                    /* this range includes all printable characters minus lparen, rparen, dquote, and decimals */
-                '*' ... '/' | ':' ... '~' | '!' | '#' ... '\'' =>
+                c if is_identifier_pos(c) =>
                     Token::Identifier(self.range, self.eat_identifier()),
-                '"' => match self.eat_string() {
+                '"' => {
+                    // errors start at the opening quote, so an unterminated string points at where
+                    // it began rather than at the end of the file
+                    let start = self.range.end;
+                    match self.eat_string() {
                         Ok(s) => Token::StringLit(self.range, s),
-                        Err(e) => Token::Error(self.range, e),
+                        Err(e) => Token::Error(Range::new(start, self.range.end), e),
+                    }
+                },
+                '0' ..= '9' => match self.eat_number() {
+                    Ok((num_str, false)) => match num_str.parse() {
+                        Ok(i) => Token::Integer(self.range, i),
+                        // too big to be an integer; fall back to a float
+                        Err(_) => Token::Number(self.range, num_str.parse().unwrap()),
                     },
-                '0' ... '9' => match self.eat_number() {
-                    Ok(n) => Token::Number(self.range, n),
+                    Ok((num_str, true)) => Token::Number(self.range, num_str.parse().unwrap()),
                     Err(e) => Token::Error(self.range, e),
                 },
                 u => Token::Unknown(self.range, u),
@@ -208,18 +381,27 @@ impl<'a> Lexer<'a> {
         }
         else {
             self.range.catchup();
-            Token::Eof(self.range)
+            match self.source_error.take() {
+                Some(e) => {
+                    // the error stands in for the EOF token, so nothing comes after it
+                    self.eof = true;
+                    Token::Error(self.range, e)
+                },
+                None => Token::Eof(self.range),
+            }
         }
     }
 
     fn eat_comment(&mut self) -> String {
         let mut comment_food = String::new();
+        // the newline is left for the whitespace skipper
         loop {
-            self.next();
-            match self.curr {
-                Some('\n') => break,
-                None => break,
-                Some(c) => comment_food.push(c),
+            match self.peek {
+                Some('\n') | None => break,
+                Some(c) => {
+                    self.next_char();
+                    comment_food.push(c);
+                },
             }
         }
         comment_food
@@ -232,11 +414,12 @@ impl<'a> Lexer<'a> {
             identifier.push(self.curr
                                 .expect("self.curr was EOF when it was detected not to be"));
             if let Some(p) = self.peek {
-                match p {
-                    '*' ... '~' | '!' | '#' ... '\'' => self.next(),
-                    _ => break,
+                if is_identifier_char(p) {
+                    self.next_char();
+                }
+                else {
+                    break;
                 }
-
             }
             else {
                 // EOF reached
@@ -251,15 +434,17 @@ impl<'a> Lexer<'a> {
         let mut string_lit = String::new();
         //let mut escape = false;
         loop {
-            self.next();
+            self.next_char();
             match self.curr {
                 Some('"') => break,
                 Some('\\') => {
-                    self.next();
+                    self.next_char();
                     match self.curr {
                         Some('r') => string_lit.push('\r'),
                         Some('n') => string_lit.push('\n'),
                         Some('t') => string_lit.push('\t'),
+                        Some('"') => string_lit.push('"'),
+                        Some('\\') => string_lit.push('\\'),
                         Some(c) => return Err(format!("unknown escape sequence: \\{}", c)),
                         None => return Err(String::from("reached EOF before end of string")),
                     }
@@ -271,7 +456,8 @@ impl<'a> Lexer<'a> {
         Ok(string_lit)
     }
 
-    fn eat_number(&mut self) -> Result<f64, String> {
+    /// Eats a number, returning its text and whether it had a decimal point.
+    fn eat_number(&mut self) -> Result<(String, bool), String> {
         trace!("eating number");
         let mut num_str = String::new();
         let mut decimal = false;
@@ -280,12 +466,11 @@ impl<'a> Lexer<'a> {
                              .expect("self.curr was EOF when it was detected not to be"));
             if let Some(c) = self.curr {
                 match c {
-                    '0' ... '9' => if let Some(p) = self.peek {
-                        match p {
-                            '0' ... '9' | '.' => { },
-                            ' ' | '\t' | '\r' | '\n' | '(' | ')' => break,
-                            u => return Err(format!("unexpected character while parsing number: {}", u)),
-                        }
+                    '0' ..= '9' => match self.peek {
+                        Some('0' ..= '9') | Some('.') => { },
+                        // the end of the input ends the number too
+                        None | Some(' ') | Some('\t') | Some('\r') | Some('\n') | Some('(') | Some(')') | Some(';') => break,
+                        Some(u) => return Err(format!("unexpected character while parsing number: {}", u)),
                     },
                     '.' => {
                         if decimal {
@@ -293,7 +478,7 @@ impl<'a> Lexer<'a> {
                         }
                         else if let Some(p) = self.peek {
                             match p {
-                                '0' ... '9' => decimal = true,
+                                '0' ..= '9' => decimal = true,
                                 u => return Err(format!("unexpected character while parsing number: {}", u)),
                             }
                         }
@@ -302,7 +487,7 @@ impl<'a> Lexer<'a> {
                         }
                     },
                     // suffix chars
-                    //'a' ... 'z' | 'A' ... 'Z' | '_' => break,
+                    //'a' ..= 'z' | 'A' ..= 'Z' | '_' => break,
                     _ => break,
                 }
             }
@@ -310,13 +495,13 @@ impl<'a> Lexer<'a> {
                 // EOF
                 break;
             }
-            self.next();
+            self.next_char();
         }
 
         /*
         if let Some(c) = self.curr {
             match c {
-                'a' ... 'z' | 'A' ... 'Z' | '_' => {
+                'a' ..= 'z' | 'A' ..= 'Z' | '_' => {
                     // suffix
                 },
                 ' ' | '\n' | '\r' | '\t' => { }, // no-op
@@ -324,14 +509,14 @@ impl<'a> Lexer<'a> {
             }
         }
         */
-        Ok(num_str.parse().unwrap())
+        Ok((num_str, decimal))
     }
 
     pub fn skip_whitespace(&mut self) {
         loop {
             if let Some(c) = self.peek {
                 match c {
-                    ' ' | '\t' | '\r' | '\n' => self.next(),
+                    ' ' | '\t' | '\r' | '\n' => self.next_char(),
                     _ => { 
                         break;
                     },
@@ -344,13 +529,196 @@ impl<'a> Lexer<'a> {
         self.range.catchup();
     }
 
-    fn next(&mut self) {
+    fn next_char(&mut self) {
         self.range.end_advance();
         self.curr = self.peek;
-        self.peek = self.source_iter.next();
+        self.peek = self.next_source_char();
         match self.curr {
             Some('\n') => self.range.end_line(),
+            Some('\t') => self.range.end_tab(self.tab_width),
             _ => { }
         }
     }
+
+    /// Gets the next character from the source. A read error ends the source early, and is kept to
+    /// be reported when the lexer reaches the end.
+    fn next_source_char(&mut self) -> Option<char> {
+        if self.source_error.is_some() {
+            return None;
+        }
+        match self.source.next() {
+            Ok(c) => c,
+            Err(e) => {
+                self.source_error = Some(e);
+                None
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token;
+
+    /// Gets the next token. The `Eof` token is given exactly once, after which this yields `None`.
+    fn next(&mut self) -> Option<Token> {
+        if self.eof {
+            None
+        }
+        else {
+            let tok = self.next_token();
+            if let Token::Eof(_) = tok {
+                self.eof = true;
+            }
+            Some(tok)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lexes some source, describing each of its tokens.
+    fn lex(source: &str) -> Vec<String> {
+        Lexer::new(source)
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn lexer_collects_tokens() {
+        assert_eq!(lex("(+ 1 2.5 \"a\") ; hi\n"),
+                   vec!["left paren", "+", "integer 1", "number 2.5", "string literal \"a\"", "right paren",
+                        "comment `; hi'", "EOF"]);
+    }
+
+    #[test]
+    fn long_lexemes_are_cut_short() {
+        assert_eq!(lex("\"abcdefghijklmnopqrstuvwxyz\" ;0123456789012345678901\n\"abcdefghijklmnopqrst\""),
+                   vec!["string literal \"abcdefghijklmnopqrst...\"", "comment `;01234567890123456789...'",
+                        "string literal \"abcdefghijklmnopqrst\"", "EOF"]);
+    }
+
+    #[test]
+    fn lexer_gives_eof_once() {
+        let mut lexer = Lexer::new("x ");
+        assert_eq!(lexer.next().unwrap().to_string(), "x");
+        match lexer.next() {
+            Some(Token::Eof(_)) => { },
+            t => panic!("expected EOF, but got {:?}", t),
+        }
+        assert!(lexer.next().is_none());
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn number_can_end_input() {
+        assert_eq!(lex("42"), vec!["integer 42", "EOF"]);
+        assert_eq!(lex("(+ 1 2) 4.5"), vec!["left paren", "+", "integer 1", "integer 2", "right paren", "number 4.5", "EOF"]);
+    }
+
+    #[test]
+    fn identifiers_take_printable_characters() {
+        assert_eq!(lex("<=> %% a.b x1 -> ~!"), vec!["<=>", "%%", "a.b", "x1", "->", "~!", "EOF"]);
+        // digits can't start one, but can continue one
+        assert_eq!(lex("1a"), vec!["syntax error: unexpected character while parsing number: a", "a", "EOF"]);
+    }
+
+    #[test]
+    fn comments_end_identifiers_and_numbers() {
+        assert_eq!(lex("abc;c\n12;c\n1.5;c"),
+                   vec!["abc", "comment `;c'", "integer 12", "comment `;c'", "number 1.5", "comment `;c'", "EOF"]);
+    }
+
+    /// Makes a range on the first line, between two columns (counting from 0).
+    fn cols(start: i64, end: i64) -> Range {
+        Range::new(Pos::new(start, 0, start), Pos::new(end, 0, end))
+    }
+
+    #[test]
+    fn merging_adjacent_ranges() {
+        assert_eq!(cols(0, 2).merge(&cols(3, 5)).to_string(), "1:1-1:6");
+        // either way around
+        assert_eq!(cols(3, 5).merge(&cols(0, 2)).to_string(), "1:1-1:6");
+    }
+
+    #[test]
+    fn merging_nested_ranges() {
+        assert_eq!(cols(0, 9).merge(&cols(3, 5)).to_string(), "1:1-1:10");
+        assert_eq!(cols(3, 5).merge(&cols(0, 9)).to_string(), "1:1-1:10");
+        assert_eq!(cols(3, 5).merge(&cols(3, 5)).to_string(), "1:4-1:6");
+    }
+
+    #[test]
+    fn merging_ranges_across_lines() {
+        let first = Range::new(Pos::new(0, 0, 0), Pos::new(4, 0, 4));
+        let last = Range::new(Pos::new(8, 1, 2), Pos::new(9, 1, 3));
+        assert_eq!(first.merge(&last).to_string(), "1:1-2:4");
+    }
+
+    /// Gets the position of the last identifier in some source, which should be one character
+    /// long, with a given tab width.
+    fn identifier_pos(source: &str, tab_width: usize) -> String {
+        let mut lexer = Lexer::new(source);
+        lexer.set_tab_width(tab_width);
+        lexer.filter(|t| match t { &Token::Identifier(_, _) => true, _ => false })
+            .last()
+            .unwrap()
+            .range()
+            .end
+            .to_string()
+    }
+
+    #[test]
+    fn tabs_are_one_column_by_default() {
+        assert_eq!(identifier_pos("\tx", DEFAULT_TAB_WIDTH as usize), "1:2");
+    }
+
+    #[test]
+    fn tabs_go_to_the_next_tab_stop() {
+        assert_eq!(identifier_pos("\tx", 4), "1:5");
+        assert_eq!(identifier_pos("\t\tx", 4), "1:9");
+        assert_eq!(identifier_pos("ab\tx", 4), "1:5");
+        assert_eq!(identifier_pos("abcd\tx", 4), "1:9");
+        assert_eq!(identifier_pos("ab\tx", 8), "1:9");
+        assert_eq!(identifier_pos("(list 1\n\tx)", 4), "2:5");
+    }
+
+    /// Describes each token in some source, along with where it is.
+    fn located(lexer: Lexer) -> Vec<String> {
+        lexer.map(|t| format!("{} at {}", t, t.range()))
+            .collect()
+    }
+
+    #[test]
+    fn reader_lexes_like_text() {
+        let source = "; héllo\n(&define f (x)\n\t\"naïve ✓\" (+ x 1.5))\n(f 2)";
+        assert_eq!(located(Lexer::from_reader(source.as_bytes())), located(Lexer::new(source)));
+    }
+
+    #[test]
+    fn reader_reports_invalid_utf8_at_end() {
+        let tokens = lex_reader(b"(list 1)\xff(list 2)");
+        // the error takes the place of EOF, and nothing after the invalid bytes is lexed
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens.last().unwrap(), "syntax error: source is not valid UTF-8 text (invalid byte sequence at offset 8)");
+        // a character that's cut off is invalid too
+        let tokens = lex_reader(b"(list \"\xc3");
+        assert!(tokens.last().unwrap().contains("invalid byte sequence at offset 7"), "{:?}", tokens);
+    }
+
+    /// Lexes some source from a reader, describing each of its tokens.
+    fn lex_reader(source: &[u8]) -> Vec<String> {
+        Lexer::from_reader(source)
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn string_errors_start_at_the_opening_quote() {
+        match Lexer::new("(x\n  \"abc\ndef").nth(2) {
+            Some(Token::Error(r, _)) => assert_eq!(r.to_string(), "2:3-3:4"),
+            t => panic!("expected an error, but got {:?}", t),
+        }
+    }
 }
@@ -22,16 +22,162 @@ mod preprocessor;
 mod util;
 mod vm;
 mod bytecode;
+mod repl;
+mod format;
+mod optimizer;
+mod reachability;
+mod stats;
+mod bench;
 mod errors {
+    use lexer::Range;
+
     // error_chain setup
-    error_chain! { }
+    error_chain! {
+        errors {
+            /// The program asked to exit with the given status.
+            Exit(status: i32) {
+                description("program exited")
+                display("program exited with status {}", status)
+            }
+            /// An error with an optional position in the source that caused it.
+            Diagnostic(range: Option<Range>, message: String) {
+                description("error in source")
+                display("{}", match range {
+                    &Some(ref r) if message.is_empty() => r.to_string(),
+                    &Some(ref r) => format!("{}: {}", r, message),
+                    &None => message.clone(),
+                })
+            }
+            /// Something that was found in an included file, like a warning about its code.
+            Included(path: String) {
+                description("in included file")
+                display("{}", path)
+            }
+        }
+    }
+
+    /// Makes an error kind for something at the given range in the source.
+    /// An empty message makes an error that just points at the range.
+    pub fn diagnostic<T: Into<String>>(range: &Range, message: T) -> ErrorKind {
+        ErrorKind::Diagnostic(Some(*range), message.into())
+    }
+
+    impl Error {
+        /// Gets this error and the errors that caused it, for as long as they are also `Error`s.
+        fn links(&self) -> Vec<&Error> {
+            let mut links = vec![self];
+            let mut next = self.1.next_error.as_ref();
+            while let Some(err) = next.and_then(|e| e.downcast_ref::<Error>()) {
+                links.push(err);
+                next = err.1.next_error.as_ref();
+            }
+            links
+        }
+
+        /// Gets the most specific source range in this error's chain, if there is one.
+        pub fn range(&self) -> Option<Range> {
+            self.links()
+                .into_iter()
+                .rev()
+                .filter_map(|e| match e.kind() {
+                    &ErrorKind::Diagnostic(range, _) => range,
+                    _ => None,
+                })
+                .next()
+        }
+
+        /// Gets the innermost included file that this error was found in, if it was in one.
+        pub fn included_file(&self) -> Option<&str> {
+            self.links()
+                .into_iter()
+                .rev()
+                .filter_map(|e| match e.kind() {
+                    &ErrorKind::Included(ref path) => Some(path.as_str()),
+                    _ => None,
+                })
+                .next()
+        }
+
+        /// Gets the messages of this error and everything that caused it, joined into one line.
+        pub fn full_message(&self) -> String {
+            self.iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": ")
+        }
+
+        /// Gets the message of the error at the very bottom of this error's chain, without any
+        /// position information.
+        pub fn root_message(&self) -> String {
+            let last = *self.links()
+                .last()
+                .unwrap();
+            match (last.kind(), &last.1.next_error) {
+                (&ErrorKind::Diagnostic(_, ref message), &None) => message.clone(),
+                _ => self.iter()
+                    .last()
+                    .unwrap()
+                    .to_string(),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use lexer::Pos;
+
+        #[test]
+        fn diagnostic_keeps_range() {
+            let range = Range::new(Pos::new(4, 1, 2), Pos::new(7, 1, 5));
+            let err: Error = diagnostic(&range, "bad thing").into();
+            assert_eq!(err.range(), Some(range));
+            assert_eq!(err.root_message(), "bad thing");
+            assert_eq!(err.to_string(), "2:3-2:6: bad thing");
+        }
+
+        #[test]
+        fn innermost_range_wins() {
+            let outer = Range::new(Pos::new(0, 0, 0), Pos::new(9, 0, 9));
+            let inner = Range::new(Pos::new(3, 0, 3), Pos::new(5, 0, 5));
+            let result: Result<()> = Err(Error::from("unknown thing"))
+                .chain_err(|| diagnostic(&inner, ""))
+                .chain_err(|| diagnostic(&outer, ""))
+                .chain_err(|| "while compiling");
+            let err = result.unwrap_err();
+            assert_eq!(err.range(), Some(inner));
+            assert_eq!(err.root_message(), "unknown thing");
+        }
+
+        #[test]
+        fn plain_errors_have_no_range() {
+            let err = Error::from("no position");
+            assert_eq!(err.range(), None);
+            assert_eq!(err.root_message(), "no position");
+        }
+
+        #[test]
+        fn included_errors_know_their_file() {
+            let range = Range::new(Pos::new(4, 1, 2), Pos::new(7, 1, 5));
+            let result: Result<()> = Err(diagnostic(&range, "bad thing").into());
+            let err = result.chain_err(|| ErrorKind::Included("lib.rasp".into()))
+                .unwrap_err();
+            assert_eq!(err.included_file(), Some("lib.rasp"));
+            assert_eq!(err.range(), Some(range));
+            assert_eq!(err.full_message(), "lib.rasp: 2:3-2:6: bad thing");
+            assert_eq!(Error::from("no file").included_file(), None);
+        }
+    }
 }
 mod builtins;
+mod json;
 
 use lexer::Lexer;
 use parser::Parser;
 use preprocessor::Preprocessor;
 use internal::{FunTable,TypeTable};
+use json::{Json, pos_json};
+use errors::ResultExt;
 
 use env_logger::LogBuilder;
 use log::{LogRecord, LogLevelFilter, LogLevel};
@@ -39,21 +185,80 @@ use argparse::{ArgumentParser, Store, StoreTrue};
 use ansi_term::{Style, Colour};
 
 use std::env;
+use std::io::{self, BufReader, Write};
+use std::fs::File;
 use std::process;
+use std::thread;
 use std::fmt::Display;
 
 struct Config {
     file: String,       // file to compile
+    eval: String,       // source code to run instead of a file
     compile_only: bool, // compile only; don't run
     run_only: bool,     // run only; don't compile
+    max_steps: usize,   // maximum number of instructions to execute; 0 for no limit
+    max_memory: usize,  // maximum total size of values in memory; 0 for no limit
+    max_include_depth: usize, // maximum number of nested includes
+    tab_width: usize,   // number of columns that a tab takes up in reported positions
+    check: bool,        // check for errors; don't run
+    format: bool,       // print the formatted source; don't compile or run
+    ast_json: bool,     // print the parsed source as JSON; don't compile or run
+    dump_functions: bool, // print the functions that were defined, after preprocessing
+    dump_types: bool,   // print the types that were defined, after preprocessing
+    stats: bool,        // print measurements of the compiled code; don't run
+    trace: bool,        // print each instruction as it runs
+    step: bool,         // print each instruction, and wait for input before running it
+    optimize: bool,     // run compiled code through the peephole optimizer
+    check_stack: bool,  // check that top-level statements leave no extra values on the stack
+    buffer_writes: bool, // buffer writes to file descriptors
+    message_format: String, // how to report errors; either "human" or "json"
+    no_color: bool,     // never color the log
+    quiet: bool,        // only log errors
+    print_result: bool, // print the value of the program's last expression
+    bench: usize,       // number of times to run the program while timing it; 0 to not benchmark
+    stack_size: usize,  // size of the interpreter's stack in megabytes; 0 for the default
+    werror: bool,       // treat warnings as errors
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             file: String::new(),
+            eval: String::new(),
             compile_only: false,
             run_only: false,
+            max_steps: 0,
+            max_memory: 0,
+            max_include_depth: preprocessor::DEFAULT_MAX_INCLUDE_DEPTH,
+            tab_width: lexer::DEFAULT_TAB_WIDTH as usize,
+            check: false,
+            format: false,
+            ast_json: false,
+            dump_functions: false,
+            dump_types: false,
+            stats: false,
+            trace: false,
+            step: false,
+            optimize: false,
+            check_stack: false,
+            buffer_writes: false,
+            message_format: String::from("human"),
+            no_color: false,
+            quiet: false,
+            print_result: false,
+            bench: 0,
+            stack_size: 0,
+            werror: false,
+        }
+    }
+
+    /// Gets the name of the source being run, for error messages and includes.
+    pub fn source_name(&self) -> &str {
+        if self.eval.is_empty() {
+            &self.file
+        }
+        else {
+            "<eval>"
         }
     }
 }
@@ -64,11 +269,61 @@ fn parse_args() -> Config {
         let mut ap = ArgumentParser::new();
         ap.set_description("The rasp interpreter");
         ap.refer(&mut config.file)
-            .add_argument("file", Store, "file to run");
+            .add_argument("file", Store, "file to run; if omitted, start an interactive session");
+        ap.refer(&mut config.eval)
+            .add_option(&["-e", "--eval"], Store, "source code to run instead of a file");
+        ap.refer(&mut config.bench)
+            .add_option(&["--bench"], Store, "run the file this many times, and report how long the runs took");
+        ap.refer(&mut config.print_result)
+            .add_option(&["--print-result"], StoreTrue, "print the value of the program's last expression");
         ap.refer(&mut config.compile_only)
             .add_option(&["-c", "--compile-only"], StoreTrue, "only compile; don't run");
         ap.refer(&mut config.run_only)
             .add_option(&["-r", "--run-only"], StoreTrue, "only run; don't compile");
+        ap.refer(&mut config.check)
+            .add_option(&["--check"], StoreTrue, "only check for compile errors; don't run");
+        ap.refer(&mut config.format)
+            .add_option(&["--format"], StoreTrue, "print the file's source, formatted; don't compile or run");
+        ap.refer(&mut config.ast_json)
+            .add_option(&["--ast-json"], StoreTrue, "print the file's parse tree as JSON; don't compile or run");
+        ap.refer(&mut config.dump_functions)
+            .add_option(&["--dump-functions"], StoreTrue,
+                        "print the functions and aliases that were defined (stops there with --compile-only)");
+        ap.refer(&mut config.dump_types)
+            .add_option(&["--dump-types"], StoreTrue, "print the types that were defined (stops there with --compile-only)");
+        ap.refer(&mut config.stats)
+            .add_option(&["--stats"], StoreTrue,
+                        "print the instruction count, jump size and stack depth of the compiled code; don't run");
+        ap.refer(&mut config.trace)
+            .add_option(&["--trace"], StoreTrue, "print each instruction, the stack, and the variables as it runs");
+        ap.refer(&mut config.step)
+            .add_option(&["--step"], StoreTrue, "like --trace, but wait for input before each instruction");
+        ap.refer(&mut config.optimize)
+            .add_option(&["--optimize"], StoreTrue, "simplify compiled code before running it");
+        ap.refer(&mut config.check_stack)
+            .add_option(&["--check-stack"], StoreTrue,
+                        "stop with an error if a top-level statement leaves extra values on the stack (for debugging the compiler)");
+        ap.refer(&mut config.buffer_writes)
+            .add_option(&["--buffer-writes"], StoreTrue, "buffer writes to files until they are flushed or closed");
+        ap.refer(&mut config.werror)
+            .add_option(&["--werror"], StoreTrue, "treat compile warnings as errors");
+        ap.refer(&mut config.message_format)
+            .add_option(&["--message-format"], Store, "how to report errors: human (default) or json");
+        ap.refer(&mut config.quiet)
+            .add_option(&["-q", "--quiet"], StoreTrue, "only log errors, ignoring RUST_LOG");
+        ap.refer(&mut config.no_color)
+            .add_option(&["--no-color"], StoreTrue, "don't color messages (they're only colored on a terminal anyway)");
+        ap.refer(&mut config.max_steps)
+            .add_option(&["--max-steps"], Store, "maximum number of instructions to execute (0 for no limit)");
+        ap.refer(&mut config.max_memory)
+            .add_option(&["--max-memory"], Store,
+                        "maximum total size of values, in string bytes and list items (0 for no limit)");
+        ap.refer(&mut config.max_include_depth)
+            .add_option(&["--max-include-depth"], Store, "maximum number of nested includes");
+        ap.refer(&mut config.tab_width)
+            .add_option(&["--tab-width"], Store, "number of columns that a tab takes up in reported positions (default 1)");
+        ap.refer(&mut config.stack_size)
+            .add_option(&["--stack-size"], Store, "size of the interpreter's stack in megabytes, for deeply recursive programs (0 for the default)");
         //ap.refer(&mut config.verbose)
         //    .add_option(&["-v", "--verbose"], StoreTrue, "verbose output");
         ap.parse_args_or_exit();
@@ -76,6 +331,85 @@ fn parse_args() -> Config {
     config
 }
 
+/// Reports an error that halted the given stage, either through the log or as a JSON object on
+/// stdout, depending on the configured message format.
+fn report_error(config: &Config, stage: &str, err_chain: &errors::Error) {
+    log_error(config, &format!("{}. Halting.", stage), err_chain);
+}
+
+/// Reports a warning found while compiling, either through the log or as a JSON object on stdout,
+/// depending on the configured message format.
+fn report_warning(config: &Config, warning: &errors::Error) {
+    if config.message_format == "json" {
+        println!("{}", diagnostic_json(config.source_name(), "warning", warning));
+    }
+    else {
+        warn!("Warning: {}", warning.full_message());
+    }
+}
+
+/// Reports an error under the given heading, either through the log or as a JSON object on stdout,
+/// depending on the configured message format.
+fn log_error(config: &Config, heading: &str, err_chain: &errors::Error) {
+    if config.message_format == "json" {
+        println!("{}", diagnostic_json(config.source_name(), "error", err_chain));
+    }
+    else {
+        error!("{}", heading);
+        error!("Caused by {}", err_chain.iter()
+               .nth(0)
+               .unwrap());
+        for err in err_chain.iter().skip(1) {
+            error!("    caused by {}", err);
+        }
+    }
+}
+
+/// Converts an error or warning into a JSON diagnostic object with the given severity.
+fn diagnostic_json(file: &str, severity: &str, err_chain: &errors::Error) -> Json {
+    let file = err_chain.included_file()
+        .unwrap_or(file);
+    let (start, end) = match err_chain.range() {
+        Some(range) => (pos_json(range.start), pos_json(range.end)),
+        None => (Json::Null, Json::Null),
+    };
+    Json::Object(vec![
+        (String::from("severity"), Json::String(severity.to_string())),
+        (String::from("message"), Json::String(err_chain.root_message())),
+        (String::from("file"), Json::String(file.to_string())),
+        (String::from("start"), start),
+        (String::from("end"), end),
+    ])
+}
+
+/// Gets how the VM should report instructions as it runs them.
+fn step_mode(config: &Config) -> vm::StepMode {
+    if config.step {
+        vm::StepMode::Pause
+    }
+    else if config.trace {
+        vm::StepMode::Trace
+    }
+    else {
+        vm::StepMode::Off
+    }
+}
+
+/// Applies the VM settings from the command line to a VM.
+fn setup_vm(config: &Config, vma: &mut vm::VM) {
+    if config.max_steps > 0 {
+        vma.set_max_steps(Some(config.max_steps));
+    }
+    if config.max_memory > 0 {
+        vma.set_max_memory(Some(config.max_memory));
+    }
+    vma.set_max_include_depth(config.max_include_depth);
+    vma.set_tab_width(config.tab_width);
+    vma.set_step_mode(step_mode(config));
+    vma.set_optimize(config.optimize);
+    vma.set_buffer_writes(config.buffer_writes);
+}
+
 fn exit_error<T: Display>(err_str: T) {
     error!("Error: {}", err_str);
     trace!("Exiting with error");
@@ -83,45 +417,123 @@ fn exit_error<T: Display>(err_str: T) {
 }
 
 fn main() {
-    // init logger
+    // parse args; this automatically exits on failure
+    let config = parse_args();
+    // init logger; the log goes to stderr, so only color it if that's a terminal
     {
-        let logger_format = |record: &LogRecord| {
+        let use_color = !config.no_color && unsafe { libc::isatty(libc::STDERR_FILENO) } != 0;
+        let logger_format = move |record: &LogRecord| {
             let now_spec = time::get_time();
             let now = now_spec.sec as f64 + (now_spec.nsec as f64 / 1000000000.0);
             // TODO : source of the log message
+            let message = format!("{time:.2} [{level:07}] {msg}", time=now, level=record.level(), msg=record.args());
+            if !use_color {
+                return message;
+            }
             let color = match record.level() {
                 LogLevel::Error => Colour::Red.bold(),
                 LogLevel::Warn => Style::new().fg(Colour::Yellow),
                 LogLevel::Info => Style::new().fg(Colour::White),
                 _ => Colour::White.dimmed(),
             };
-            format!("{}", color.paint(format!("{time:.2} [{level:07}] {msg}", 
-                                              time=now, level=record.level(), msg=record.args())))
+            format!("{}", color.paint(message))
         };
         let mut builder = LogBuilder::new();
-        builder.format(logger_format)
-               .filter(None, LogLevelFilter::Warn);
-        if let Ok(env_var) = env::var("RUST_LOG") {
-            builder.parse(env_var.as_str());
+        builder.format(logger_format);
+        if config.quiet {
+            // only errors, whatever RUST_LOG says
+            builder.filter(None, LogLevelFilter::Error);
+        }
+        else {
+            builder.filter(None, LogLevelFilter::Warn);
+            if let Ok(env_var) = env::var("RUST_LOG") {
+                builder.parse(env_var.as_str());
+            }
         }
         builder.init().unwrap();
     }
     trace!("Starting up");
-    trace!("Parsing args");
-    // parse args; this automatically exits on failure
-    let config = parse_args();
+    if config.message_format != "human" && config.message_format != "json" {
+        exit_error(format!("unknown message format `{}' (expected human or json)", config.message_format));
+    }
 
-    // load file contents
-    let read_result = util::read_file(&config.file);
-    if let &Err(ref err) = &read_result {
-        exit_error(format!("could not read {}: {}", config.file, err));
+    if config.stack_size == 0 {
+        run(config);
+    }
+    else {
+        // deep recursion in a program recurses in the VM too, so it gets a thread with a bigger stack
+        let stack_bytes = match config.stack_size.checked_mul(1024 * 1024) {
+            Some(stack_bytes) => stack_bytes,
+            None => {
+                exit_error(format!("stack size of {} megabytes is too large", config.stack_size));
+                return;
+            },
+        };
+        let spawned = thread::Builder::new()
+            .stack_size(stack_bytes)
+            .spawn(move || run(config));
+        let child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                exit_error(format!("could not start the interpreter thread: {}", err));
+                return;
+            },
+        };
+        if child.join().is_err() {
+            exit_error("the interpreter thread panicked");
+        }
     }
-    trace!("Load {}", &config.file);
+}
 
-    // lex
-    let source_text = read_result.unwrap();
+/// Runs the REPL, a benchmark, or a program, as configured.
+fn run(config: Config) {
+    if config.file.is_empty() && config.eval.is_empty() {
+        let mut vma = vm::VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        setup_vm(&config, &mut vma);
+        let repl_result = repl::run(&mut vma, |err_chain| log_error(&config, "Error.", err_chain));
+        // exiting skips dropping the VM, which would flush its writes
+        vma.flush_writes();
+        match repl_result {
+            Ok(()) => info!("OK"),
+            Err(err_chain) => {
+                report_error(&config, "Error", &err_chain);
+                exit_error("Interactive session failed");
+            },
+        }
+        return;
+    }
+
+    if config.bench > 0 {
+        if config.file.is_empty() {
+            exit_error("--bench needs a file to run");
+        }
+        match bench::run(&config.file, config.bench, |vma| setup_vm(&config, vma)) {
+            Ok(result) => println!("{}: {} runs, mean {} (min {}, max {})", config.file, result.runs,
+                                   bench::millis(result.mean()), bench::millis(result.min), bench::millis(result.max)),
+            Err(err_chain) => {
+                report_error(&config, "Error", &err_chain);
+                exit_error("Benchmark failed");
+            },
+        }
+        return;
+    }
+
+    // lex; files are read as they are lexed, rather than loaded up front
     trace!("Creating lexer");
-    let lexer = Lexer::new(&source_text);
+    let mut lexer = if config.eval.is_empty() {
+        let open_result = File::open(&config.file)
+            .chain_err(|| format!("could not read {}", config.file));
+        if let Err(ref err_chain) = open_result {
+            report_error(&config, "Read error", err_chain);
+            exit_error("Compilation failed");
+        }
+        trace!("Load {}", &config.file);
+        Lexer::from_reader(BufReader::new(open_result.unwrap()))
+    }
+    else {
+        Lexer::new(&config.eval)
+    };
+    lexer.set_tab_width(config.tab_width);
 
     // parse
     trace!("Creating parser");
@@ -129,73 +541,144 @@ fn main() {
     trace!("Making AST");
     let parse_result = parser.parse();
     if let Err(ref err_chain) = parse_result {
-        error!("Parse error. Halting.");
-        error!("Caused by {}", err_chain.iter()
-               .nth(0)
-               .unwrap());
-        for err in err_chain.iter().skip(1) {
-            error!("    caused by {}", err);
-        }
+        report_error(&config, "Parse error", err_chain);
         exit_error("Compilation failed");
     }
     let mut ast = parse_result.unwrap();
+    if config.format {
+        print!("{}", format::format_source(&ast));
+        return;
+    }
+    if config.ast_json {
+        println!("{}", Json::Array(ast.iter().map(json::ast_json).collect()));
+        return;
+    }
     let mut fun_table = FunTable::new(Vec::new());
     let mut type_table = TypeTable::new(Vec::new());
 
     // Preprocess 
     {
         trace!("Preprocessing");
-        let mut preprocessor = Preprocessor::new(&config.file, &mut ast, &mut fun_table, &mut type_table);
+        let mut preprocessor = Preprocessor::new(config.source_name(), &mut ast, &mut fun_table, &mut type_table);
+        preprocessor.set_include_depth(0, config.max_include_depth);
+        preprocessor.set_tab_width(config.tab_width);
         let compile_result = preprocessor.preprocess();
         if let Err(ref err_chain) = compile_result {
-            error!("Compile error. Halting.");
-            error!("Caused by {}", err_chain.iter()
-                   .nth(0)
-                   .unwrap());
-            for err in err_chain.iter().skip(1) {
-                error!("    caused by {}", err);
-            }
+            report_error(&config, "Compile error", err_chain);
             exit_error("Compilation failed");
         }
+        for warning in preprocessor.warnings() {
+            report_warning(&config, warning);
+        }
+        if config.werror && !preprocessor.warnings().is_empty() {
+            exit_error("Compilation failed (warnings are treated as errors)");
+        }
+    }
+    if config.dump_functions {
+        print!("{}", fun_table.describe());
+    }
+    if config.dump_types {
+        print!("{}", type_table.describe());
+    }
+    if (config.dump_functions || config.dump_types) && config.compile_only {
+        return;
     }
     // Make bytecode
     let bytecode = {
-        let to_bytecode = bytecode::ToBytecode::new(&mut fun_table, &mut type_table);
+        let mut to_bytecode = bytecode::ToBytecode::new(&mut fun_table, &mut type_table);
+        to_bytecode.set_inline_constants(config.optimize);
+        to_bytecode.set_check_stack(config.check_stack);
         match to_bytecode.to_bytecode(&ast) {
             Ok(codez) => codez,
             Err(err_chain) => {
-                error!("Compile error. Halting.");
-                error!("Caused by {}", err_chain.iter()
-                       .nth(0)
-                       .unwrap());
-                for err in err_chain.iter().skip(1) {
-                    error!("    caused by {}", err);
-                }
+                report_error(&config, "Compile error", &err_chain);
                 exit_error("Compilation failed");
                 unreachable!()
             }
         }
     };
 
+    let bytecode = if config.optimize {
+        optimizer::optimize(bytecode)
+    }
+    else {
+        bytecode
+    };
+
+    // a sanity check on code generation, and the optimizer
+    let unreachable = reachability::check(&bytecode);
+    for warning in &unreachable {
+        report_warning(&config, warning);
+    }
+    if config.werror && !unreachable.is_empty() {
+        exit_error("Compilation failed (warnings are treated as errors)");
+    }
+
     // bytecode debug
     debug!("Here comes the bytecode");
     for b in &bytecode {
         debug!("{:?}", b);
     }
 
+    // every function body is measured too, even ones that would never be called
+    if config.stats {
+        let mut code_stats = stats::Stats::new();
+        code_stats.add(&bytecode);
+        for fun in fun_table.iter() {
+            let mut to_bytecode = bytecode::ToBytecode::for_function(&fun_table, &type_table, fun);
+            to_bytecode.set_inline_constants(config.optimize);
+            match to_bytecode.body_to_bytecode(&fun.body) {
+                Ok(codez) => code_stats.add_function(&if config.optimize { optimizer::optimize(codez) } else { codez }),
+                Err(err_chain) => {
+                    report_error(&config, &format!("Compile error in function `{}'", fun.name), &err_chain);
+                    exit_error("Compilation failed");
+                },
+            }
+        }
+        println!("{}", code_stats);
+        return;
+    }
+
+    // function bodies are normally compiled when they're first called, so compile all of them
+    // up front to find their errors too
+    if config.check {
+        for fun in fun_table.iter() {
+            let to_bytecode = bytecode::ToBytecode::for_function(&fun_table, &type_table, fun);
+            if let Err(err_chain) = to_bytecode.body_to_bytecode(&fun.body) {
+                report_error(&config, &format!("Compile error in function `{}'", fun.name), &err_chain);
+                exit_error("Compilation failed");
+            }
+        }
+        info!("OK");
+        return;
+    }
+
     // save compiled file(?)
     // run(?)
     let mut vma = vm::VM::new(fun_table, type_table);
-    match vma.run(&bytecode) {
-        Ok(()) => info!("OK"),
+    setup_vm(&config, &mut vma);
+    let run_result = vma.run(&bytecode);
+    vma.flush_writes();
+    match run_result {
+        Ok(()) => {
+            debug!("Finished with {} values on the stack and {} variable scopes", vma.stack_len(), vma.var_depth());
+            if config.print_result {
+                if let Some(value) = vma.peek_value() {
+                    println!("{}", value);
+                }
+            }
+            info!("OK");
+        },
+        Err(errors::Error(errors::ErrorKind::Exit(status), _)) => {
+            trace!("Exiting with status {}", status);
+            io::stdout().flush()
+                .expect("could not flush stdout");
+            process::exit(status);
+        },
         Err(err_chain) => {
-            use lexer::Range;
-            error!("Runtime error. Halting.");
-            error!("Caused by {}", err_chain.iter()
-                   .nth(0)
-                   .unwrap());
-            for err in err_chain.iter().skip(1) {
-                error!("    caused by {}", err);
+            report_error(&config, "Runtime error", &err_chain);
+            if config.message_format == "json" {
+                exit_error("Execution failed");
             }
             error!("Function stack:");
             let mut count = vma.fun_stack()
@@ -213,16 +696,20 @@ fn main() {
                         .last()
                         .expect("Double fault: exception occurred in a function, but that function doesn't have a body")
                         .range();
-                    let range = Range::new(first.start, last.end);
+                    let range = first.merge(last);
                     error!("    {:02}. {} (defined in \"{}\" at {})", count, fname, fun.source_file, range);
                 }
-                else {
+                else if builtins::BUILTIN_FUNCTIONS.contains_key(fname.as_str()) {
                     error!("    {:02}. {} (BUILTIN)", count, fname);
                 }
+                else {
+                    error!("    {:02}. {} (local function)", count, fname);
+                }
                 count -= 1;
             }
             // pedantic information
             vma.dump_debug();
+            exit_error("Execution failed");
         }
     }
     // shut down
@@ -0,0 +1,211 @@
+use bytecode::Bytecode;
+use vm::Value;
+
+use std::cmp;
+
+/// Simplifies compiled bytecode with a peephole pass, without changing what it does.
+/// The rewrites are applied until none of them match anymore:
+///
+/// * `Push(v) Pop(name)` becomes `Store(name, v)`
+/// * `Store(name, v) Load(name)` becomes `Store(name, v) Push(v)`, skipping the variable lookup
+/// * `Push(v) PopN(1)` and `Dup PopN(1)` are removed
+/// * `PopN(a) PopN(b)` becomes `PopN(a + b)`
+/// * `Skip(0)` is removed
+///
+/// Rewrites only ever look at neighbouring instructions, so they never reach across a
+/// `NewVarStack` or `PopVarStack`. Instructions that a `Skip` or `SkipFalse` jumps into the middle
+/// of are left alone, and skip counts are adjusted for any instructions that were removed. The
+/// bodies of closures are optimized too.
+/// Since fewer instructions run, optimized code counts fewer steps towards `--max-steps`.
+pub fn optimize(code: Vec<Bytecode>) -> Vec<Bytecode> {
+    let mut code = code.into_iter()
+        .map(|b| match b {
+            Bytecode::MakeClosure(name, params, body) => Bytecode::MakeClosure(name, params, optimize(body)),
+            b => b,
+        })
+        .collect::<Vec<_>>();
+    loop {
+        let (optimized, changed) = optimize_pass(&code);
+        code = optimized;
+        if !changed {
+            return code;
+        }
+    }
+}
+
+/// Applies the peephole rewrites once over some bytecode.
+/// Returns the rewritten code, and whether anything was changed.
+fn optimize_pass(code: &[Bytecode]) -> (Vec<Bytecode>, bool) {
+    let targets = jump_targets(code);
+    let mut optimized = Vec::new();
+    // where each original instruction ended up, for fixing up skip counts
+    let mut new_index = Vec::new();
+    let mut changed = false;
+    let mut index = 0;
+    while index < code.len() {
+        new_index.push(optimized.len());
+        let next = code.get(index + 1);
+        // a jump into the middle of a pair means it can't be rewritten
+        let pair = if targets[index + 1] { None } else { next };
+        let replacement = match (&code[index], pair) {
+            (&Bytecode::Push(ref value), Some(&Bytecode::Pop(ref name))) if !is_lookup(value) =>
+                Some(vec![Bytecode::Store(name.clone(), value.clone())]),
+            (&Bytecode::Store(ref name, ref value), Some(&Bytecode::Load(ref load_name)))
+                if name == load_name && !is_lookup(value) =>
+                Some(vec![Bytecode::Store(name.clone(), value.clone()), Bytecode::Push(value.clone())]),
+            (&Bytecode::Push(ref value), Some(&Bytecode::PopN(1))) if !is_lookup(value) => Some(Vec::new()),
+            (&Bytecode::Dup, Some(&Bytecode::PopN(1))) => Some(Vec::new()),
+            (&Bytecode::PopN(a), Some(&Bytecode::PopN(b))) => Some(vec![Bytecode::PopN(a + b)]),
+            _ => None,
+        };
+        if let Some(mut replacement) = replacement {
+            new_index.push(optimized.len());
+            optimized.append(&mut replacement);
+            changed = true;
+            index += 2;
+        }
+        else if let Bytecode::Skip(0) = code[index] {
+            changed = true;
+            index += 1;
+        }
+        else {
+            optimized.push(code[index].clone());
+            index += 1;
+        }
+    }
+    new_index.push(optimized.len());
+
+    // skips count instructions, so they have to be adjusted for the ones that were removed
+    for (index, b) in code.iter().enumerate() {
+        let target = match b {
+            // skipping past the end just stops
+            &Bytecode::Skip(n) | &Bytecode::SkipFalse(n) => cmp::min(index + 1 + n, code.len()),
+            _ => continue,
+        };
+        let at = new_index[index];
+        if new_index[index + 1] == at {
+            // this was a removed `Skip(0)`
+            continue;
+        }
+        let count = new_index[target] - at - 1;
+        match optimized[at] {
+            Bytecode::Skip(ref mut n) | Bytecode::SkipFalse(ref mut n) => *n = count,
+            _ => unreachable!(),
+        }
+    }
+    (optimized, changed)
+}
+
+/// Gets which instruction indices are jumped to by a `Skip` or `SkipFalse`.
+/// The result has room past the end of the code, for jumps to the very end.
+fn jump_targets(code: &[Bytecode]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 2];
+    for (index, b) in code.iter().enumerate() {
+        match b {
+            &Bytecode::Skip(n) | &Bytecode::SkipFalse(n) => targets[cmp::min(index + 1 + n, code.len())] = true,
+            _ => { },
+        }
+    }
+    targets
+}
+
+/// Gets whether pushing a value looks up a variable, rather than pushing the value as it is.
+fn is_lookup(value: &Value) -> bool {
+    match value {
+        &Value::Identifier(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use internal::{FunTable, TypeTable};
+    use vm::VM;
+
+    fn push(i: i64) -> Bytecode {
+        Bytecode::Push(Value::Integer(i))
+    }
+
+    fn assert_code_eq(actual: Vec<Bytecode>, expected: Vec<Bytecode>) {
+        assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn then_arm_skip_counts_are_recomputed() {
+        let code = vec![
+            Bytecode::Push(Value::Boolean(true)),
+            Bytecode::SkipFalse(4),
+            push(1),
+            Bytecode::Pop("x".to_string()),
+            Bytecode::Load("x".to_string()),
+            Bytecode::Skip(1),
+            push(2),
+        ];
+        assert_code_eq(optimize(code), vec![
+            Bytecode::Push(Value::Boolean(true)),
+            Bytecode::SkipFalse(3),
+            Bytecode::Store("x".to_string(), Value::Integer(1)),
+            push(1),
+            Bytecode::Skip(1),
+            push(2),
+        ]);
+    }
+
+    #[test]
+    fn else_arm_skip_counts_are_recomputed() {
+        let code = vec![
+            Bytecode::Push(Value::Boolean(false)),
+            Bytecode::SkipFalse(2),
+            push(1),
+            Bytecode::Skip(3),
+            push(5),
+            Bytecode::PopN(1),
+            push(2),
+            push(3),
+        ];
+        assert_code_eq(optimize(code), vec![
+            Bytecode::Push(Value::Boolean(false)),
+            Bytecode::SkipFalse(2),
+            push(1),
+            Bytecode::Skip(1),
+            push(2),
+            push(3),
+        ]);
+    }
+
+    #[test]
+    fn pairs_that_are_jumped_into_are_kept() {
+        let code = vec![
+            Bytecode::Push(Value::Boolean(true)),
+            Bytecode::SkipFalse(1),
+            push(1),
+            Bytecode::PopN(1),
+        ];
+        assert_code_eq(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn zero_skips_are_removed() {
+        assert_code_eq(optimize(vec![push(1), Bytecode::Skip(0), push(2)]), vec![push(1), push(2)]);
+    }
+
+    /// Runs some source code with and without optimization, checking that both give the same values.
+    fn assert_same_when_optimized(source: &str) {
+        let mut results = Vec::new();
+        for &optimize in &[false, true] {
+            let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+            vm.set_optimize(optimize);
+            results.push(vm.eval("<test>", source).unwrap());
+        }
+        assert_eq!(results[0], results[1], "optimizing changed the result of {}", source);
+    }
+
+    #[test]
+    fn optimizing_keeps_results() {
+        assert_same_when_optimized("(if (= 1 1) (let ((x 1)) x) 2) (list 3)");
+        assert_same_when_optimized("(if (= 1 2) 1 (let ((x 2)) (list x x))) (list 3)");
+        assert_same_when_optimized("(list (and 1 (let ((y 3)) y)) (or (= 1 2) 4) (case 2 (1 1) (2 (let ((z 5)) z))))");
+        assert_same_when_optimized("(&define f (n) (if (zero? n) (list) (conj (f (- n 1)) n))) (f 5)");
+    }
+}
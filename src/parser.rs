@@ -7,8 +7,8 @@ pub struct Parser<'a> {
     current_tok: Token,
 }
 
-fn parse_error(pos: &Range, message: &str) -> String {
-    format!("{}: {}", pos, message)
+fn parse_error(pos: &Range, message: &str) -> Error {
+    ErrorKind::Diagnostic(Some(*pos), message.to_string()).into()
 }
 
 impl<'a> Parser<'a> {
@@ -19,28 +19,29 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Parses the source into its top-level expressions. Comments are kept as `AST::Comment` items.
+    /// A source with no expressions (empty, or only whitespace and comments) parses to an empty list,
+    /// which compiles to empty bytecode and runs without doing anything.
     pub fn parse(&mut self) -> Result<Vec<AST>> {
         self.next();
         let mut ast = Vec::new();
         loop {
             match self.current_tok {
-                Token::Identifier(r, _) | Token::StringLit(r, _) | Token::Lparen(r) | Token::Number(r, _) => {
+                Token::Identifier(r, _) | Token::StringLit(r, _) | Token::Lparen(r) | Token::Number(r, _)
+                | Token::Integer(r, _) => {
                     let expr_result = self.expr();
                     if expr_result.is_err() {
-                        let start = r.start;
-                        let end = self.range().end;
-                        if start == end {
-                            expr_result.chain_err(|| format!("expression at {}", Range::new(start, end)))?;
-                        }
-                        else {
-                            expr_result.chain_err(|| format!("expression spanning {}", Range::new(start, end)))?;
-                        }
+                        let range = r.merge(&self.range());
+                        expr_result.chain_err(|| diagnostic(&range, "expression"))?;
                     }
                     else {
                         ast.push(expr_result.unwrap());
                     }
                 },
-                Token::Comment(_, _) => self.next(),
+                Token::Comment(r, ref s) => {
+                    ast.push(AST::Comment(r, s.clone()));
+                    self.next();
+                },
                 Token::Eof(_) => break,
                 Token::Unknown(r, _) => return Err(parse_error(&r,
                     &self.unexpected_token("left paren, identifier, string literal, or comment")).into()),
@@ -60,19 +61,18 @@ impl<'a> Parser<'a> {
                 &self.unexpected_token("left paren, identifier, number, or string literal")).into())
         }
 
-        let start = self.lexer
-                        .range
-                        .start;
         let expr = match self.current_tok {
             Token::Identifier(r, ref id) => AST::Identifier(r, id.clone()),
             Token::StringLit(r, ref s_lit) => AST::StringLit(r, s_lit.clone()),
             Token::Number(r, ref num) => AST::Number(r, *num),
-            Token::Lparen(_) => {
+            Token::Integer(r, ref num) => AST::Integer(r, *num),
+            Token::Lparen(start) => {
                 let mut exprs = Vec::new();
                 self.next();
                 // the next token may not be an expression start; it may just be an rparen
                 while self.is_expr_start() || self.current_tok.is_comment() {
-                    if self.current_tok.is_comment() {
+                    if let Token::Comment(r, ref s) = self.current_tok {
+                        exprs.push(AST::Comment(r, s.clone()));
                         self.next();
                         continue;
                     }
@@ -85,24 +85,18 @@ impl<'a> Parser<'a> {
                     }
                 }
 
-                if let Token::Error(_, ref s) = self.current_tok {
-                    return Err(s.as_str()
-                                .into());
+                if let Token::Error(r, ref s) = self.current_tok {
+                    return Err(parse_error(&r, &format!("lexer error: {}", s)));
                 }
                 else if let Token::Unknown(r, c) = self.current_tok {
                     return Err(parse_error(&r, &format!("syntax error: unexpected character {}", c)).into())
                 }
                 else if !self.current_tok.is_rparen() {
-                    return Err(self.unexpected_token(
-                            "left paren, identifier, string literal, number, or right paren").into());
+                    return Err(parse_error(&self.current_tok.range(), &self.unexpected_token(
+                            "left paren, identifier, string literal, number, or right paren")));
                 }
 
-                let end = self.lexer
-                    .range
-                    .end;
-                
-                let range = Range::new(start, end);
-                AST::Expr(range, exprs)
+                AST::Expr(start.merge(&self.current_tok.range()), exprs)
             },
             _ => unreachable!(),
         };
@@ -113,7 +107,8 @@ impl<'a> Parser<'a> {
     /// Gets whether the current character is an expression start
     fn is_expr_start(&self) -> bool {
         match self.current_tok {
-            Token::Lparen(_) | Token::Identifier(_,_) | Token::StringLit(_, _) | Token::Number(_, _) => true,
+            Token::Lparen(_) | Token::Identifier(_,_) | Token::StringLit(_, _) | Token::Number(_, _)
+            | Token::Integer(_, _) => true,
             _ => false,
         }
     }
@@ -124,10 +119,62 @@ impl<'a> Parser<'a> {
     }
 
     fn unexpected_token(&self, expected: &'static str) -> String {
-        format!("unexpected {} at {}: expected {}", self.current_tok, self.current_tok.range(), expected)
+        format!("unexpected {}: expected {}", self.current_tok, expected)
     }
 
     fn next(&mut self) {
         self.current_tok = self.lexer.next_token();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::ToBytecode;
+    use internal::{FunTable, TypeTable};
+
+    fn parse(source: &str) -> Vec<AST> {
+        Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let ast = parse("; top\n(list 1 ; inner\n  2)\n");
+        assert_eq!(ast.len(), 2);
+        match ast[0] {
+            AST::Comment(range, ref text) => {
+                assert_eq!(text, " top");
+                assert_eq!(range.to_string(), "1:1-1:5");
+            },
+            ref a => panic!("expected a comment, but got {:?}", a),
+        }
+        match ast[1].exprs()[2] {
+            AST::Comment(range, ref text) => {
+                assert_eq!(text, " inner");
+                assert_eq!(range.to_string(), "2:9-2:15");
+            },
+            ref a => panic!("expected a comment, but got {:?}", a),
+        }
+    }
+
+    #[test]
+    fn top_level_comments_do_not_change_bytecode() {
+        let fun_table = FunTable::new(Vec::new());
+        let type_table = TypeTable::new(Vec::new());
+        let generator = ToBytecode::new(&fun_table, &type_table);
+        let with_comments = generator.to_bytecode(&parse("; first\n(list 1 2)\n; second\n(list 3)\n; last"))
+            .unwrap();
+        let without_comments = generator.to_bytecode(&parse("(list 1 2)\n(list 3)"))
+            .unwrap();
+        assert_eq!(format!("{:?}", with_comments), format!("{:?}", without_comments));
+    }
+
+    #[test]
+    fn expressions_span_their_parens() {
+        let ast = parse("(list 1\n  (list 2))");
+        assert_eq!(ast[0].range().to_string(), "1:1-2:11");
+        assert_eq!(ast[0].exprs()[2].range().to_string(), "2:3-2:10");
+    }
+}
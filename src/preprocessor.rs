@@ -2,12 +2,31 @@ use ast::AST;
 use internal::*;
 use gatherer::*;
 use errors::*;
+use lexer::{Range, DEFAULT_TAB_WIDTH};
+use builtins::BUILTIN_FUNCTIONS;
+use bytecode::{SPECIAL_FORMS, THREADING_FORM};
+
+use std::collections::HashSet;
+use std::mem;
+
+/// The default maximum number of nested includes.
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 64;
 
 pub struct Preprocessor<'a, 'b> {
     source_file: &'a str,
     ast: &'b mut Vec<AST>,
     fun_table: &'b mut FunTable,
     type_table: &'b mut TypeTable,
+    /// How many includes deep the source file is
+    include_depth: usize,
+    /// Maximum number of nested includes before giving up
+    max_include_depth: usize,
+    /// Number of columns that a tab takes up in included files
+    tab_width: usize,
+    /// Problems that were found which don't stop the source from compiling
+    warnings: Vec<Error>,
+    /// Names of the functions that the source and its includes defined
+    defined_funs: Vec<String>,
 }
 
 impl<'a, 'b> Preprocessor<'a, 'b> {
@@ -18,9 +37,41 @@ impl<'a, 'b> Preprocessor<'a, 'b> {
             ast: ast,
             fun_table: fun_table,
             type_table: type_table,
+            include_depth: 0,
+            max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
+            tab_width: DEFAULT_TAB_WIDTH as usize,
+            warnings: Vec::new(),
+            defined_funs: Vec::new(),
         }
     }
 
+    /// Gets the names of the functions that were defined while preprocessing, including the ones in
+    /// included files. They may replace functions that were already in the function table.
+    pub fn defined_funs(&self) -> &Vec<String> {
+        &self.defined_funs
+    }
+
+    /// Gets the warnings found while preprocessing, including the ones in included files.
+    pub fn warnings(&self) -> &Vec<Error> {
+        &self.warnings
+    }
+
+    /// Takes the warnings found while preprocessing, leaving none behind.
+    pub fn take_warnings(&mut self) -> Vec<Error> {
+        mem::replace(&mut self.warnings, Vec::new())
+    }
+
+    /// Sets the number of columns that a tab takes up in included files.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width;
+    }
+
+    /// Sets how many includes deep the source file is, and how deep includes may go.
+    pub fn set_include_depth(&mut self, include_depth: usize, max_include_depth: usize) {
+        self.include_depth = include_depth;
+        self.max_include_depth = max_include_depth;
+    }
+
     /// Manipulates a given AST based on builtin functions and user-defined macros.
     /// It completes the following stages:
     /// * Preprocessing
@@ -36,18 +87,32 @@ impl<'a, 'b> Preprocessor<'a, 'b> {
     }
 
     /// Does preprocessing actions on the AST. This involves:
+    /// * Removing comments inside of expressions; top-level comments are kept as docstrings
+    /// * Expanding `->` threading forms
     /// * Gathering includes
     /// * Gathering user-defined types
     /// * Gathering function definitions
     /// * Gathering external function definitions
+    /// * Gathering function aliases
     /// * Removing all AST items that had something gathered from them
     fn preprocess_builtins(&mut self) -> Result<()> {
+        for expr in self.ast.iter_mut() {
+            expr.strip_comments();
+            expand_threading(expr)?;
+        }
         // get includes
         debug!("Gathering includes");
         {
             let include_result = {
-                let mut include_gatherer = IncludeGatherer::new(self.fun_table, self.type_table);
-                include_gatherer.gather(self.ast)
+                let mut include_gatherer = IncludeGatherer::new(self.source_file, self.fun_table, self.type_table,
+                                                                self.include_depth, self.max_include_depth);
+                include_gatherer.set_tab_width(self.tab_width);
+                let include_result = include_gatherer.gather(self.ast);
+                self.defined_funs
+                    .extend(include_gatherer.defined_funs().iter().cloned());
+                self.warnings
+                    .append(&mut include_gatherer.take_warnings());
+                include_result
             };
             if include_result.is_err() {
                 include_result.chain_err(|| format!("{}", self.source_file))?;
@@ -84,6 +149,11 @@ impl<'a, 'b> Preprocessor<'a, 'b> {
                 return Err(e);
             }
             let funs = fun_result.unwrap();
+            self.check_unused_params(&funs);
+            self.check_shadowed_builtins(&funs);
+            self.check_endless_recursion(&funs);
+            self.defined_funs
+                .extend(funs.iter().map(|f| f.name.clone()));
             self.fun_table
                 .append(funs);
         }
@@ -102,6 +172,30 @@ impl<'a, 'b> Preprocessor<'a, 'b> {
                 .append(funs);
         }
         */
+        // get aliases
+        debug!("Gathering aliases");
+        {
+            let aliases = AliasGatherer.gather(self.ast)?;
+            for (target, alias, range) in aliases {
+                let fun_name = self.fun_table
+                    .resolve_alias(&target)
+                    .to_string();
+                if !self.fun_table.has_fun(&fun_name) && !BUILTIN_FUNCTIONS.contains_key(fun_name.as_str()) {
+                    return Err(diagnostic(&range, format!("cannot alias unknown function `{}'", target)).into());
+                }
+                if self.fun_table.has_fun(&alias) || self.fun_table.has_alias(&alias)
+                    || BUILTIN_FUNCTIONS.contains_key(alias.as_str()) {
+                    return Err(diagnostic(&range, format!("cannot use `{}' as an alias, since it is already a function name", alias))
+                               .into());
+                }
+                if SPECIAL_FORMS.contains(&alias.as_str()) {
+                    return Err(diagnostic(&range, format!("cannot use `{}' as an alias, since it is a special form", alias))
+                               .into());
+                }
+                self.fun_table
+                    .add_alias(&alias, &fun_name);
+            }
+        }
         self.fun_table
             .dump_debug();
 
@@ -118,4 +212,287 @@ impl<'a, 'b> Preprocessor<'a, 'b> {
             });
         Ok(())
     }
+
+    /// Warns about parameters that are never used in the bodies of their functions.
+    /// Parameters whose names start with an underscore are expected to be unused.
+    fn check_unused_params(&mut self, funs: &Vec<Function>) {
+        for fun in funs {
+            let mut used = HashSet::new();
+            for expr in &fun.body {
+                expr.walk(&mut |item| if item.is_identifier() {
+                    used.insert(item.identifier().to_string());
+                });
+            }
+            let range = body_range(fun);
+            for param in &fun.params {
+                if !param.name.starts_with('_') && !used.contains(&param.name) {
+                    self.warnings.push(ErrorKind::Diagnostic(range, format!("parameter `{}' of function `{}' is never used",
+                                                                            param.name, fun.name)).into());
+                }
+            }
+        }
+    }
+
+    /// Warns about functions that have the same name as a builtin function. Calls to that name go
+    /// to the user's function instead of the builtin.
+    fn check_shadowed_builtins(&mut self, funs: &Vec<Function>) {
+        for fun in funs {
+            if BUILTIN_FUNCTIONS.contains_key(fun.name.as_str()) {
+                self.warnings.push(ErrorKind::Diagnostic(body_range(fun), format!("function `{}' shadows the builtin function of the same name",
+                                                                                  fun.name)).into());
+            }
+        }
+    }
+
+    /// Warns about functions that will always call themselves with the same arguments before
+    /// anything else can happen, which means they never return.
+    /// This is only a heuristic: it only looks at the parts of the body that always run, and any
+    /// branch (e.g. `if` or `and`) is assumed to be able to stop the recursion.
+    fn check_endless_recursion(&mut self, funs: &Vec<Function>) {
+        for fun in funs {
+            if let Some(range) = fun.body.iter().filter_map(|expr| unconditional_self_call(expr, fun)).next() {
+                self.warnings.push(ErrorKind::Diagnostic(Some(range), format!("function `{}' always calls itself with the same arguments, so it will never return",
+                                                                              fun.name)).into());
+            }
+        }
+    }
+}
+
+/// Rewrites every `->` form inside of an expression into the nested calls that it stands for.
+/// Each step after the first value is called with the result of the step before it as its first
+/// argument, so `(-> x (f a) (g b))` becomes `(g (f x a) b)`. A step that's just a function name is
+/// called with only that argument, so `(-> x f)` becomes `(f x)`.
+fn expand_threading(expr: &mut AST) -> Result<()> {
+    let mut error = None;
+    expr.walk_mut(&mut |item| {
+        // a step may itself be a `->` form, which becomes the new item after threading
+        while error.is_none() && is_threading_form(item) {
+            if let Err(e) = thread_steps(item) {
+                error = Some(e);
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether an expression is a `->` form.
+fn is_threading_form(expr: &AST) -> bool {
+    expr.is_expr() && expr.exprs().first().map_or(false, |first| first.is_identifier()
+                                                  && first.identifier() == THREADING_FORM)
+}
+
+/// Replaces a `->` form with the nested calls of its steps.
+fn thread_steps(item: &mut AST) -> Result<()> {
+    let range = *item.range();
+    let mut exprs = match item {
+        &mut AST::Expr(_, ref mut exprs) => exprs.drain(1 ..).collect::<Vec<AST>>().into_iter(),
+        _ => unreachable!(),
+    };
+    let mut threaded = match exprs.next() {
+        Some(value) => value,
+        None => return Err(diagnostic(&range, format!("`{}' requires a value to thread through its steps", THREADING_FORM)).into()),
+    };
+    for step in exprs {
+        threaded = match step {
+            AST::Identifier(r, name) => AST::Expr(r, vec![AST::Identifier(r, name), threaded]),
+            AST::Expr(r, ref exprs) if exprs.is_empty() =>
+                return Err(diagnostic(&r, format!("steps of `{}' may not be empty expressions", THREADING_FORM)).into()),
+            AST::Expr(r, mut step_exprs) => {
+                step_exprs.insert(1, threaded);
+                AST::Expr(r, step_exprs)
+            },
+            step => return Err(diagnostic(step.range(), format!("steps of `{}' must be a function name or a call", THREADING_FORM)).into()),
+        };
+    }
+    // the outermost call stands for the whole form, so errors in it point at all of it
+    *item = match threaded {
+        AST::Expr(_, exprs) => AST::Expr(range, exprs),
+        value => value,
+    };
+    Ok(())
+}
+
+/// Finds a call of a function to itself with its own parameters, in the part of an expression that
+/// is always evaluated.
+/// Only the conditions of `if`, `when`, `unless`, `case`, `match`, `and` and `or` are always
+/// evaluated, as are the assigned values of a `let` and the value of a `def`. The body of a
+/// `define` isn't evaluated at all until it's called.
+fn unconditional_self_call(expr: &AST, fun: &Function) -> Option<Range> {
+    if !expr.is_expr() || expr.exprs().is_empty() || !expr.exprs()[0].is_identifier() {
+        return None;
+    }
+    let exprs = expr.exprs();
+    let always_evaluated: Vec<&AST> = match exprs[0].identifier() {
+        "if" | "when" | "unless" | "case" | "match" | "and" | "or" => exprs.iter().skip(1).take(1).collect(),
+        "let" => exprs.iter()
+            .skip(1)
+            .take(1)
+            .filter(|setz| setz.is_expr())
+            .flat_map(|setz| setz.exprs().iter())
+            .filter(|set| set.is_expr() && set.exprs().len() == 2)
+            .map(|set| &set.exprs()[1])
+            .collect(),
+        "def" => exprs.iter().skip(2).take(1).collect(),
+        "define" => Vec::new(),
+        name => {
+            let args = &exprs[1..];
+            let same_args = args.len() == fun.params.len()
+                && args.iter()
+                    .zip(fun.params.iter())
+                    .all(|(arg, param)| arg.is_identifier() && arg.identifier() == param.name);
+            if name == fun.name && same_args {
+                return Some(*expr.range());
+            }
+            // arguments are evaluated before the call
+            args.iter().collect()
+        },
+    };
+    always_evaluated.into_iter()
+        .filter_map(|expr| unconditional_self_call(expr, fun))
+        .next()
+}
+
+/// Gets the range of a function's body, if it has one.
+fn body_range(fun: &Function) -> Option<Range> {
+    match (fun.body.first(), fun.body.last()) {
+        (Some(first), Some(last)) => Some(first.range().merge(last.range())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+    use vm::Value;
+    use vm::tests::{run_value, run_error, temp_source, error_text};
+
+    /// Preprocesses some source, describing the warnings that it gives.
+    fn warnings(source: &str) -> Vec<String> {
+        let mut ast = Parser::new(Lexer::new(source))
+            .parse()
+            .unwrap();
+        let mut fun_table = FunTable::new(Vec::new());
+        let mut type_table = TypeTable::new(Vec::new());
+        let mut preprocessor = Preprocessor::new("<test>", &mut ast, &mut fun_table, &mut type_table);
+        preprocessor.preprocess()
+            .unwrap();
+        preprocessor.warnings()
+            .iter()
+            .map(|w| error_text(w))
+            .collect()
+    }
+
+    #[test]
+    fn alias_calls_builtin() {
+        assert_eq!(run_value("(&alias + add) (list (+ 1 2) (add 1 2))"),
+                   Value::from(vec![Value::Integer(3), Value::Integer(3)]));
+    }
+
+    #[test]
+    fn alias_calls_user_function() {
+        assert_eq!(run_value("(&define twice (x) (* x 2)) (&alias twice double) (&alias double dbl) (dbl 4)"),
+                   Value::Integer(8));
+    }
+
+    #[test]
+    fn local_shadows_alias() {
+        assert_eq!(run_value("(&alias + add) (let ((add 5)) add)"), Value::Integer(5));
+    }
+
+    #[test]
+    fn alias_must_name_a_function() {
+        assert!(run_error("(&alias nope add)").contains("cannot alias unknown function `nope'"));
+        assert!(run_error("(&alias + car)").contains("cannot use `car' as an alias, since it is already a function name"));
+        assert!(run_error("(&alias + if)").contains("cannot use `if' as an alias, since it is a special form"));
+    }
+
+    #[test]
+    fn unused_params_are_warned_about() {
+        assert_eq!(warnings("(&define f (a b) a)"), vec!["1:17-1:18: parameter `b' of function `f' is never used"]);
+        assert_eq!(warnings("(&define f (a ? b) 1)"),
+                   vec!["1:19-1:20: parameter `a' of function `f' is never used", "1:19-1:20: parameter `b' of function `f' is never used"]);
+    }
+
+    #[test]
+    fn used_and_underscored_params_are_not_warned_about() {
+        assert!(warnings("(&define f (a b) (list a (let ((c b)) c)))").is_empty());
+        assert!(warnings("(&define f (_a) 1)").is_empty());
+    }
+
+    #[test]
+    fn shadowed_builtins_are_warned_about() {
+        assert_eq!(warnings("(&define car (xs) xs)"),
+                   vec!["1:18-1:19: function `car' shadows the builtin function of the same name"]);
+        assert!(warnings("(&define first (xs) xs)").is_empty());
+    }
+
+    #[test]
+    fn user_functions_shadow_builtins() {
+        assert_eq!(run_value("(&define car (xs) 42) (car (list 1 2))"), Value::Integer(42));
+        assert_eq!(run_value("(&define inc (a b) (+ a b)) (inc 1 2)"), Value::Integer(3));
+    }
+
+    #[test]
+    fn endless_recursion_is_warned_about() {
+        assert_eq!(warnings("(&define f (x) (f x))"),
+                   vec!["1:16-1:20: function `f' always calls itself with the same arguments, so it will never return"]);
+        // in the parts of the body that always run
+        assert_eq!(warnings("(&define f (x) (if (f x) 1 2))").len(), 1);
+        assert_eq!(warnings("(&define f (x) (when (f x) 1))").len(), 1);
+        assert_eq!(warnings("(&define f (x) (unless (f x) 1))").len(), 1);
+        assert_eq!(warnings("(&define f (x) (let ((y (f x))) y))").len(), 1);
+        assert_eq!(warnings("(&define f (x) (+ 1 (f x)))").len(), 1);
+    }
+
+    #[test]
+    fn conditional_or_changing_recursion_is_not_warned_about() {
+        let sources = [
+            "(&define f (x) (if (= x 0) 0 (f x)))",
+            "(&define f (x) (when (= x 0) (f x)))",
+            "(&define f (x) (unless (= x 0) (f x)))",
+            "(&define f (x) (and x (f x)))",
+            "(&define f (x) (f (- x 1)))",
+            "(&define f (x) (define g () (f x)) 1)",
+        ];
+        for source in &sources {
+            assert_eq!(warnings(source), Vec::<String>::new(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn included_files_give_warnings_with_their_path() {
+        let inner = temp_source("inner-warnings.rasp", "(&define endless (x) (endless x))");
+        let outer = temp_source("outer-warnings.rasp", &format!("(&include \"{}\")\n(&define car (xs) xs)\n(&define g (a b) a)",
+                                                                 inner));
+        assert_eq!(warnings(&format!("(&include \"{}\")", outer)), vec![
+            format!("{}: 1:22-1:32: function `endless' always calls itself with the same arguments, so it will never return", inner),
+            format!("{}: 3:17-3:18: parameter `b' of function `g' is never used", outer),
+            format!("{}: 2:18-2:19: function `car' shadows the builtin function of the same name", outer),
+        ]);
+    }
+
+    #[test]
+    fn threading_calls_each_step_with_the_last_result() {
+        assert_eq!(run_value("(-> 5 (- 1) (list 2) car)"), Value::Integer(4));
+        // steps may be threading forms too
+        assert_eq!(run_value("(-> (list 1 2) (-> cdr) car)"), Value::Integer(2));
+        assert_eq!(run_value("(-> 7)"), Value::Integer(7));
+    }
+
+    #[test]
+    fn threading_checks_its_steps() {
+        assert!(run_error("(->)").contains("`->' requires a value to thread through its steps"));
+        assert!(run_error("(-> 1 ())").contains("steps of `->' may not be empty expressions"));
+        assert!(run_error("(-> 1 2)").contains("steps of `->' must be a function name or a call"));
+    }
+
+    #[test]
+    fn errors_in_threading_point_at_the_whole_form() {
+        assert_eq!(run_error("(-> 1 (car 2))"), "1:1-1:14: builtin function car takes exactly 1 arguments, but was called with 2");
+    }
 }
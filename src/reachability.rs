@@ -0,0 +1,108 @@
+use bytecode::Bytecode;
+use errors::*;
+
+/// Finds instructions in compiled bytecode that can never run, which would point at a bug in code
+/// generation. Every `Skip` and `SkipFalse` is followed, starting from the first instruction.
+/// Returns a warning for each run of unreachable instructions, including ones in closure bodies.
+pub fn check(code: &[Bytecode]) -> Vec<Error> {
+    let mut warnings = Vec::new();
+    check_in(code, None, &mut warnings);
+    warnings
+}
+
+fn check_in(code: &[Bytecode], closure: Option<&str>, warnings: &mut Vec<Error>) {
+    let reached = reachable(code);
+    let mut index = 0;
+    while index < code.len() {
+        if reached[index] {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < code.len() && !reached[index] {
+            index += 1;
+        }
+        let location = match closure {
+            Some(name) => format!(" in closure `{}'", name),
+            None => String::new(),
+        };
+        warnings.push(format!("bytecode instructions {} to {}{} can never run ({:?} first)",
+                              start, index - 1, location, code[start]).into());
+    }
+    for b in code {
+        if let &Bytecode::MakeClosure(ref name, _, ref body) = b {
+            check_in(body, Some(name), warnings);
+        }
+    }
+}
+
+/// Gets which instructions can be reached from the start of some bytecode.
+fn reachable(code: &[Bytecode]) -> Vec<bool> {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || reached[index] {
+            continue;
+        }
+        reached[index] = true;
+        match code[index] {
+            Bytecode::Skip(n) => pending.push(index + 1 + n),
+            Bytecode::SkipFalse(n) => {
+                pending.push(index + 1);
+                pending.push(index + 1 + n);
+            },
+            _ => pending.push(index + 1),
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::tests::compile;
+    use vm::Value;
+
+    /// Gets the text of each warning about some bytecode.
+    fn messages(code: &[Bytecode]) -> Vec<String> {
+        check(code).iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn compiled_code_is_reachable() {
+        let code = compile("(&define f (x) (if x 1 2)) (when (f 1) (f 2)) (and 1 (or 2 3)) (case 1 (1 2) (3 4))");
+        assert_eq!(messages(&code), Vec::<String>::new());
+    }
+
+    #[test]
+    fn skipped_instructions_are_unreachable() {
+        let code = vec![
+            Bytecode::Skip(2),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Push(Value::Integer(3)),
+        ];
+        assert_eq!(messages(&code), vec!["bytecode instructions 1 to 2 can never run (Push(Integer(1)) first)"]);
+    }
+
+    #[test]
+    fn both_branches_of_skip_false_are_reachable() {
+        let code = vec![
+            Bytecode::Push(Value::Boolean(true)),
+            Bytecode::SkipFalse(2),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Skip(1),
+            Bytecode::Push(Value::Integer(2)),
+        ];
+        assert_eq!(messages(&code), Vec::<String>::new());
+    }
+
+    #[test]
+    fn closure_bodies_are_checked() {
+        let body = vec![Bytecode::Skip(1), Bytecode::Push(Value::Integer(1))];
+        let code = vec![Bytecode::MakeClosure(String::from("f"), Vec::new(), body)];
+        assert_eq!(messages(&code), vec!["bytecode instructions 1 to 1 in closure `f' can never run (Push(Integer(1)) first)"]);
+    }
+}
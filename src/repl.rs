@@ -0,0 +1,152 @@
+use vm::{self, VM};
+use lexer::{Lexer, Token};
+use builtins::BUILTIN_DOCS;
+use errors::*;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Name used for code entered in the REPL, e.g. as the file that its functions are defined in.
+const REPL_SOURCE_NAME: &'static str = "<repl>";
+/// Prompt shown when starting a new expression.
+const PROMPT: &'static str = "> ";
+/// Prompt shown when an expression continues onto another line.
+const CONTINUE_PROMPT: &'static str = ". ";
+/// Command that describes a function, e.g. `:help car`.
+const HELP_COMMAND: &'static str = ":help";
+/// Command that lists the names that start with a prefix, e.g. `:complete st`.
+const COMPLETE_COMMAND: &'static str = ":complete";
+
+/// Runs an interactive read-eval-print loop on stdin, until the end of input.
+/// Input is read until its parentheses are balanced, so expressions may span several lines. A line
+/// starting with `:help` describes a function, and one starting with `:complete` lists the names
+/// that could complete a prefix, instead of being run. Errors
+/// in the entered code, including calls to `exit`, are passed to `report` before reading more
+/// input; errors that stop the REPL (e.g. an unclosed expression at the end of input) are returned.
+pub fn run<F: Fn(&Error)>(vm: &mut VM, report: F) -> Result<()> {
+    let input = Rc::new(RefCell::new(io::stdin().lock()));
+    // the debugger reads through the same lock, since stdin stays locked while code runs
+    let step_input = input.clone();
+    vm.set_step_input(Box::new(move || vm::read_line(&mut *step_input.borrow_mut())));
+    let mut source = String::new();
+    loop {
+        print!("{}", if source.is_empty() { PROMPT } else { CONTINUE_PROMPT });
+        io::stdout().flush()
+            .chain_err(|| "could not write prompt")?;
+        let line = match vm::read_line(&mut *input.borrow_mut()).chain_err(|| "could not read input")? {
+            Some(line) => line,
+            None => break,
+        };
+        if source.is_empty() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some(HELP_COMMAND) => {
+                    match words.next() {
+                        Some(name) => println!("{}", help(vm, name)),
+                        None => println!("usage: {} function-name", HELP_COMMAND),
+                    }
+                    continue;
+                },
+                Some(COMPLETE_COMMAND) => {
+                    println!("{}", vm.completions(words.next().unwrap_or("")).join(" "));
+                    continue;
+                },
+                _ => { },
+            }
+        }
+        source += &line;
+        source.push('\n');
+        if paren_depth(&source) > 0 {
+            continue;
+        }
+
+        match vm.eval(REPL_SOURCE_NAME, &source) {
+            Ok(values) => for value in values {
+                println!("{}", value);
+            },
+            Err(err_chain) => {
+                report(&err_chain);
+                vm.recover();
+            },
+        }
+        source.clear();
+    }
+    // leave the shell's prompt on its own line
+    println!();
+    if paren_depth(&source) > 0 {
+        Err("unexpected end of input: expression is missing a right paren".into())
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Describes a function: how it's called, and its docstring or description.
+fn help(vm: &VM, name: &str) -> String {
+    let fun_name = vm.fun_table()
+        .resolve_alias(name);
+    if let Some(fun) = vm.fun_table().get_fun(fun_name) {
+        let mut text = fun.usage();
+        if !fun.docstring.is_empty() {
+            text += "\n";
+            text += fun.docstring.trim();
+        }
+        text + &format!("\n(defined in {})", fun.source_file)
+    }
+    else if let Some(doc) = BUILTIN_DOCS.get(fun_name) {
+        doc.to_string()
+    }
+    else {
+        format!("no function named `{}'", name)
+    }
+}
+
+/// Gets how many more left parens there are than right parens in some source code.
+fn paren_depth(source: &str) -> i64 {
+    let mut depth = 0;
+    for token in Lexer::new(source) {
+        match token {
+            Token::Lparen(_) => depth += 1,
+            Token::Rparen(_) => depth -= 1,
+            // the parser will report this
+            Token::Error(_, _) => break,
+            _ => { },
+        }
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use internal::{FunTable, TypeTable};
+
+    /// Makes a VM that has run some source code.
+    fn vm_with(source: &str) -> VM {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.eval(REPL_SOURCE_NAME, source)
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn help_describes_user_functions() {
+        let vm = vm_with("(&define f (a b :int ? c) \"does a thing\" (list a b c))");
+        assert_eq!(help(&vm, "f"), "(f a b :int ? c)\ndoes a thing\n(defined in <repl>)");
+        let vm = vm_with("; no string\n(&define g () 1)");
+        assert_eq!(help(&vm, "g"), "(g)\nno string\n(defined in <repl>)");
+    }
+
+    #[test]
+    fn help_describes_builtins_and_aliases() {
+        let vm = vm_with("(&alias car first)");
+        assert_eq!(help(&vm, "car"), "(car list) gets the first item of a list or string");
+        assert_eq!(help(&vm, "first"), "(car list) gets the first item of a list or string");
+    }
+
+    #[test]
+    fn help_names_unknown_functions() {
+        assert_eq!(help(&vm_with(""), "nope"), "no function named `nope'");
+    }
+}
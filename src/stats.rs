@@ -0,0 +1,157 @@
+use bytecode::Bytecode;
+
+use std::cmp;
+use std::fmt;
+
+/// Measurements of some compiled bytecode, for `--stats`.
+pub struct Stats {
+    /// Number of instructions, including the ones in function and closure bodies
+    pub instructions: usize,
+    /// Number of user-defined functions whose bodies were measured
+    pub functions: usize,
+    /// Number of closures made with `define`
+    pub closures: usize,
+    /// Largest number of instructions skipped by a `Skip` or `SkipFalse`
+    pub max_jump: usize,
+    /// Estimate of the most values that any one function (or the top level) has on the stack at once
+    pub max_stack_depth: usize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            instructions: 0,
+            functions: 0,
+            closures: 0,
+            max_jump: 0,
+            max_stack_depth: 0,
+        }
+    }
+
+    /// Adds the measurements of a function's body.
+    pub fn add_function(&mut self, code: &[Bytecode]) {
+        self.functions += 1;
+        self.add(code);
+    }
+
+    /// Adds the measurements of some code, and the bodies of any closures in it.
+    pub fn add(&mut self, code: &[Bytecode]) {
+        self.instructions += code.len();
+        self.max_stack_depth = cmp::max(self.max_stack_depth, max_stack_depth(code));
+        for b in code {
+            match b {
+                &Bytecode::Skip(n) | &Bytecode::SkipFalse(n) => self.max_jump = cmp::max(self.max_jump, n),
+                &Bytecode::MakeClosure(_, _, ref body) => {
+                    self.closures += 1;
+                    self.add(body);
+                },
+                _ => { },
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "functions compiled: {}", self.functions)?;
+        writeln!(f, "closures: {}", self.closures)?;
+        writeln!(f, "max jump: {}", self.max_jump)?;
+        write!(f, "max stack depth: {}", self.max_stack_depth)
+    }
+}
+
+/// Estimates the most values that some code has on the stack at once, relative to where the stack
+/// was when it started.
+/// Every call is assumed to push a single result, and where two paths meet, the deeper stack is
+/// kept. Jumps only go forwards, so a single pass over the code sees every path into an
+/// instruction before the instruction itself.
+fn max_stack_depth(code: &[Bytecode]) -> usize {
+    // the stack depth coming into each instruction, if it can be reached; one past the end is
+    // where skips to the very end go
+    let mut depths: Vec<Option<usize>> = vec![None; code.len() + 1];
+    if !code.is_empty() {
+        depths[0] = Some(0);
+    }
+    let mut max_depth = 0;
+    for (index, b) in code.iter().enumerate() {
+        let depth = match depths[index] {
+            Some(depth) => depth,
+            None => continue,
+        };
+        max_depth = cmp::max(max_depth, depth);
+        let after = match b {
+            &Bytecode::Call(_, arg_count) => depth.saturating_sub(arg_count) + 1,
+            &Bytecode::CallValue(arg_count) => depth.saturating_sub(arg_count + 1) + 1,
+            &Bytecode::Push(_) | &Bytecode::Load(_) | &Bytecode::LoadGlobal(_) | &Bytecode::MakeClosure(_, _, _)
+                | &Bytecode::Dup => depth + 1,
+            &Bytecode::Pop(_) | &Bytecode::PopGlobal(_) | &Bytecode::SkipFalse(_) => depth.saturating_sub(1),
+            &Bytecode::PopN(n) => depth.saturating_sub(n),
+            &Bytecode::Swap | &Bytecode::Rot | &Bytecode::Store(_, _) | &Bytecode::NewVarStack
+                | &Bytecode::PopVarStack | &Bytecode::Skip(_) | &Bytecode::MatchList(_, _)
+                | &Bytecode::CheckStack(_) => depth,
+        };
+        max_depth = cmp::max(max_depth, after);
+        let mut targets = Vec::new();
+        match b {
+            &Bytecode::Skip(n) => targets.push(index + 1 + n),
+            &Bytecode::SkipFalse(n) => {
+                targets.push(index + 1);
+                targets.push(index + 1 + n);
+            },
+            _ => targets.push(index + 1),
+        }
+        for target in targets {
+            // skipping past the end just stops
+            let target = cmp::min(target, code.len());
+            depths[target] = Some(cmp::max(depths[target].unwrap_or(0), after));
+        }
+    }
+    max_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::tests::compile;
+    use vm::Value;
+
+    #[test]
+    fn stack_depth_follows_nesting() {
+        assert_eq!(max_stack_depth(&compile("(list 1 (list 2 3))")), 3);
+        assert_eq!(max_stack_depth(&compile("(list 1 2) (list 3)")), 2);
+        assert_eq!(max_stack_depth(&[]), 0);
+    }
+
+    #[test]
+    fn stack_depth_takes_the_deeper_branch() {
+        let code = vec![
+            Bytecode::Push(Value::Boolean(true)),
+            Bytecode::SkipFalse(3),
+            Bytecode::Push(Value::Integer(1)),
+            Bytecode::Push(Value::Integer(2)),
+            Bytecode::Skip(1),
+            Bytecode::Push(Value::Integer(3)),
+        ];
+        assert_eq!(max_stack_depth(&code), 2);
+    }
+
+    #[test]
+    fn closures_and_jumps_are_measured() {
+        let code = compile("(define f (x) (if x (list x x) 0)) (f 1)");
+        let mut stats = Stats::new();
+        stats.add(&code);
+        assert_eq!(stats.closures, 1);
+        assert!(stats.instructions > code.len());
+        assert!(stats.max_jump > 0);
+        stats.add_function(&code);
+        assert_eq!((stats.functions, stats.closures), (1, 2));
+    }
+
+    #[test]
+    fn stats_are_listed_one_per_line() {
+        let stats = Stats { instructions: 10, functions: 2, closures: 1, max_jump: 4, max_stack_depth: 3 };
+        assert_eq!(stats.to_string(),
+                   "instructions: 10\nfunctions compiled: 2\nclosures: 1\nmax jump: 4\nmax stack depth: 3");
+    }
+}
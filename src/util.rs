@@ -1,12 +1,53 @@
-use std;
+use errors::*;
+
 use std::fs::File;
 use std::io::prelude::*;
 
-pub fn read_file(path: &str) -> std::io::Result<String> {
-    let mut source_text = String::new();
+/// Reads a source file as UTF-8 text.
+/// A file that isn't valid UTF-8 (e.g. a binary file) gives a different error from one that
+/// couldn't be read, naming the byte offset of the first invalid sequence.
+pub fn read_file(path: &str) -> Result<String> {
+    let mut bytes = Vec::new();
     {
-        let mut file = try!(File::open(path));
-        try!(file.read_to_string(&mut source_text));
+        let mut file = File::open(path)
+            .chain_err(|| format!("could not read {}", path))?;
+        file.read_to_end(&mut bytes)
+            .chain_err(|| format!("could not read {}", path))?;
+    }
+    match String::from_utf8(bytes) {
+        Ok(source_text) => Ok(source_text),
+        Err(err) => Err(format!("{} is not valid UTF-8 text (invalid byte sequence at offset {})",
+                                path, err.utf8_error().valid_up_to()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::tests::{error_text, temp_path, temp_source};
+
+    #[test]
+    fn reads_utf8_files() {
+        let path = temp_source("utf8.rasp", "(list \"é\" \"\0\")");
+        assert_eq!(read_file(&path).unwrap(), "(list \"é\" \"\0\")");
+    }
+
+    #[test]
+    fn invalid_utf8_is_not_read_error() {
+        let path = temp_path("binary.rasp");
+        File::create(&path)
+            .and_then(|mut file| file.write_all(b"(list 1)\xff\xfe"))
+            .unwrap();
+        let err = read_file(&path)
+            .unwrap_err();
+        assert_eq!(error_text(&err), format!("{} is not valid UTF-8 text (invalid byte sequence at offset 8)", path));
+    }
+
+    #[test]
+    fn missing_file_is_read_error() {
+        let path = temp_path("missing.rasp");
+        let err = read_file(&path)
+            .unwrap_err();
+        assert!(error_text(&err).starts_with(&format!("could not read {}: ", path)), "{}", error_text(&err));
     }
-    Ok(source_text)
 }
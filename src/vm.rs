@@ -1,39 +1,133 @@
 use internal::{FunTable, TypeTable, Function, Type};
 use bytecode::{ToBytecode, Bytecode};
 use errors::*;
-use builtins::BUILTIN_FUNCTIONS;
+use builtins::{self, BUILTIN_FUNCTIONS};
+use lexer::{Lexer, DEFAULT_TAB_WIDTH};
+use parser::Parser;
+use preprocessor::{Preprocessor, DEFAULT_MAX_INCLUDE_DEPTH};
+use util;
+use optimizer;
 
-use std::collections::HashMap;
+use libc::{self, c_int, c_void};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 /// Represents a run-time value
-#[derive(PartialEq, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum Value {
     /// A string value.
     String(String),
     /// A numeric value.
     Number(f64),
+    /// An exact integer value.
+    Integer(i64),
+    /// A single character.
+    Char(char),
     /// An identifier. This may be treated as a reference in the future.
     Identifier(String),
-    /// A list.
-    List(Vec<Value>),
+    /// A list. Lists are shared between the values that hold them, so copying a list is cheap; it's
+    /// only copied for real when one of the sharers takes its items (see `into_list`).
+    List(Rc<Vec<Value>>),
     Boolean(bool),
-    /// A special VM value that delimits the start of a varargs value to a function call.
-    /// The value contains the number of instructions before the EndArgs.
-    StartArgs(i64),
-    /// A special VM value that delimits the end of a varargs value to a function call.
-    EndArgs,
+    /// A named function, either user-defined or builtin, used as a value.
+    Function(String),
+    /// A function defined at runtime, along with the variables it captured.
+    Closure(Rc<Closure>),
 }
 
+/// How deeply nested lists can be before `Value::equals` gives up comparing them.
+pub const MAX_EQUALITY_DEPTH: usize = 1000;
+
 impl Value {
+    /// Compares two values like `==`, but without recursing, so large lists can't overflow the stack.
+    /// Lists of different lengths are unequal without looking at their items. Lists nested more than
+    /// `MAX_EQUALITY_DEPTH` levels deep are an error.
+    pub fn equals(&self, other: &Value) -> Result<bool> {
+        let mut pairs = vec![(self, other, 0)];
+        while let Some((a, b, depth)) = pairs.pop() {
+            match (a, b) {
+                (&Value::List(ref a), &Value::List(ref b)) => {
+                    if a.len() != b.len() {
+                        return Ok(false);
+                    }
+                    if depth >= MAX_EQUALITY_DEPTH {
+                        return Err(format!("lists are nested too deeply to compare (more than {} levels)",
+                                           MAX_EQUALITY_DEPTH).into());
+                    }
+                    // reversed, so items are compared from the front
+                    pairs.extend(a.iter().zip(b.iter()).rev().map(|(a, b)| (a, b, depth + 1)));
+                },
+                (a, b) => if a != b {
+                    return Ok(false);
+                },
+            }
+        }
+        Ok(true)
+    }
+
+    /// Gets whether this value counts as true where a condition is expected, e.g. in `if`, `and`
+    /// and `or`. These are falsy:
+    ///
+    /// * `false`
+    /// * the numbers `0` and `0.0`
+    /// * the empty string and the empty list
+    ///
+    /// Everything else, including any character or function, is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            &Value::Boolean(b) => b,
+            &Value::Number(n) => n != 0.0,
+            &Value::Integer(i) => i != 0,
+            &Value::String(ref s) => !s.is_empty(),
+            &Value::List(ref l) => !l.is_empty(),
+            &Value::Char(_) | &Value::Identifier(_) | &Value::Function(_) | &Value::Closure(_) => true,
+        }
+    }
+
     pub fn type_str(&self) -> &'static str {
         match self {
             &Value::String(_) => "string",
             &Value::List(_) => "list",
             &Value::Number(_) => "number",
+            &Value::Integer(_) => "integer",
+            &Value::Char(_) => "char",
             &Value::Identifier(_) => "identifier",
             &Value::Boolean(_) => "boolean",
-            &Value::StartArgs(_) => "startargs",
-            &Value::EndArgs => "endargs",
+            &Value::Function(_) | &Value::Closure(_) => "function",
+        }
+    }
+
+    /// Gets whether this value is of a type. Numbers of either kind are `:int`s, and `:listy`
+    /// values are strings or lists.
+    pub fn has_type(&self, value_type: &Type, type_table: &TypeTable) -> bool {
+        match value_type {
+            &Type::Any => true,
+            &Type::Number => self.is_number(),
+            &Type::Str => self.is_string(),
+            &Type::Listy => self.is_listy(),
+            &Type::ListOf(ref item_type) => match self {
+                &Value::List(ref l) => l.iter().all(|item| item.has_type(item_type, type_table)),
+                _ => false,
+            },
+            &Type::Record(ref fields) => match self {
+                &Value::List(ref l) => l.len() == fields.len()
+                    && l.iter()
+                        .zip(fields.iter())
+                        .all(|(item, &(_, ref field_type))| item.has_type(field_type, type_table)),
+                _ => false,
+            },
+            &Type::Named(_, ref inner) => self.has_type(inner, type_table),
+            &Type::TypeDef(_, ref target) => match type_table.get_type(target) {
+                Some(target_type) => self.has_type(target_type, type_table),
+                None => false,
+            },
         }
     }
 
@@ -58,9 +152,17 @@ impl Value {
         }
     }
 
+    /// Gets whether this value is numeric; this includes both floats and integers.
     pub fn is_number(&self) -> bool {
         match self {
-            &Value::Number(_) => true,
+            &Value::Number(_) | &Value::Integer(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            &Value::Integer(_) => true,
             _ => false,
         }
     }
@@ -74,9 +176,16 @@ impl Value {
     }
     */
 
+    /// Makes a list value out of some items.
+    pub fn new_list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(items))
+    }
+
+    /// Takes the items of a list value. If the list is shared with other values, the items are
+    /// copied, so changing them doesn't change the other values.
     pub fn into_list(self) -> Vec<Value> {
         match self {
-            Value::List(v) => v,
+            Value::List(v) => Rc::try_unwrap(v).unwrap_or_else(|v| (*v).clone()),
             _ => panic!("called into_list() on non-List vm::Value"),
         }
     }
@@ -88,65 +197,571 @@ impl Value {
         }
     }
 
+    /// Gets the value of a numeric value as a float, converting integers if necessary.
     pub fn number(&self) -> f64 {
         match self {
             &Value::Number(n) => n,
+            &Value::Integer(i) => i as f64,
             _ => panic!("called number() on non-Number vm::Value"),
         }
     }
-    
-    pub fn start_args(&self) -> i64 {
+
+    pub fn integer(&self) -> i64 {
         match self {
-            &Value::StartArgs(n) => n,
-            _ => panic!("called start_args() on non-StartArgs vm::Value"),
+            &Value::Integer(i) => i,
+            _ => panic!("called integer() on non-Integer vm::Value"),
         }
     }
 
-    /*
-    pub fn is_start_args(&self) -> bool {
+    pub fn is_char(&self) -> bool {
         match self {
-            &Value::StartArgs(_) => true,
+            &Value::Char(_) => true,
             _ => false,
         }
     }
-    */
-    
-    pub fn is_end_args(&self) -> bool {
+
+    pub fn char(&self) -> char {
         match self {
-            &Value::EndArgs => true,
+            &Value::Char(c) => c,
+            _ => panic!("called char() on non-Char vm::Value"),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    /// Structural equality; integers and floats are compared by their numeric value.
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&Value::String(ref a), &Value::String(ref b)) => a == b,
+            (&Value::Integer(a), &Value::Integer(b)) => a == b,
+            (&Value::Number(_), &Value::Number(_)) | (&Value::Number(_), &Value::Integer(_))
+            | (&Value::Integer(_), &Value::Number(_)) => self.number() == other.number(),
+            (&Value::Char(a), &Value::Char(b)) => a == b,
+            (&Value::Identifier(ref a), &Value::Identifier(ref b)) => a == b,
+            (&Value::List(ref a), &Value::List(ref b)) => a == b,
+            (&Value::Boolean(a), &Value::Boolean(b)) => a == b,
+            (&Value::Function(ref a), &Value::Function(ref b)) => a == b,
+            // closures are only equal to themselves
+            (&Value::Closure(ref a), &Value::Closure(ref b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 }
 
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Integer(i)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<char> for Value {
+    fn from(c: char) -> Value {
+        Value::Char(c)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Boolean(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Value {
+        Value::new_list(l)
+    }
+}
+
+/// Makes the error for converting a value into a Rust type that it doesn't hold.
+fn conversion_error(value: &Value, expected: &str) -> Error {
+    format!("expected {} value, but instead got {}", expected, value.type_str()).into()
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = Error;
+
+    /// Gets the value of a number; integers are converted to floats.
+    fn try_from(value: Value) -> Result<f64> {
+        if value.is_number() {
+            Ok(value.number())
+        }
+        else {
+            Err(conversion_error(&value, "a number"))
+        }
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<i64> {
+        match value {
+            Value::Integer(i) => Ok(i),
+            v => Err(conversion_error(&v, "an integer")),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<String> {
+        match value {
+            Value::String(s) => Ok(s),
+            v => Err(conversion_error(&v, "a string")),
+        }
+    }
+}
+
+impl TryFrom<Value> for char {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<char> {
+        match value {
+            Value::Char(c) => Ok(c),
+            v => Err(conversion_error(&v, "a char")),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<bool> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            v => Err(conversion_error(&v, "a boolean")),
+        }
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Vec<Value>> {
+        match value {
+            l @ Value::List(_) => Ok(l.into_list()),
+            v => Err(conversion_error(&v, "a list")),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Writes this value the way it would be written in source.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Value::String(ref s) => write!(f, "{:?}", s),
+            &Value::Number(n) => match non_finite_name(n) {
+                Some(name) => write!(f, "{}", name),
+                // debug formatting keeps the decimal point on whole floats
+                None => write!(f, "{:?}", n),
+            },
+            &Value::Integer(i) => write!(f, "{}", i),
+            &Value::Char(c) => write!(f, "{:?}", c),
+            &Value::Identifier(ref s) => write!(f, "{}", s),
+            &Value::List(ref l) => {
+                write!(f, "(")?;
+                for (index, value) in l.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, ")")
+            },
+            &Value::Boolean(b) => write!(f, "{}", b),
+            &Value::Function(ref name) => write!(f, "<function {}>", name),
+            &Value::Closure(ref closure) => write!(f, "<function {}>", closure.name),
+        }
+    }
+}
+
+/// Gets how a float that isn't finite is written: `inf`, `-inf` or `nan`.
+/// This is the same however the float is printed, and whatever the sign of a NaN is.
+pub fn non_finite_name(n: f64) -> Option<&'static str> {
+    if n.is_nan() {
+        Some("nan")
+    }
+    else if n.is_infinite() {
+        Some(if n > 0.0 { "inf" } else { "-inf" })
+    }
+    else {
+        None
+    }
+}
+
+/// How the VM reports the instructions that it runs, for debugging.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StepMode {
+    /// Instructions are not reported.
+    Off,
+    /// Each instruction is printed to stderr with the state of the VM before it runs.
+    Trace,
+    /// Like `Trace`, but waits for a line of input (see `VM::set_step_input`) before running each
+    /// instruction.
+    Pause,
+}
+
+/// Reads a line of input for the debugger in `Pause` step mode, giving `None` at the end of input.
+pub type StepInput = Box<dyn FnMut() -> io::Result<Option<String>>>;
+
+/// Reads a line without its line ending, giving `None` at the end of input.
+pub fn read_line<R: io::BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// A function defined at runtime with the `define` special form.
+#[derive(Debug)]
+pub struct Closure {
+    pub name: String,
+    params: Vec<String>,
+    body: Vec<Bytecode>,
+    /// The variables that were visible where the function was defined
+    captured: VarTable,
+}
+
 type ValueStack = Vec<Value>;
 type VarTable = HashMap<String, Value>;
 
+/// Gets the variables in a table, sorted by name so that they're always listed in the same order.
+fn sorted_vars(table: &VarTable) -> Vec<(&String, &Value)> {
+    let mut vars = table.iter()
+        .collect::<Vec<_>>();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    vars
+}
+
+/// A running count of how much memory the values in a VM take up, for the memory limit. A value's
+/// size is the bytes of its strings and the items of its lists (including nested ones), and a
+/// closure's size is the size of what it captured. Lists and closures are shared between the values
+/// that hold them, so each one is only counted once, for as long as anything holds it.
+/// Values are added when they're pushed or put in a variable, and removed when they're popped or
+/// their variable goes away. Nothing is counted when there's no limit.
+#[derive(Default)]
+struct MemoryUse {
+    /// Whether values are being counted
+    enabled: bool,
+    /// Total size of the values that have been added and not removed
+    total: usize,
+    /// How many of the added values hold each shared list or closure, by its address
+    holders: HashMap<usize, usize>,
+}
+
+impl MemoryUse {
+    /// Counts a value that was pushed or put in a variable.
+    fn add(&mut self, value: &Value) {
+        if !self.enabled {
+            return;
+        }
+        let mut values = vec![value];
+        while let Some(value) = values.pop() {
+            match value {
+                &Value::String(ref s) | &Value::Identifier(ref s) => self.total += s.len(),
+                &Value::List(ref l) => if self.hold(&**l as *const Vec<Value> as usize) {
+                    self.total += l.len();
+                    values.extend(l.iter());
+                },
+                &Value::Closure(ref closure) => if self.hold(&**closure as *const Closure as usize) {
+                    values.extend(closure.captured.values());
+                },
+                _ => { },
+            }
+        }
+    }
+
+    /// Stops counting a value that was popped, or whose variable went away.
+    fn remove(&mut self, value: &Value) {
+        if !self.enabled {
+            return;
+        }
+        let mut values = vec![value];
+        while let Some(value) = values.pop() {
+            match value {
+                &Value::String(ref s) | &Value::Identifier(ref s) => self.total -= s.len(),
+                &Value::List(ref l) => if self.release(&**l as *const Vec<Value> as usize) {
+                    self.total -= l.len();
+                    values.extend(l.iter());
+                },
+                &Value::Closure(ref closure) => if self.release(&**closure as *const Closure as usize) {
+                    values.extend(closure.captured.values());
+                },
+                _ => { },
+            }
+        }
+    }
+
+    fn add_vars(&mut self, table: &VarTable) {
+        for value in table.values() {
+            self.add(value);
+        }
+    }
+
+    fn remove_vars(&mut self, table: &VarTable) {
+        for value in table.values() {
+            self.remove(value);
+        }
+    }
+
+    /// Counts another holder of a shared value, giving whether it's the first one.
+    fn hold(&mut self, address: usize) -> bool {
+        let holders = self.holders
+            .entry(address)
+            .or_insert(0);
+        *holders += 1;
+        *holders == 1
+    }
+
+    /// Stops counting a holder of a shared value, giving whether it was the last one.
+    fn release(&mut self, address: usize) -> bool {
+        let holders = self.holders
+            .get_mut(&address)
+            .expect("released a shared value that wasn't being held");
+        *holders -= 1;
+        if *holders == 0 {
+            self.holders.remove(&address);
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+/// Number of bytes that may be buffered for a file descriptor before they're written out.
+const WRITE_BUFFER_SIZE: usize = 8192;
+
+/// Writes all of some bytes to a file descriptor, with as many `write` calls as it takes. Gives the
+/// result of the last call, which is negative if writing failed.
+fn write_all(fd: c_int, bytes: &[u8]) -> isize {
+    let mut written = 0;
+    loop {
+        let result = unsafe {
+            libc::write(fd, bytes[written ..].as_ptr() as *const c_void, bytes.len() - written)
+        };
+        if result < 0 {
+            return result;
+        }
+        written += result as usize;
+        if written >= bytes.len() {
+            return result;
+        }
+    }
+}
+
+/// A function that a name was resolved to when it was first called.
+#[derive(Clone)]
+enum CallTarget {
+    /// A user-defined function, along with its compiled body
+    User(Rc<Function>, Rc<Vec<Bytecode>>),
+    Builtin(fn(&mut VM) -> Result<()>),
+}
+
 /// Represents a RASP virtual machine that runs bytecode.
 pub struct VM {
     var_stack: Vec<VarTable>,
+    /// Variables made by a top-level `def`, which every scope can see, and which last between runs
+    globals: VarTable,
     value_stack: ValueStack,
     fun_table: FunTable,
     type_table: TypeTable,
-    /// Cache of functions' compiled Bytecode
-    fun_bytecode: HashMap<String, Vec<Bytecode>>,
+    /// What function names have been resolved to, so calls only look them up once
+    call_targets: HashMap<String, CallTarget>,
     fun_stack: Vec<String>,
+    /// Maximum number of instructions that may be executed, if any
+    max_steps: Option<usize>,
+    /// Maximum total size of the values on the stack and in variables, if any
+    max_memory: Option<usize>,
+    /// How much memory the values on the stack and in variables take up, when there's a limit
+    memory: MemoryUse,
+    /// Maximum number of nested includes in loaded code
+    max_include_depth: usize,
+    /// How instructions are reported as they run
+    step_mode: StepMode,
+    /// Where the debugger reads its input from in `Pause` step mode
+    step_input: StepInput,
+    /// Whether compiled code is run through the peephole optimizer
+    optimize: bool,
+    /// Number of columns that a tab takes up in loaded source code
+    tab_width: usize,
+    /// Number of arguments that the running builtin function was called with
+    arg_count: usize,
+    /// Number of instructions executed so far
+    steps: usize,
+    /// Files that have been loaded at runtime
+    loaded_files: HashSet<PathBuf>,
+    /// Files that are in the middle of being loaded at runtime
+    loading_files: Vec<PathBuf>,
+    /// Whether writes to file descriptors are buffered
+    buffer_writes: bool,
+    /// Bytes that have been written to each file descriptor, but not flushed yet
+    write_buffers: HashMap<c_int, Vec<u8>>,
 }
 
 impl VM {
     pub fn new(fun_table: FunTable, type_table: TypeTable) -> VM {
         VM {
             var_stack: Vec::new(),
+            globals: VarTable::new(),
             value_stack: ValueStack::new(),
             fun_table: fun_table,
             type_table: type_table,
-            fun_bytecode: HashMap::new(),
+            call_targets: HashMap::new(),
             fun_stack: Vec::new(),
+            max_steps: None,
+            max_memory: None,
+            memory: MemoryUse::default(),
+            max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
+            step_mode: StepMode::Off,
+            step_input: Box::new(|| read_line(&mut io::stdin().lock())),
+            optimize: false,
+            tab_width: DEFAULT_TAB_WIDTH as usize,
+            arg_count: 0,
+            steps: 0,
+            loaded_files: HashSet::new(),
+            loading_files: Vec::new(),
+            buffer_writes: false,
+            write_buffers: HashMap::new(),
         }
     }
 
+    /// Sets the maximum number of instructions this VM will execute before giving up. The count
+    /// starts over for each source passed to `eval`, so e.g. every REPL input gets the whole limit.
+    /// `None` means there is no limit.
+    pub fn set_max_steps(&mut self, max_steps: Option<usize>) {
+        self.max_steps = max_steps;
+    }
+
+    /// Sets the maximum total size of values (see `MemoryUse`) that may be on the stack and in
+    /// variables at once, including the variables of callers. `None` means there is no limit.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.max_memory = max_memory;
+        self.recount_memory();
+    }
+
+    /// Counts the memory used by everything on the stack and in variables from scratch.
+    fn recount_memory(&mut self) {
+        self.memory = MemoryUse::default();
+        self.memory.enabled = self.max_memory.is_some();
+        for value in &self.value_stack {
+            self.memory.add(value);
+        }
+        for table in &self.var_stack {
+            self.memory.add_vars(table);
+        }
+        self.memory.add_vars(&self.globals);
+    }
+
+    /// Sets how this VM reports the instructions that it runs.
+    pub fn set_step_mode(&mut self, step_mode: StepMode) {
+        self.step_mode = step_mode;
+    }
+
+    /// Sets where the debugger reads its input from in `Pause` step mode, which is stdin by default.
+    /// Anything else that reads stdin while the VM runs, like the REPL, should read it through the
+    /// same reader, or the two will deadlock over it.
+    pub fn set_step_input(&mut self, step_input: StepInput) {
+        self.step_input = step_input;
+    }
+
+    /// Sets whether code that this VM compiles is run through the peephole optimizer.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Sets whether writes to file descriptors are buffered, rather than written out right away.
+    /// Buffered bytes are written out when the buffer fills up, when the file descriptor is flushed
+    /// or closed, and when the VM is dropped.
+    pub fn set_buffer_writes(&mut self, buffer_writes: bool) {
+        self.buffer_writes = buffer_writes;
+    }
+
+    /// Writes bytes to a file descriptor, or buffers them if writes are buffered. Gives the result
+    /// of `write`; buffered bytes count as written.
+    pub fn write_fd(&mut self, fd: c_int, bytes: &[u8]) -> isize {
+        if !self.buffer_writes {
+            return unsafe {
+                libc::write(fd, bytes.as_ptr() as *const c_void, bytes.len())
+            };
+        }
+        let full = {
+            let buffer = self.write_buffers
+                .entry(fd)
+                .or_insert_with(Vec::new);
+            buffer.extend_from_slice(bytes);
+            buffer.len() >= WRITE_BUFFER_SIZE
+        };
+        if full {
+            let result = self.flush_fd(fd);
+            if result < 0 {
+                return result;
+            }
+        }
+        bytes.len() as isize
+    }
+
+    /// Writes out any buffered bytes for a file descriptor. Gives the result of the last `write`, or
+    /// 0 if nothing was buffered.
+    pub fn flush_fd(&mut self, fd: c_int) -> isize {
+        match self.write_buffers.remove(&fd) {
+            Some(ref buffer) if !buffer.is_empty() => write_all(fd, buffer),
+            _ => 0,
+        }
+    }
+
+    /// Writes out the buffered bytes for every file descriptor.
+    pub fn flush_writes(&mut self) {
+        let fds = self.write_buffers
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for fd in fds {
+            self.flush_fd(fd);
+        }
+    }
+
+    /// Sets the number of columns that a tab takes up in source code that this VM loads, for
+    /// reporting positions.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width;
+    }
+
+    /// Sets the maximum number of nested includes in code that this VM loads.
+    pub fn set_max_include_depth(&mut self, max_include_depth: usize) {
+        self.max_include_depth = max_include_depth;
+    }
+
     pub fn run(&mut self, bytecode: &Vec<Bytecode>) -> Result<()>{
         let mut skip = 0usize;
+        // how many values were on the stack after the last checked statement
+        let mut statement_depth = self.value_stack.len();
         self.var_stack
             .push(VarTable::new());
         for b in bytecode {
@@ -155,72 +770,59 @@ impl VM {
                 trace!("skipping {:?}", b);
                 continue;
             }
+            self.steps += 1;
+            if let Some(max_steps) = self.max_steps {
+                if self.steps > max_steps {
+                    return Err("execution step limit exceeded".into());
+                }
+            }
             trace!("executing {:?}", b);
             trace!("value stack: {:?}", self.value_stack);
+            if self.step_mode != StepMode::Off {
+                self.report_step(b)?;
+            }
             match b {
-                &Bytecode::Call(ref fname, arg_count) => {
-                    if self.has_function(fname) {
-                        if !self.has_compiled_function(fname) {
-                            let fun = self.fun_table
-                                .get_fun(fname)
-                                .unwrap();
-                            let bytecode_result = self.compile_function(fun);
-                            if let Ok(bytecode) = bytecode_result {
-                                self.fun_bytecode
-                                    .insert(fname.to_string(), bytecode);
-                            }
-                            else {
-                                bytecode_result.chain_err(|| "failure to compile function")?;
+                &Bytecode::Call(ref fname, arg_count) => self.call_function(fname, arg_count)?,
+                &Bytecode::CallValue(arg_count) => match self.pop_value() {
+                    Value::Function(fname) => {
+                        // calls through values aren't checked at compile time, so check them here
+                        if let Some(fun) = self.fun_table.get_fun(&fname) {
+                            let min_args = fun.params
+                                .iter()
+                                .take_while(|p| !p.optional)
+                                .count();
+                            let max_args = fun.params.len();
+                            if arg_count < min_args || arg_count > max_args {
+                                return Err(format!("function {} takes {} to {} arguments, but was called with {}",
+                                                   fname, min_args, max_args, arg_count).into());
                             }
                         }
-                        let bytecode = self.fun_bytecode
-                            .get(fname)
-                            .unwrap()
-                            .clone();
-                        self.fun_stack.push(fname.to_string());
-                        let fun = self.fun_table
-                            .get_fun(fname)
-                            .unwrap()
-                            .clone();
-                        debug!("popping {} args", arg_count);
-                        for arg_index in 0 .. arg_count {
-                            trace!("popping arg {}", arg_index + 1);
-                            let arg = self.pop_value();
-                            let ref param_name = fun.params[arg_index]
-                                .name;
-                            self.set_var(param_name, &arg);
-                        }
-                        let extras = fun.params.len() - arg_count;
-                        for arg_index in 0 .. extras {
-                            let ref param = fun.params[extras + arg_index];
-                            let default_value = {
-                                let base_type = self.type_table
-                                    .get_type(param.param_type.name())
-                                    .expect("could not get type that was retrieved from a function");
-                                match base_type {
-                                    &Type::Number => Value::Number(0.0),
-                                    &Type::Str => Value::String(String::new()),
-                                    &Type::Any | &Type::Listy => Value::List(vec![]),
-                                    &Type::TypeDef(_, _) => panic!("Reached typedef as base type when deducing default values"),
-                                }
-                            };
-                            self.set_var(&param.name, &default_value);
+                        self.call_function(&fname, arg_count)?;
+                    },
+                    Value::Closure(closure) => self.call_closure(closure, arg_count)?,
+                    v => return Err(format!("attempt to call {} value as a function", v.type_str()).into()),
+                },
+                &Bytecode::MakeClosure(ref name, ref params, ref body) => {
+                    // capture everything that's visible, with inner scopes taking priority
+                    let mut captured = VarTable::new();
+                    for vartable in &self.var_stack {
+                        for (key, value) in vartable {
+                            captured.insert(key.clone(), value.clone());
                         }
-
-                        // TODO: extra error message
-                        self.run(&bytecode)?;
-                        self.fun_stack.pop();
-                    }
-                    else if BUILTIN_FUNCTIONS.contains_key(fname.as_str()) {
-                        self.fun_stack.push(fname.to_string());
-                        let builtin = BUILTIN_FUNCTIONS.get(fname.as_str())
-                            .unwrap();
-                        builtin(self)?;
-                        self.fun_stack.pop();
                     }
-                    else {
+                    let closure = Closure {
+                        name: name.clone(),
+                        params: params.clone(),
+                        body: body.clone(),
+                        captured: captured,
+                    };
+                    self.push(Value::Closure(Rc::new(closure)));
+                },
+                &Bytecode::LoadGlobal(ref fname) => {
+                    if !self.has_function(fname) && !BUILTIN_FUNCTIONS.contains_key(fname.as_str()) {
                         return Err(format!("unknown function {}", fname).into());
                     }
+                    self.push(Value::Function(fname.clone()));
                 },
                 &Bytecode::Push(ref value) => match value {
                     // TODO(alek): references
@@ -231,52 +833,230 @@ impl VM {
                                 None => return Err(format!("unknown identifier {}", name).into()),
                             }
                         };
-                        self.value_stack
-                            .push(value);
+                        self.push(value);
                     },
-                    v => self.value_stack
-                            .push(v.clone()),
+                    // plain values are rebuilt directly; only the ones that own memory are cloned (and
+                    // counted towards the memory limit)
+                    &Value::Integer(i) => self.value_stack
+                            .push(Value::Integer(i)),
+                    &Value::Number(n) => self.value_stack
+                            .push(Value::Number(n)),
+                    &Value::Boolean(b) => self.value_stack
+                            .push(Value::Boolean(b)),
+                    &Value::Char(c) => self.value_stack
+                            .push(Value::Char(c)),
+                    v => self.push(v.clone()),
                 },
                 &Bytecode::Pop(ref name) => {
-                    let value = self.value_stack
-                        .pop()
-                        .expect("attempted to pop a value off of an empty stack");
-                    self.set_var(name, &value);
+                    // the value moves into the variable, so it's still counted
+                    let value = self.move_value();
+                    self.insert_var(name, value);
+                },
+                &Bytecode::PopGlobal(ref name) => {
+                    let value = self.move_value();
+                    if let Some(replaced) = self.globals.insert(name.clone(), value) {
+                        self.memory.remove(&replaced);
+                    }
+                },
+                &Bytecode::PopN(n) => {
+                    if n > self.value_stack.len() {
+                        return Err(format!("VM error: attempted to pop {} values off of a stack of {}", n, self.value_stack.len()).into());
+                    }
+                    let new_len = self.value_stack.len() - n;
+                    for value in self.value_stack.drain(new_len ..) {
+                        self.memory.remove(&value);
+                    }
+                },
+                &Bytecode::Dup => {
+                    let value = match self.value_stack.last() {
+                        Some(value) => value.clone(),
+                        None => return Err("VM error: attempted to duplicate a value on an empty stack".into()),
+                    };
+                    self.push(value);
+                },
+                &Bytecode::Swap => {
+                    let len = self.value_stack.len();
+                    if len < 2 {
+                        return Err(format!("VM error: attempted to swap values on a stack of {}", len).into());
+                    }
+                    self.value_stack.swap(len - 1, len - 2);
+                },
+                &Bytecode::Rot => {
+                    let len = self.value_stack.len();
+                    if len < 3 {
+                        return Err(format!("VM error: attempted to rotate values on a stack of {}", len).into());
+                    }
+                    let value = self.value_stack.remove(len - 3);
+                    self.value_stack.push(value);
                 },
                 &Bytecode::Load(ref name) => {
                     let value = match self.get_var(name) {
                         Some(value) => value,
                         None => return Err(format!("unknown variable or function name: {}", name).into()),
                     }.clone();
-                    self.value_stack.push(value);
+                    self.push(value);
+                },
+                &Bytecode::Store(ref name, ref value) => {
+                    self.memory.add(value);
+                    self.insert_var(name, value.clone());
                 },
-                &Bytecode::Store(ref name, ref value) => self.set_var(name, value),
                 &Bytecode::NewVarStack => self.var_stack.push(VarTable::new()),
                 &Bytecode::PopVarStack => { 
-                    self.var_stack.pop()
+                    let vars = self.var_stack.pop()
                         .expect("tried to pop variable table stack but there was nothing on the stack");
+                    self.memory.remove_vars(&vars);
                 },
                 &Bytecode::Skip(n) => skip = n,
                 &Bytecode::SkipFalse(n) => match self.pop_value() {
-                    Value::Number(num) => if num == 0.0 {
-                        skip = n;
-                    },
-                    Value::String(s) => if s.len() == 0 {
+                    e @ Value::Identifier(_) => return Err(format!("VM error: invalid boolean value reached (got {:?})", e).into()),
+                    value => if !value.is_truthy() {
                         skip = n;
                     },
-                    Value::List(l) => if l.len() == 0 {
-                        skip = n;
-                    },
-                    Value::Boolean(t) => if !t {
-                        skip = n;
-                    },
-                    e => return Err(format!("VM error: invalid boolean value reached (got {:?})", e).into()),
                 },
+                &Bytecode::MatchList(len, at_least) => {
+                    let matches = match self.pop_value() {
+                        Value::List(ref l) => if at_least { l.len() >= len } else { l.len() == len },
+                        _ => false,
+                    };
+                    self.push(Value::Boolean(matches));
+                },
+                &Bytecode::CheckStack(ref statement) => {
+                    let depth = self.value_stack.len();
+                    if depth < statement_depth || depth > statement_depth + 1 {
+                        return Err(format!("VM error: {} changed the stack by {} values, but should leave at most its result",
+                                           statement, depth as i64 - statement_depth as i64).into());
+                    }
+                    statement_depth = depth;
+                },
+            }
+            if let Some(max_memory) = self.max_memory {
+                if self.memory.total > max_memory {
+                    return Err(format!("memory limit exceeded (values take up {}, but the limit is {})",
+                                       self.memory.total, max_memory).into());
+                }
             }
         }
-        self.var_stack
+        let vars = self.var_stack
             .pop()
             .unwrap();
+        self.memory.remove_vars(&vars);
+        Ok(())
+    }
+
+    /// Calls a function by name, with the given number of arguments on top of the stack.
+    /// The function that a name refers to is only looked up (and compiled) the first time it's
+    /// called; later calls use the cached target.
+    fn call_function(&mut self, fname: &str, arg_count: usize) -> Result<()> {
+        let target = match self.call_targets.get(fname) {
+            Some(target) => target.clone(),
+            None => {
+                let target = self.resolve_call(fname)?;
+                self.call_targets
+                    .insert(fname.to_string(), target.clone());
+                target
+            },
+        };
+        match target {
+            CallTarget::User(fun, bytecode) => {
+                self.fun_stack.push(fname.to_string());
+                // function bodies get a fresh scope that only contains their parameters, so
+                // they can't see (or clobber) any of the caller's variables
+                let mut scope = VarTable::new();
+                debug!("popping {} args", arg_count);
+                // arguments were pushed in order, so the last argument is on top
+                for arg_index in (0 .. arg_count).rev() {
+                    trace!("popping arg {}", arg_index + 1);
+                    // arguments move into the function's scope, so they're still counted
+                    let arg = self.move_value();
+                    scope.insert(fun.params[arg_index].name.clone(), arg);
+                }
+                for param in fun.params.iter().skip(arg_count) {
+                    let default_value = {
+                        let base_type = self.type_table
+                            .get_type(param.param_type.name())
+                            .unwrap_or(&param.param_type);
+                        match base_type {
+                            &Type::Number => Value::Integer(0),
+                            &Type::Str => Value::String(String::new()),
+                            &Type::Any | &Type::Listy | &Type::ListOf(_) | &Type::Record(_) | &Type::Named(_, _) =>
+                                Value::new_list(vec![]),
+                            &Type::TypeDef(_, _) => panic!("Reached typedef as base type when deducing default values"),
+                        }
+                    };
+                    self.memory.add(&default_value);
+                    scope.insert(param.name.clone(), default_value);
+                }
+
+                // the function's value goes above what's on the stack now
+                let depth = self.value_stack.len();
+                // the caller's variables are still counted while they're swapped out
+                let caller_vars = mem::replace(&mut self.var_stack, vec![scope]);
+                // TODO: extra error message
+                let run_result = self.run(&bytecode);
+                self.restore_vars(caller_vars);
+                run_result?;
+                if let Some(ref return_type) = fun.return_type {
+                    match self.value_stack.get(depth) {
+                        Some(value) if !value.has_type(return_type, &self.type_table) =>
+                            return Err(format!("function {} should give a value of type {}, but gave a {}",
+                                               fname, return_type, value.type_str()).into()),
+                        Some(_) => { },
+                        None => return Err(format!("function {} should give a value of type {}, but gave nothing",
+                                                   fname, return_type).into()),
+                    }
+                }
+                self.fun_stack.pop();
+            },
+            CallTarget::Builtin(builtin) => {
+                builtins::check_arity(fname, arg_count)?;
+                self.fun_stack.push(fname.to_string());
+                self.arg_count = arg_count;
+                builtin(self)?;
+                self.fun_stack.pop();
+            },
+        }
+        Ok(())
+    }
+
+    /// Finds the function that a name refers to, compiling it if it's a user-defined function.
+    /// User-defined functions shadow builtins of the same name.
+    fn resolve_call(&self, fname: &str) -> Result<CallTarget> {
+        if let Some(fun) = self.fun_table.get_fun(fname) {
+            let bytecode = self.compile_function(fun)
+                .chain_err(|| "failure to compile function")?;
+            Ok(CallTarget::User(Rc::new(fun.clone()), Rc::new(bytecode)))
+        }
+        else if let Some(builtin) = BUILTIN_FUNCTIONS.get(fname) {
+            Ok(CallTarget::Builtin(*builtin))
+        }
+        else {
+            Err(format!("unknown function {}", fname).into())
+        }
+    }
+
+    /// Calls a closure, with the given number of arguments on top of the stack.
+    fn call_closure(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<()> {
+        if arg_count != closure.params.len() {
+            return Err(format!("function {} takes {} arguments, but was called with {}",
+                               closure.name, closure.params.len(), arg_count).into());
+        }
+        self.fun_stack.push(closure.name.clone());
+        // the closure's scope has what it captured, itself (so it can recurse), and its parameters
+        let mut scope = closure.captured.clone();
+        scope.insert(closure.name.clone(), Value::Closure(closure.clone()));
+        self.memory.add_vars(&scope);
+        for param in closure.params.iter().rev() {
+            let arg = self.move_value();
+            if let Some(captured) = scope.insert(param.clone(), arg) {
+                self.memory.remove(&captured);
+            }
+        }
+
+        let caller_vars = mem::replace(&mut self.var_stack, vec![scope]);
+        let run_result = self.run(&closure.body);
+        self.restore_vars(caller_vars);
+        run_result?;
+        self.fun_stack.pop();
         Ok(())
     }
 
@@ -289,11 +1069,20 @@ impl VM {
     }
 
     pub fn push(&mut self, value: Value) {
+        self.memory.add(&value);
         self.value_stack
             .push(value);
     }
 
     pub fn pop_value(&mut self) -> Value {
+        let value = self.move_value();
+        self.memory.remove(&value);
+        value
+    }
+
+    /// Pops a value that's moving somewhere else in this VM, e.g. into a variable, so it's still
+    /// counted towards the memory limit.
+    fn move_value(&mut self) -> Value {
         if self.value_stack.len() == 0 {
             // we know a crash is going to happen
             self.dump_debug();
@@ -303,13 +1092,63 @@ impl VM {
             .expect("attempted to pop a value off of an empty value stack")
     }
 
+    /// Gets the value on top of the stack, if there is one.
+    /// After running a program, this is the value of its last expression.
     pub fn peek_value(&self) -> Option<&Value> {
-        if self.value_stack.len() == 0 {
-            None
+        self.value_stack
+            .last()
+    }
+
+    /// Gets the number of values on the stack.
+    pub fn stack_len(&self) -> usize {
+        self.value_stack
+            .len()
+    }
+
+    /// Gets the number of variable scopes that are active.
+    pub fn var_depth(&self) -> usize {
+        self.var_stack
+            .len()
+    }
+
+    /// Gets the names that start with a prefix and could be completed to, for tab completion: the
+    /// defined functions and aliases, the builtin functions, and the variables in scope (including
+    /// globals). The names are sorted, without duplicates.
+    pub fn completions(&self, prefix: &str) -> Vec<String> {
+        let mut names = self.fun_table
+            .names_with_prefix(prefix)
+            .into_iter()
+            .chain(BUILTIN_FUNCTIONS.keys().cloned().filter(|name| name.starts_with(prefix)))
+            .chain(self.var_stack
+                   .iter()
+                   .chain(Some(&self.globals))
+                   .flat_map(|vars| vars.keys())
+                   .map(|name| name.as_str())
+                   .filter(|name| name.starts_with(prefix)))
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Pops the given number of values off of the stack, in the order that they were pushed.
+    pub fn pop_values(&mut self, count: usize) -> Result<Vec<Value>> {
+        let len = self.value_stack.len();
+        if count > len {
+            return Err(format!("VM error: attempted to pop {} values off of a stack of {}", count, len).into());
         }
-        else {
-            Some(&self.value_stack[self.value_stack.len() - 1])
+        let values = self.value_stack.split_off(len - count);
+        for value in &values {
+            self.memory.remove(value);
         }
+        Ok(values)
+    }
+
+    /// Gets the number of arguments that the running builtin function was called with.
+    /// This is only meaningful before the builtin calls any other functions.
+    pub fn arg_count(&self) -> usize {
+        self.arg_count
     }
 
     pub fn dump_debug(&self) {
@@ -327,7 +1166,7 @@ impl VM {
         for table in &self.var_stack {
             let mut table_count = table.len();
             debug!("{:02}. Var table", count);
-            for (key, value) in table {
+            for (key, value) in sorted_vars(table) {
                 debug!("   {:02}. {} -> {:?}", table_count, key, value); 
                 table_count -= 1;
             }
@@ -336,20 +1175,155 @@ impl VM {
         }
     }
 
+    /// Prints an instruction that's about to run, along with the value stack and the variables in
+    /// the innermost scope, all on one line. The whole state of the VM also goes to the debug log.
+    /// In `Pause` mode, this then waits for the user to either step to the next instruction, or
+    /// continue without pausing.
+    fn report_step(&mut self, b: &Bytecode) -> Result<()> {
+        self.dump_debug();
+        let stack = self.value_stack
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let vars = match self.var_stack.last() {
+            Some(table) => sorted_vars(table)
+                .into_iter()
+                .map(|(key, value)| format!("{} = {}", key, value))
+                .collect::<Vec<String>>(),
+            None => Vec::new(),
+        };
+        eprintln!("{:>6}. {:<32} stack: ({}) vars: {{{}}}", self.steps, format!("{:?}", b), stack, vars.join(", "));
+
+        if self.step_mode == StepMode::Pause {
+            eprint!("[s]tep or [c]ontinue? ");
+            let line = (self.step_input)()
+                .chain_err(|| "could not read debugger input")?;
+            // stop pausing on "continue", or when there's no more input
+            match line {
+                Some(ref line) if line.trim() != "c" && line.trim() != "continue" => { },
+                _ => self.step_mode = StepMode::Trace,
+            }
+        }
+        Ok(())
+    }
+
     fn get_var(&self, name: &str) -> Option<&Value> {
         for vartable in self.var_stack.iter().rev() {
             if vartable.contains_key(name) {
                 return vartable.get(name);
             }
         }
-        None
+        self.globals
+            .get(name)
     }
 
-    fn set_var(&mut self, name: &str, value: &Value) {
-        self.var_stack
+    /// Puts a value that's already counted towards the memory limit in a variable in the innermost
+    /// scope, replacing any value that the variable had.
+    fn insert_var(&mut self, name: &str, value: Value) {
+        let replaced = self.var_stack
             .last_mut()
             .unwrap()
-            .insert(name.to_string(), value.clone());
+            .insert(name.to_string(), value);
+        if let Some(replaced) = replaced {
+            self.memory.remove(&replaced);
+        }
+    }
+
+    /// Puts back the variables of a caller, after a call has run with its own.
+    fn restore_vars(&mut self, caller_vars: Vec<VarTable>) {
+        let vars = mem::replace(&mut self.var_stack, caller_vars);
+        for table in &vars {
+            self.memory.remove_vars(table);
+        }
+    }
+
+    /// Compiles and runs a file, adding its functions and types to this VM.
+    /// Returns false if the file had already been loaded, in which case nothing is done.
+    pub fn load_file(&mut self, path: &str) -> Result<bool> {
+        let full_path = fs::canonicalize(path)
+            .chain_err(|| format!("could not load {}", path))?;
+        if self.loading_files.contains(&full_path) {
+            return Err(format!("recursive load of {}", path).into());
+        }
+        else if self.loaded_files.contains(&full_path) {
+            return Ok(false);
+        }
+
+        self.loading_files.push(full_path.clone());
+        let load_result = self.load_file_unchecked(path);
+        self.loading_files.pop();
+        match load_result {
+            // exiting isn't an error in the loaded file, so pass it along untouched
+            Err(Error(ErrorKind::Exit(status), state)) => return Err(Error(ErrorKind::Exit(status), state)),
+            r => r.chain_err(|| format!("while loading {}", path))?,
+        }
+        self.loaded_files.insert(full_path);
+        Ok(true)
+    }
+
+    fn load_file_unchecked(&mut self, path: &str) -> Result<()> {
+        let source_text = util::read_file(path)?;
+        // anything the loaded file leaves on the stack is discarded
+        self.eval(path, &source_text)?;
+        Ok(())
+    }
+
+    /// Compiles and runs some source code, whose functions and types are added to this VM's.
+    /// The source is run in its own scope, and the values that it leaves on the stack are returned.
+    /// Each source gets the full step limit, except for loaded files, whose steps count towards the
+    /// program that loaded them.
+    pub fn eval(&mut self, source_name: &str, source_text: &str) -> Result<Vec<Value>> {
+        if self.loading_files.is_empty() {
+            self.steps = 0;
+        }
+        let mut lexer = Lexer::new(source_text);
+        lexer.set_tab_width(self.tab_width);
+        let mut ast = Parser::new(lexer)
+            .parse()?;
+        let defined_funs = {
+            let mut preprocessor = Preprocessor::new(source_name, &mut ast, &mut self.fun_table, &mut self.type_table);
+            preprocessor.set_include_depth(0, self.max_include_depth);
+            preprocessor.set_tab_width(self.tab_width);
+            preprocessor.preprocess()?;
+            for warning in preprocessor.warnings() {
+                warn!("Warning: {}", warning.full_message());
+            }
+            preprocessor.defined_funs()
+                .clone()
+        };
+        // new functions may shadow builtins that names were already resolved to, or replace
+        // functions that were already compiled. When optimizing, a replaced function may also have
+        // been inlined into any other compiled function, so they're all compiled again.
+        let recompile_all = self.optimize && !defined_funs.is_empty();
+        self.call_targets
+            .retain(|name, target| match target {
+                &mut CallTarget::User(_, _) => !recompile_all && !defined_funs.contains(name),
+                &mut CallTarget::Builtin(_) => false,
+            });
+        let mut generator = ToBytecode::new(&self.fun_table, &self.type_table);
+        generator.set_inline_constants(self.optimize);
+        let mut bytecode = generator.to_bytecode(&ast)?;
+        if self.optimize {
+            bytecode = optimizer::optimize(bytecode);
+        }
+
+        let stack_size = self.value_stack
+            .len();
+        let caller_vars = mem::replace(&mut self.var_stack, Vec::new());
+        let run_result = self.run(&bytecode);
+        self.restore_vars(caller_vars);
+        run_result?;
+        let count = self.value_stack.len() - stack_size;
+        self.pop_values(count)
+    }
+
+    /// Clears the value and function stacks left behind by an error, so this VM can keep running
+    /// new code.
+    pub fn recover(&mut self) {
+        self.value_stack.clear();
+        self.fun_stack.clear();
+        self.recount_memory();
     }
 
     fn compile_function(&self, fun: &Function) -> Result<Vec<Bytecode>>{ 
@@ -360,8 +1334,9 @@ impl VM {
         }
         */
         let mut bytecode = {
-            let generator = ToBytecode::new(&self.fun_table, &self.type_table);
-            match generator.to_bytecode(&fun.body) {
+            let mut generator = ToBytecode::for_function(&self.fun_table, &self.type_table, fun);
+            generator.set_inline_constants(self.optimize);
+            match generator.body_to_bytecode(&fun.body) {
                 Ok(b) => b,
                 e => { 
                     e.chain_err(|| format!("failure to compile function `{}'", fun.name))?;
@@ -370,6 +1345,9 @@ impl VM {
             }
         };
         //prelude.append(&mut bytecode);
+        if self.optimize {
+            bytecode = optimizer::optimize(bytecode);
+        }
         debug!("--------------------------------------------------------------------------------");
         debug!("Compiled code for {}", fun.name);
         for ref p in &bytecode {
@@ -387,8 +1365,552 @@ impl VM {
 
     /// Gets if we have the bytecode for a function compiled
     fn has_compiled_function(&self, name: &str) -> bool {
-        self.fun_bytecode
-            .contains_key(name)
+        match self.call_targets.get(name) {
+            Some(&CallTarget::User(_, _)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.flush_writes();
     }
 }
 
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::process;
+
+    /// Runs some source code in a fresh VM, giving the values that it left on the stack.
+    pub fn run_source(source: &str) -> Result<Vec<Value>> {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.eval("<test>", source)
+    }
+
+    /// Runs some source code in a fresh VM, giving the last value that it left on the stack.
+    pub fn run_value(source: &str) -> Value {
+        run_source(source)
+            .unwrap()
+            .pop()
+            .expect("source left no values on the stack")
+    }
+
+    /// Runs some bytecode in a fresh VM, giving the values that it left on the stack.
+    fn run_bytecode(bytecode: Vec<Bytecode>) -> Result<Vec<Value>> {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.run(&bytecode)?;
+        Ok(vm.value_stack.clone())
+    }
+
+    /// Gets the path of a file for a test in the temporary directory.
+    pub fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("rasp-test-{}-{}", process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Writes a source file for a test into the temporary directory, giving its path.
+    pub fn temp_source(name: &str, source: &str) -> String {
+        let path = temp_path(name);
+        File::create(&path)
+            .and_then(|mut file| file.write_all(source.as_bytes()))
+            .unwrap();
+        path
+    }
+
+    /// Gets the messages of an error and everything that caused it, joined into one line.
+    pub fn error_text(err: &Error) -> String {
+        err.iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join(": ")
+    }
+
+    /// Runs some source code that should fail, giving its error's messages.
+    pub fn run_error(source: &str) -> String {
+        match run_source(source) {
+            Ok(values) => panic!("expected an error, but got {:?}", values),
+            Err(err) => error_text(&err),
+        }
+    }
+
+    const LOOP: &'static str = "(&define spin (n) (if (positive? n) (spin n) 0))";
+
+    #[test]
+    fn max_steps_stops_infinite_loop() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_steps(Some(100));
+        let err = vm.eval("<test>", &format!("{} (spin 1)", LOOP))
+            .unwrap_err();
+        assert!(error_text(&err).contains("execution step limit exceeded"));
+    }
+
+    #[test]
+    fn max_steps_allows_short_programs() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_steps(Some(100));
+        assert_eq!(vm.eval("<test>", &format!("{} (spin 0)", LOOP)).unwrap(), vec![Value::Integer(0)]);
+    }
+
+    #[test]
+    fn max_steps_is_per_eval() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_steps(Some(12));
+        for _ in 0 .. 10 {
+            assert_eq!(vm.eval("<test>", "(+ 1 2)").unwrap(), vec![Value::Integer(3)]);
+        }
+    }
+
+    #[test]
+    fn callee_does_not_see_caller_locals() {
+        let err = run_error("(&define callee () x) (&define caller (x) (callee)) (caller 5)");
+        assert!(err.contains("unknown variable or function name: x"));
+        let err = run_error("(&define callee () x) (let ((x 5)) (callee))");
+        assert!(err.contains("unknown variable or function name: x"));
+    }
+
+    #[test]
+    fn callee_params_shadow_nothing_in_caller() {
+        let values = run_source("(&define callee (x) x) (&define caller (x) (list x (callee 2) x)) (caller 1)")
+            .unwrap();
+        assert_eq!(values, vec![Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(1)])]);
+    }
+
+    #[test]
+    fn dup_doubles_top_value() {
+        let values = run_bytecode(vec![Bytecode::Push(Value::Integer(1)), Bytecode::Push(Value::from("a")), Bytecode::Dup])
+            .unwrap();
+        assert_eq!(values, vec![Value::Integer(1), Value::from("a"), Value::from("a")]);
+    }
+
+    #[test]
+    fn dup_on_empty_stack_is_error() {
+        let err = run_bytecode(vec![Bytecode::Dup])
+            .unwrap_err();
+        assert!(error_text(&err).contains("attempted to duplicate a value on an empty stack"));
+    }
+
+    /// Pushes the integers from 1 up to `count`.
+    fn push_counting(count: i64) -> Vec<Bytecode> {
+        (1 .. count + 1).map(|i| Bytecode::Push(Value::Integer(i))).collect()
+    }
+
+    #[test]
+    fn swap_exchanges_top_two_values() {
+        let mut bytecode = push_counting(3);
+        bytecode.push(Bytecode::Swap);
+        assert_eq!(run_bytecode(bytecode).unwrap(), vec![Value::Integer(1), Value::Integer(3), Value::Integer(2)]);
+        let err = run_bytecode(vec![Bytecode::Push(Value::Integer(1)), Bytecode::Swap])
+            .unwrap_err();
+        assert!(error_text(&err).contains("attempted to swap values on a stack of 1"));
+    }
+
+    #[test]
+    fn rot_brings_third_value_to_top() {
+        let mut bytecode = push_counting(4);
+        bytecode.push(Bytecode::Rot);
+        assert_eq!(run_bytecode(bytecode).unwrap(),
+                   vec![Value::Integer(1), Value::Integer(3), Value::Integer(4), Value::Integer(2)]);
+        let mut bytecode = push_counting(2);
+        bytecode.push(Bytecode::Rot);
+        let err = run_bytecode(bytecode)
+            .unwrap_err();
+        assert!(error_text(&err).contains("attempted to rotate values on a stack of 2"));
+    }
+
+    #[test]
+    fn empty_source_runs_nothing() {
+        assert_eq!(run_source("").unwrap(), vec![]);
+        assert_eq!(run_source(" \n\t; just a comment\n").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn builtins_are_values() {
+        let source = "(&define apply2 (f a b) (f a b)) (list (apply2 + 1 2) (apply2 append \"a\" \"b\"))";
+        assert_eq!(run_source(source).unwrap(), vec![Value::from(vec![Value::Integer(3), Value::from("ab")])]);
+    }
+
+    #[test]
+    fn user_functions_are_values() {
+        let source = "(&define twice (x) (* 2 x)) (&define apply1 (f a) (f a)) (apply1 twice 4)";
+        assert_eq!(run_source(source).unwrap(), vec![Value::Integer(8)]);
+    }
+
+    #[test]
+    fn stack_and_scope_depths() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        let (stack_len, var_depth) = (vm.stack_len(), vm.var_depth());
+        vm.run(&push_counting(2))
+            .unwrap();
+        assert_eq!(vm.stack_len(), stack_len + 2);
+        vm.run(&vec![Bytecode::NewVarStack, Bytecode::NewVarStack])
+            .unwrap();
+        assert_eq!(vm.var_depth(), var_depth + 2);
+        vm.run(&vec![Bytecode::PopVarStack, Bytecode::PopN(1)])
+            .unwrap();
+        assert_eq!((vm.stack_len(), vm.var_depth()), (stack_len + 1, var_depth + 1));
+        // eval gives back the values that it made, leaving the stack as it was
+        vm.eval("<test>", "(&define add (a b) (+ a b)) (add 1 2)")
+            .unwrap();
+        assert_eq!((vm.stack_len(), vm.var_depth()), (stack_len + 1, var_depth + 1));
+    }
+
+    #[test]
+    fn values_round_trip_through_conversions() {
+        assert_eq!(f64::try_from(Value::from(1.5)).unwrap(), 1.5);
+        assert_eq!(i64::try_from(Value::from(3i64)).unwrap(), 3);
+        assert_eq!(String::try_from(Value::from("abc")).unwrap(), "abc");
+        assert_eq!(String::try_from(Value::from(String::from("def"))).unwrap(), "def");
+        assert_eq!(char::try_from(Value::from('x')).unwrap(), 'x');
+        assert_eq!(bool::try_from(Value::from(true)).unwrap(), true);
+        let list = vec![Value::from(1i64), Value::from("a")];
+        assert_eq!(Vec::<Value>::try_from(Value::from(list.clone())).unwrap(), list);
+    }
+
+    #[test]
+    fn integers_convert_to_floats() {
+        assert_eq!(f64::try_from(Value::from(2i64)).unwrap(), 2.0);
+        // but not the other way around
+        assert!(i64::try_from(Value::from(2.0)).is_err());
+    }
+
+    #[test]
+    fn conversions_name_the_wrong_type() {
+        let err = String::try_from(Value::from(1i64))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "expected a string value, but instead got integer");
+        let err = Vec::<Value>::try_from(Value::from("a"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "expected a list value, but instead got string");
+    }
+
+    #[test]
+    fn vars_are_sorted_by_name() {
+        let mut table = VarTable::new();
+        for (i, name) in ["zeta", "alpha", "mu", "beta", "omega"].iter().enumerate() {
+            table.insert(name.to_string(), Value::Integer(i as i64));
+        }
+        let names = sorted_vars(&table)
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["alpha", "beta", "mu", "omega", "zeta"]);
+    }
+
+    /// Makes a list with `depth` levels of lists around an integer.
+    fn nested_list(depth: usize) -> Value {
+        let mut value = Value::Integer(1);
+        for _ in 0 .. depth {
+            value = Value::new_list(vec![value]);
+        }
+        value
+    }
+
+    #[test]
+    fn equals_compares_structure() {
+        let list = |items: Vec<Value>| Value::new_list(items);
+        assert!(list(vec![Value::Integer(1), list(vec![Value::from("a")])])
+                .equals(&list(vec![Value::Number(1.0), list(vec![Value::from("a")])])).unwrap());
+        assert!(!list(vec![Value::Integer(1), list(vec![Value::from("a")])])
+                .equals(&list(vec![Value::Integer(1), list(vec![Value::from("b")])])).unwrap());
+        assert!(!list(vec![Value::Integer(1)]).equals(&list(vec![Value::Integer(1), Value::Integer(2)])).unwrap());
+        assert!(!Value::Integer(1).equals(&Value::from("1")).unwrap());
+    }
+
+    #[test]
+    fn equals_limits_nesting() {
+        assert!(nested_list(MAX_EQUALITY_DEPTH).equals(&nested_list(MAX_EQUALITY_DEPTH)).unwrap());
+        let err = nested_list(MAX_EQUALITY_DEPTH + 1).equals(&nested_list(MAX_EQUALITY_DEPTH + 1))
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("lists are nested too deeply to compare (more than {} levels)", MAX_EQUALITY_DEPTH));
+        // lengths are checked before going any deeper
+        let short = Value::new_list(vec![]);
+        assert!(!nested_list(MAX_EQUALITY_DEPTH + 1).equals(&short).unwrap());
+    }
+
+    #[test]
+    fn completions_cover_every_kind_of_name() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.eval("<test>", "(&define char-count (s) 1) (&alias char-count char-total)")
+            .unwrap();
+        // run pops the scope that it starts in, so leave another one behind for it to pop
+        vm.run(&vec![Bytecode::Store(String::from("char-var"), Value::Integer(1)), Bytecode::NewVarStack])
+            .unwrap();
+        assert_eq!(vm.completions("char-"),
+                   vec!["char->number", "char-count", "char-downcase", "char-total", "char-upcase", "char-var"]);
+        assert_eq!(vm.completions("char-c"), vec!["char-count"]);
+        assert!(vm.completions("zzz").is_empty());
+    }
+
+    #[test]
+    fn completions_have_no_duplicates() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.run(&vec![Bytecode::Store(String::from("x"), Value::Integer(1)), Bytecode::NewVarStack,
+                     Bytecode::Store(String::from("x"), Value::Integer(2)), Bytecode::NewVarStack])
+            .unwrap();
+        assert_eq!(vm.completions("x"), vec!["x"]);
+    }
+
+    #[test]
+    fn redefined_functions_replace_compiled_ones() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        let mut eval = |source| vm.eval("<test>", source).unwrap();
+        eval("(&define f () 1) (&define g () (f))");
+        assert_eq!(eval("(list (f) (g))"), vec![Value::from(vec![Value::Integer(1), Value::Integer(1)])]);
+        eval("(&define f () 2)");
+        // callers that were already compiled call the new definition too
+        assert_eq!(eval("(list (f) (g))"), vec![Value::from(vec![Value::Integer(2), Value::Integer(2)])]);
+    }
+
+    #[test]
+    fn functions_replace_compiled_builtins() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        assert_eq!(vm.eval("<test>", "(inc 1)").unwrap(), vec![Value::Integer(2)]);
+        assert_eq!(vm.eval("<test>", "(&define inc (x) (+ x 10)) (inc 1)").unwrap(), vec![Value::Integer(11)]);
+    }
+
+    /// Makes a VM with buffered writes, which has opened a temporary file for writing as `fd`.
+    fn buffered_vm(path: &str) -> VM {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_buffer_writes(true);
+        vm.eval("<test>", &format!("(def fd (stdopen \"{}\" \"w\"))", path))
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn buffered_writes_wait_for_flush() {
+        let path = temp_path("buffered-writes");
+        let mut vm = buffered_vm(&path);
+        // strings are written with their NUL terminator
+        assert_eq!(vm.eval("<test>", "(stdwrite fd \"ab\") (stdwrite fd \"cd\")").unwrap(),
+                   vec![Value::Integer(3), Value::Integer(3)]);
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        assert_eq!(vm.eval("<test>", "(flush fd)").unwrap(), vec![Value::Integer(6)]);
+        assert_eq!(fs::read(&path).unwrap(), b"ab\0cd\0");
+        // nothing is left to flush
+        assert_eq!(vm.eval("<test>", "(flush fd)").unwrap(), vec![Value::Integer(0)]);
+
+        vm.eval("<test>", "(stdwrite fd \"ef\")").unwrap();
+        drop(vm);
+        assert_eq!(fs::read(&path).unwrap(), b"ab\0cd\0ef\0");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unbuffered_writes_go_straight_out() {
+        let path = temp_path("unbuffered-writes");
+        let source = format!("(let ((fd (stdopen \"{}\" \"w\"))) (stdwrite fd \"ab\") (flush fd))", path);
+        assert_eq!(run_value(&source), Value::Integer(0));
+        assert_eq!(fs::read(&path).unwrap(), b"ab\0");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn full_buffers_are_written_out() {
+        let path = temp_path("full-write-buffer");
+        let mut vm = buffered_vm(&path);
+        let fill = format!("(stdwrite fd \"{}\")", "x".repeat(WRITE_BUFFER_SIZE - 2));
+        vm.eval("<test>", &fill).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 0);
+        vm.eval("<test>", "(stdwrite fd \"\")").unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), WRITE_BUFFER_SIZE);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn closing_flushes_writes() {
+        let path = temp_path("close-flushes");
+        let mut vm = buffered_vm(&path);
+        vm.eval("<test>", "(stdwrite fd \"hi\") (stdclose fd)").unwrap();
+        // checked before the VM is dropped, which would flush it anyway
+        assert_eq!(fs::read(&path).unwrap(), b"hi\0");
+        drop(vm);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pushes_copy_constants() {
+        let constants = vec![
+            Value::Integer(-3),
+            Value::Number(2.5),
+            Value::Boolean(true),
+            Value::Char('x'),
+            Value::from("str"),
+            Value::List(Rc::new(vec![Value::Integer(1)])),
+        ];
+        let bytecode = constants.iter()
+            .chain(constants.iter())
+            .cloned()
+            .map(Bytecode::Push)
+            .collect();
+        let stack = run_bytecode(bytecode).unwrap();
+        assert_eq!(stack[.. constants.len()], constants[..]);
+        assert_eq!(stack[constants.len() ..], constants[..]);
+    }
+
+    /// Makes a VM with a memory limit.
+    fn vm_with_max_memory(max_memory: usize) -> VM {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_max_memory(Some(max_memory));
+        vm
+    }
+
+    #[test]
+    fn memory_is_released_after_eval() {
+        let sources = [
+            "(list \"ab\" (list \"cd\" (list)))",
+            "(let ((l (list 1 2 3)) (s \"str\")) (def t s) (list l l t))",
+            "(&define f (a ? b) (list a b)) (f (list \"x\")) (f \"y\" \"z\")",
+            "(&define f (n) (if (= n 0) (list) (conj (f (- n 1)) \"n\"))) (f 10)",
+            "(let ((x \"captured\")) (define g (y) (list x y)) (g \"arg\") (g (list x)))",
+            "(match (list \"a\" \"b\") ((h & t) t) (_ 0))",
+            "(car (list \"first\" \"second\"))",
+        ];
+        for source in &sources {
+            let mut vm = vm_with_max_memory(usize::max_value());
+            vm.eval("<test>", source)
+                .unwrap();
+            assert_eq!((vm.memory.total, vm.memory.holders.len()), (0, 0), "{}", source);
+        }
+    }
+
+    #[test]
+    fn memory_is_recounted_after_errors() {
+        let mut vm = vm_with_max_memory(usize::max_value());
+        let _ = vm.eval("<test>", "(&define f (s) (car s)) (let ((l (list \"abc\"))) (f 1))")
+            .unwrap_err();
+        vm.recover();
+        assert_eq!((vm.memory.total, vm.memory.holders.len()), (0, 0));
+    }
+
+    #[test]
+    fn shared_lists_are_counted_once() {
+        // the inner list is 2 items and 8 bytes, and the outer one adds 4 items
+        let source = "(let ((l (list \"aaaa\" \"bbbb\"))) (list l l l l))";
+        assert!(vm_with_max_memory(14).eval("<test>", source).is_ok());
+        assert_eq!(vm_with_max_memory(13).eval("<test>", source).unwrap_err().to_string(),
+                   "memory limit exceeded (values take up 14, but the limit is 13)");
+    }
+
+    #[test]
+    fn callers_variables_count_towards_memory() {
+        let source = |size| format!("(&define g () (list \"0123456789\")) (let ((big \"{}\")) (g))", "x".repeat(size));
+        assert!(vm_with_max_memory(45).eval("<test>", &source(30)).is_ok());
+        assert_eq!(vm_with_max_memory(45).eval("<test>", &source(40)).unwrap_err().to_string(),
+                   "memory limit exceeded (values take up 50, but the limit is 45)");
+    }
+
+    #[test]
+    fn top_level_defs_last_between_evals() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        let mut eval = |source| vm.eval("<test>", source).unwrap();
+        eval("(def x 5)");
+        assert_eq!(eval("(+ x 1)"), vec![Value::Integer(6)]);
+        eval("(def x (list x))");
+        assert_eq!(eval("x"), vec![Value::from(vec![Value::Integer(5)])]);
+        eval("(&define f () x)");
+        assert_eq!(eval("(f)"), vec![Value::from(vec![Value::Integer(5)])]);
+    }
+
+    #[test]
+    fn globals_count_towards_memory() {
+        let mut vm = vm_with_max_memory(usize::max_value());
+        vm.eval("<test>", "(def s \"abcd\") (def l (list s s))")
+            .unwrap();
+        assert_eq!(vm.memory.total, 4 + 2 + 8);
+        vm.eval("<test>", "(def l 0)")
+            .unwrap();
+        assert_eq!(vm.memory.total, 4);
+    }
+
+    #[test]
+    fn falsy_values() {
+        let falsy = [Value::Boolean(false), Value::Integer(0), Value::Number(0.0), Value::from(""), Value::from(Vec::new())];
+        for value in &falsy {
+            assert!(!value.is_truthy(), "{:?}", value);
+        }
+        let truthy = [Value::Boolean(true), Value::Integer(-1), Value::Number(0.5), Value::from(" "),
+                      Value::from(vec![Value::Boolean(false)]), Value::Char('\0'), Value::Function(String::from("car"))];
+        for value in &truthy {
+            assert!(value.is_truthy(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn conditions_use_truthiness() {
+        assert_eq!(run_value("(list (if 0 1 2) (if \"\" 1 2) (if (list) 1 2) (if (car \"a\") 1 2) (if car 1 2))"),
+                   Value::from(vec![Value::Integer(2), Value::Integer(2), Value::Integer(2), Value::Integer(1), Value::Integer(1)]));
+    }
+
+    #[test]
+    fn non_finite_numbers_display_as_names() {
+        use std::f64;
+        assert_eq!(Value::Number(f64::INFINITY).to_string(), "inf");
+        assert_eq!(Value::Number(f64::NEG_INFINITY).to_string(), "-inf");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "nan");
+        assert_eq!(Value::Number(-f64::NAN).to_string(), "nan");
+        assert_eq!(Value::Number(2.0).to_string(), "2.0");
+    }
+
+    #[test]
+    fn redefined_functions_replace_inlined_ones() {
+        let mut vm = VM::new(FunTable::new(Vec::new()), TypeTable::new(Vec::new()));
+        vm.set_optimize(true);
+        let mut eval = |source| vm.eval("<test>", source).unwrap();
+        eval("(&define f () 1) (&define g () (f))");
+        assert_eq!(eval("(g)"), vec![Value::Integer(1)]);
+        eval("(&define f () 2)");
+        assert_eq!(eval("(g)"), vec![Value::Integer(2)]);
+    }
+
+    #[test]
+    fn shared_lists_are_copied_when_taken() {
+        let list = Value::new_list(vec![Value::Integer(1), Value::Integer(2)]);
+        let copy = list.clone();
+        match (&list, &copy) {
+            (&Value::List(ref a), &Value::List(ref b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => unreachable!(),
+        }
+        let mut items = copy.into_list();
+        items.push(Value::Integer(3));
+        assert_eq!(list, Value::from(vec![Value::Integer(1), Value::Integer(2)]));
+        // the last holder takes the items without copying them
+        assert_eq!(list.into_list().len(), 2);
+    }
+
+    #[test]
+    fn changing_a_list_leaves_its_sharers() {
+        assert_eq!(run_value("(let ((a (list 1 2))) (let ((b (set-nth a 0 9)) (c (conj a 3))) (list a b c)))"),
+                   Value::from(vec![Value::from(vec![Value::Integer(1), Value::Integer(2)]),
+                                    Value::from(vec![Value::Integer(9), Value::Integer(2)]),
+                                    Value::from(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)])]));
+    }
+
+    #[test]
+    fn arity_errors_show_the_signature() {
+        assert!(run_error("(&define f (x :int) x) (f)")
+                .contains("no variant of function f takes 0 arguments (takes exactly 1 arguments: (x :int))"));
+        assert!(run_error("(&define g (a ? b) a) (g 1 2 3)")
+                .contains("no variant of function g takes 3 arguments (takes 1 to 2 arguments: (a, b?))"));
+    }
+
+    #[test]
+    fn check_stack_catches_leaked_and_lost_values() {
+        let check = || Bytecode::CheckStack(String::from("statement"));
+        assert!(run_bytecode(vec![Bytecode::Push(Value::Integer(1)), check(), Bytecode::Push(Value::Integer(2)), check()]).is_ok());
+        let leaked = run_bytecode(vec![Bytecode::Push(Value::Integer(1)), Bytecode::Push(Value::Integer(2)), check()]);
+        assert_eq!(leaked.unwrap_err().to_string(),
+                   "VM error: statement changed the stack by 2 values, but should leave at most its result");
+        let lost = run_bytecode(vec![Bytecode::Push(Value::Integer(1)), check(), Bytecode::PopN(1), check()]);
+        assert!(lost.is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Tests that run the rasp binary itself, for behaviour that depends on its command line or exit
+//! status.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process::{self, Command, Output, Stdio};
+
+/// Runs the rasp binary with some arguments, giving it some text on stdin.
+fn rasp(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rasp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run rasp");
+    child.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output()
+        .unwrap()
+}
+
+/// Writes a source file for a test into the temporary directory, giving its path.
+fn temp_source(name: &str, source: &str) -> String {
+    let path = env::temp_dir()
+        .join(format!("rasp-cli-test-{}-{}", process::id(), name));
+    File::create(&path)
+        .and_then(|mut file| file.write_all(source.as_bytes()))
+        .unwrap();
+    path.to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Gets what a run of rasp wrote to stdout.
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Gets what a run of rasp wrote to stderr, which is where its log goes.
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn exit_sets_status() {
+    let output = rasp(&["-e", "(exit 3) (exit 4)"], "");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn exit_flushes_buffered_writes() {
+    let output = rasp(&["--buffer-writes", "-e", "(stdwrite 1 \"hi\") (exit 0)"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("hi"));
+}
+
+#[test]
+fn check_reports_errors_in_functions() {
+    let output = rasp(&["--check", "-e", "(stdwrite 1 \"ran\") (&define f () (let x))"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Compile error in function `f'"));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn check_does_not_run() {
+    let output = rasp(&["--check", "-e", "(stdwrite 1 \"ran\") (exit 3)"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn json_diagnostics_have_positions() {
+    let output = rasp(&["--message-format", "json", "-e", "(list 1)\n  (bogus)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output),
+               "{\"severity\":\"error\",\"message\":\"attempt to call non-existent function `bogus'\",\
+                \"file\":\"<eval>\",\"start\":{\"line\":2,\"col\":3},\"end\":{\"line\":2,\"col\":9}}\n");
+}
+
+#[test]
+fn empty_file_runs_cleanly() {
+    let path = temp_source("empty.rasp", "");
+    let output = rasp(&[&path, "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn comment_only_file_runs_cleanly() {
+    let path = temp_source("comments.rasp", "; nothing to see here\n\n   ; or here\n");
+    let output = rasp(&[&path, "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn repl_joins_lines_until_parens_balance() {
+    let output = rasp(&["-q"], "(&define add1 (x)\n  (+ x\n 1))\n(add1\n 2)\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> . . > . 3\n> \n");
+}
+
+#[test]
+fn repl_reports_unclosed_input() {
+    let output = rasp(&["-q"], "(list 1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("expression is missing a right paren"));
+}
+
+#[test]
+fn repl_keeps_running_after_exit() {
+    let output = rasp(&["-q"], "(exit 3)\n(+ 1 2)\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> > 3\n> \n");
+    assert!(stderr(&output).contains("program exited with status 3"));
+}
+
+#[test]
+fn repl_step_limit_is_per_input() {
+    let output = rasp(&["-q", "--max-steps", "12"], &"(+ 1 2)\n".repeat(6));
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output).matches("3\n").count(), 6);
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn trace_prints_each_executed_instruction() {
+    let output = rasp(&["--trace", "-e", "(if (= 1 1) (+ 1 2) 3)"], "");
+    assert_eq!(output.status.code(), Some(0));
+    let lines = stderr(&output)
+        .lines()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    // the skipped else branch isn't run, so it isn't traced
+    assert_eq!(lines.len(), 8);
+    assert!(lines[0].contains("1. Push(Integer(1))"));
+    assert!(lines[6].contains("7. Call(\"+\", 2)") && lines[6].contains("stack: (1 2)"));
+}
+
+#[test]
+fn trace_shows_variables() {
+    let output = rasp(&["--trace", "-e", "(let ((x 5)) x)"], "");
+    assert!(stderr(&output).lines().any(|line| line.contains("Load(\"x\")") && line.contains("vars: {x = 5}")));
+}
+
+#[test]
+fn print_result_prints_last_value() {
+    let output = rasp(&["-e", "(+ 1 2)", "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+    let output = rasp(&["-e", "42", "--print-result"], "");
+    assert_eq!(stdout(&output), "42\n");
+}
+
+#[test]
+fn print_result_prints_nothing_without_value() {
+    let output = rasp(&["-e", "(&define f () 1)", "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn stack_size_allows_deep_recursion() {
+    let path = temp_source("deep.rasp", "(&define count (n) (if (zero? n) 0 (+ 1 (count (dec n)))))\n(count 20000)\n");
+    let output = rasp(&[&path, "--print-result"], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("stack overflow"), "{}", stderr(&output));
+    let output = rasp(&[&path, "--print-result", "--stack-size", "512"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "20000\n");
+}
+
+#[test]
+fn stack_size_rejects_huge_sizes() {
+    let output = rasp(&["--stack-size", &usize::MAX.to_string(), "-e", "1"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("is too large"), "{}", stderr(&output));
+}
+
+#[test]
+fn stack_size_keeps_exit_status() {
+    let output = rasp(&["-e", "(exit 3)", "--stack-size", "16"], "");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn warnings_do_not_stop_programs() {
+    let output = rasp(&["-e", "(&define f (a b) a) (f 1 2)", "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n");
+    assert!(stderr(&output).contains("Warning: 1:17-1:18: parameter `b' of function `f' is never used"), "{}", stderr(&output));
+}
+
+#[test]
+fn werror_stops_programs_with_warnings() {
+    let output = rasp(&["-e", "(&define f (a b) a) (f 1 2)", "--print-result", "--werror"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("warnings are treated as errors"), "{}", stderr(&output));
+    // but not programs without them
+    let output = rasp(&["-e", "(&define f (a) a) (f 1)", "--print-result", "--werror"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn werror_stops_programs_with_warnings_in_included_files() {
+    let lib = temp_source("werror-lib.rasp", "(&define f (a b) a)\n");
+    let main = temp_source("werror-main.rasp", &format!("(&include \"{}\")\n(f 1 2)\n", lib));
+    let output = rasp(&[&main, "--print-result", "--werror"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains(&format!("{}: 1:17-1:18: parameter `b' of function `f' is never used", lib)),
+            "{}", stderr(&output));
+    assert!(stderr(&output).contains("warnings are treated as errors"), "{}", stderr(&output));
+}
+
+#[test]
+fn json_warnings_have_warning_severity() {
+    let output = rasp(&["--message-format", "json", "-e", "(&define f (a b) a)"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output),
+               "{\"severity\":\"warning\",\"message\":\"parameter `b' of function `f' is never used\",\
+                \"file\":\"<eval>\",\"start\":{\"line\":1,\"col\":17},\"end\":{\"line\":1,\"col\":18}}\n");
+}
+
+#[test]
+fn repl_help_command() {
+    let output = rasp(&["-q"], ":help inc\n(&define f (x) \"gives x\" x)\n:help f\n:help\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output),
+               "> (inc number) adds one to a number\n> > (f x)\ngives x\n(defined in <repl>)\n> usage: :help function-name\n> \n");
+}
+
+#[test]
+fn ast_json_does_not_run() {
+    let output = rasp(&["--ast-json", "-e", "(exit 3)"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("[{\"kind\":\"expr\""), "{}", stdout(&output));
+    assert!(stdout(&output).contains("\"value\":\"exit\""));
+}
+
+#[test]
+fn repl_redefines_functions() {
+    let output = rasp(&["-q"], "(&define f () 1)\n(f)\n(&define f () 2)\n(f)\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> > 1\n> > 2\n> \n");
+}
+
+#[test]
+fn log_is_not_colored_off_terminal() {
+    for args in &[&["-e", "(bogus)"][..], &["--no-color", "-e", "(bogus)"][..]] {
+        let output = rasp(args, "");
+        assert_eq!(output.status.code(), Some(1));
+        assert!(stderr(&output).contains("attempt to call non-existent function `bogus'"));
+        assert!(!stderr(&output).contains('\u{1b}'), "colored log: {:?}", stderr(&output));
+    }
+}
+
+#[test]
+fn quiet_only_logs_errors() {
+    let output = rasp(&["-q", "-e", "(&define f (a b) a) (f 1 2)", "--print-result"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(stderr(&output), "");
+    let output = rasp(&["--quiet", "-e", "(&define f (a b) a) (bogus)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr(&output).contains("Warning"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("attempt to call non-existent function `bogus'"), "{}", stderr(&output));
+}
+
+#[test]
+fn quiet_ignores_rust_log() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rasp"))
+        .args(["-q", "-e", "(list 1)"])
+        .env("RUST_LOG", "trace")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn repl_keeps_top_level_defs() {
+    let output = rasp(&[], "(def x 5)\n(&define f () (+ x 1))\n(f)\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> > > 6\n> \n");
+}
+
+#[test]
+fn dump_functions_stops_with_compile_only() {
+    let output = rasp(&["--dump-functions", "--compile-only", "-e", "(&define f (a) a) (stdwrite 1 \"ran\")"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "(f a)\n");
+}
+
+#[test]
+fn step_in_repl_reads_the_same_input() {
+    // the first instruction pauses, the "s" steps to the second, and the "c" runs the rest; the
+    // next line is then read by the REPL again
+    let output = rasp(&["-q", "--step"], "(+ 1 2)\ns\nc\n(+ 3 4)\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> 3\n> 7\n> \n");
+    assert_eq!(stderr(&output).matches("[s]tep or [c]ontinue? ").count(), 2);
+}